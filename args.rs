@@ -1,4 +1,5 @@
 use clap::{Args, Parser, Subcommand, ValueEnum};
+use crate::compress::Codec;
 
 #[derive(Parser)]
 pub struct Arguments {
@@ -11,6 +12,8 @@ pub enum Commands {
     Embed(EmbedParams),
     Dislodge(DislodgeParams),
     Download(DownloadParams),
+    /// Generate an X25519 key pair for --recipient and --identity, or an Ed25519 signing key
+    Keygen(KeygenParams),
 }
 
 #[derive(Args, Default, Debug)]
@@ -42,6 +45,62 @@ pub struct EmbedParams {
     #[arg(long)]
     /// Output video resolution
     pub resolution: Option<String>,
+    
+    #[arg(short, long)]
+    /// Output path (file, or directory for image sequences)
+    pub out_path: Option<String>,
+    
+    #[arg(short, long)]
+    /// Output format
+    pub format: Option<EmbedOutputFormat>,
+    
+    #[arg(long)]
+    /// Frame codec for AVI output
+    pub avi_codec: Option<EmbedAviCodec>,
+    
+    #[arg(long)]
+    /// How Y4M output stores colour (bt601 by default)
+    pub y4m_color: Option<EmbedY4mColor>,
+    
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=128))]
+    /// Reed–Solomon check bytes per 255 byte codeword (0 disables error correction)
+    pub fec_parity: Option<u8>,
+    
+    #[arg(long, num_args = 0..=1, default_missing_value = "25", value_name = "REPAIR_PERCENT")]
+    /// Fountain-code the frames so any big enough subset of them decodes, with this many repair symbols in percent
+    pub fountain: Option<u32>,
+    
+    #[arg(long, value_name = "FRAMES")]
+    /// Calibration frames of known colours in front of the data (1 by default, 0 for none)
+    pub calibration_frames: Option<u32>,
+    
+    #[arg(long, value_name = "FRAMES")]
+    /// Repeat a calibration frame every this many data frames
+    pub calibration_interval: Option<u32>,
+    
+    #[arg(long, conflicts_with = "password_file")]
+    /// Encrypt the data with a key derived from this password
+    pub password: Option<String>,
+    
+    #[arg(long)]
+    /// Encrypt the data with a key derived from the password in this file
+    pub password_file: Option<String>,
+    
+    #[arg(long, conflicts_with_all = ["password", "password_file"])]
+    /// Encrypt the data to this public key (from `keygen`); repeat for several recipients
+    pub recipient: Vec<String>,
+    
+    #[arg(long)]
+    /// Sign the data with the Ed25519 key in this file (from `keygen --signing`)
+    pub sign_key: Option<String>,
+    
+    #[arg(long)]
+    /// Compress the data before embedding it
+    pub compress: Option<EmbedCompression>,
+    
+    #[arg(long, requires = "compress")]
+    /// Compression level (zstd 1-22, 19 by default; deflate 0-9, 9 by default)
+    pub compress_level: Option<i32>,
 }
 
 #[derive(Debug, Clone, ValueEnum)]
@@ -60,6 +119,69 @@ pub enum EmbedOutputMode {
     Colored,
     /// Uses black and white pixels
     Binary,
+    /// Uses 4 gray levels, 2 bits per block
+    Gray4,
+    /// Uses 8 gray levels, 3 bits per block
+    Gray8,
+    /// Uses 16 gray levels, 4 bits per block
+    Gray16,
+    /// Uses 8 colours that share chroma over 2x2 blocks, 1.5 bits per block
+    Palette8,
+    /// Uses 16 colours that share chroma over 2x2 blocks, 2.5 bits per block
+    Palette16,
+}
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum EmbedOutputFormat {
+    /// Custom .binvid container
+    #[default]
+    Binvid,
+    /// Legacy BINVID/COLVID file for older readers
+    BinvidV1,
+    /// Numbered PNG frames plus a manifest.json
+    Png,
+    /// Uncompressed YUV4MPEG2 video
+    Y4m,
+    /// AVI video (MJPEG or uncompressed frames)
+    Avi,
+    /// H.264 MP4 video, encoded by ffmpeg
+    Mp4,
+    /// Lossless FFV1 MKV video, encoded by ffmpeg
+    Mkv,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum EmbedAviCodec {
+    /// High quality JPEG frames
+    Mjpeg,
+    /// Uncompressed, lossless frames
+    Raw,
+}
+
+impl From<EmbedAviCodec> for crate::avi::AviCodec {
+    fn from(value: EmbedAviCodec) -> Self {
+        match value {
+            EmbedAviCodec::Mjpeg => Self::Mjpeg,
+            EmbedAviCodec::Raw => Self::Raw,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum EmbedY4mColor {
+    /// Full-range BT.601 YCbCr, shown correctly by any player
+    Bt601,
+    /// Lossless reversible transform for COLOR mode; only this tool shows or decodes it correctly
+    Rct,
+}
+
+impl From<EmbedY4mColor> for crate::y4m::Y4mColor {
+    fn from(value: EmbedY4mColor) -> Self {
+        match value {
+            EmbedY4mColor::Bt601 => Self::Bt601,
+            EmbedY4mColor::Rct => Self::Rct,
+        }
+    }
 }
 
 impl From<EmbedOutputMode> for crate::settings::OutputMode {
@@ -67,6 +189,78 @@ impl From<EmbedOutputMode> for crate::settings::OutputMode {
         match value {
             EmbedOutputMode::Colored => Self::Color,
             EmbedOutputMode::Binary => Self::Binary,
+            EmbedOutputMode::Gray4 => Self::Gray4,
+            EmbedOutputMode::Gray8 => Self::Gray8,
+            EmbedOutputMode::Gray16 => Self::Gray16,
+            EmbedOutputMode::Palette8 => Self::Palette8,
+            EmbedOutputMode::Palette16 => Self::Palette16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum EmbedCompression {
+    /// Zstandard
+    Zstd,
+    /// Deflate (zlib's raw format)
+    Deflate,
+    /// Store the data as is
+    None,
+    /// Zstandard, unless the data is already compressed
+    Auto,
+}
+
+impl From<EmbedCompression> for crate::compress::Options {
+    fn from(value: EmbedCompression) -> Self {
+        let (codec, auto) = match value {
+            EmbedCompression::Zstd => (Codec::Zstd, false),
+            EmbedCompression::Deflate => (Codec::Deflate, false),
+            EmbedCompression::None => (Codec::None, false),
+            EmbedCompression::Auto => (Codec::Zstd, true),
+        };
+        Self { codec, auto, level: None }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum DislodgeThreshold {
+    /// Halfway between black and white as rendered (128)
+    Fixed,
+    /// Otsu's split of each frame's block brightness
+    Otsu,
+    /// Otsu's split per region of the frame, for uneven brightness
+    Adaptive,
+    /// Halfway between the black and white swatches in each frame's header strip
+    Calibrated,
+}
+
+impl From<DislodgeThreshold> for crate::threshold::Strategy {
+    fn from(value: DislodgeThreshold) -> Self {
+        match value {
+            DislodgeThreshold::Fixed => Self::Fixed,
+            DislodgeThreshold::Otsu => Self::Otsu,
+            DislodgeThreshold::Adaptive => Self::Adaptive,
+            DislodgeThreshold::Calibrated => Self::Calibrated,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum DislodgeSampling {
+    /// The pixel (or four pixels) at the centre of each block
+    Centre,
+    /// Median of each block's inner pixels, skipping a border of a quarter of the block size
+    Median,
+    /// Mean of each block's inner pixels once the darkest and brightest quarter are dropped
+    TrimmedMean,
+}
+
+impl From<DislodgeSampling> for crate::sampling::Sampling {
+    fn from(value: DislodgeSampling) -> Self {
+        match value {
+            DislodgeSampling::Centre => Self::Centre,
+            DislodgeSampling::Median => Self::Median,
+            DislodgeSampling::TrimmedMean => Self::TrimmedMean,
         }
     }
 }
@@ -80,6 +274,58 @@ pub struct DislodgeParams {
     /// Path to file output (including extension)
     #[arg(short, long)]
     pub out_path: Option<String>,
+    
+    /// Etching mode, for videos that do not record it
+    #[arg(long)]
+    pub mode: Option<EmbedOutputMode>,
+    
+    /// Block size in pixels per side, for videos that do not record it
+    #[arg(long)]
+    pub block_size: Option<u32>,
+    
+    /// Payload size in bytes, for videos that do not record it
+    #[arg(long)]
+    pub data_size: Option<u64>,
+    
+    /// Reed–Solomon check bytes per codeword, for videos that do not record it
+    #[arg(long)]
+    pub fec_parity: Option<u8>,
+    
+    /// How binary blocks are told apart (calibrated by default)
+    #[arg(long)]
+    pub threshold: Option<DislodgeThreshold>,
+    
+    /// How the colour of a block is read from its pixels (trimmed-mean by default; blocks under 4x4 are always read from their centre)
+    #[arg(long, conflicts_with = "compare_sampling")]
+    pub sampling: Option<DislodgeSampling>,
+    
+    /// Decode with every sampling strategy, report their error rates and keep the best
+    #[arg(long)]
+    pub compare_sampling: bool,
+    
+    /// Password for encrypted data
+    #[arg(long, conflicts_with = "password_file")]
+    pub password: Option<String>,
+    
+    /// File holding the password for encrypted data
+    #[arg(long)]
+    pub password_file: Option<String>,
+    
+    /// Identity file (from `keygen`) for data encrypted to recipients
+    #[arg(long)]
+    pub identity: Option<String>,
+    
+    /// Only write the output if it is signed by this Ed25519 public key (or the key in this file)
+    #[arg(long)]
+    pub verify_key: Option<String>,
+    
+    /// Write the output even if it does not match the hash recorded at embed time
+    #[arg(long)]
+    pub force: bool,
+    
+    /// Replace an existing file when writing to the original file name (without -o)
+    #[arg(long)]
+    pub overwrite: bool,
 }
 
 #[derive(Args, Default)]
@@ -87,4 +333,15 @@ pub struct DownloadParams {
     /// Video URL
     #[arg(short, long)]
     pub url: Option<String>,
+}
+
+#[derive(Args, Default)]
+pub struct KeygenParams {
+    /// Write the identity to this file instead of printing it
+    #[arg(short, long)]
+    pub out_path: Option<String>,
+    
+    /// Generate an Ed25519 signing key for --sign-key and --verify-key instead
+    #[arg(long)]
+    pub signing: bool,
 }
\ No newline at end of file
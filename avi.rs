@@ -0,0 +1,529 @@
+// AVI (RIFF) muxing and demuxing.
+//
+// Frames are stored either as baseline JPEG (MJPG) or as uncompressed
+// bottom-up BGR bitmaps, which keeps the file lossless. The frame layout is
+// kept as JSON in an extra top-level "sdhm" chunk; players skip chunks they
+// do not know.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use anyhow::{Result, Context, anyhow};
+use image::{ImageFormat, RgbImage, Rgb};
+use image::codecs::jpeg::JpegEncoder;
+use crate::etcher::{self, VideoMetadata};
+use crate::settings::{Data, Settings};
+
+pub const MAGIC: &[u8] = b"RIFF";
+
+// Quality used for MJPEG frames
+const JPEG_QUALITY: u8 = 100;
+
+// Chunk holding our frame layout
+const METADATA_CHUNK: &[u8; 4] = b"sdhm";
+
+// Index flag marking a key frame
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+// Main header flag saying an idx1 index follows the movi list
+const AVIF_HASINDEX: u32 = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AviCodec {
+    // Baseline JPEG per frame
+    Mjpeg,
+    // Uncompressed 24-bit bitmaps
+    Raw,
+}
+
+impl AviCodec {
+    fn chunk_id(&self) -> &'static [u8; 4] {
+        match self {
+            AviCodec::Mjpeg => b"00dc",
+            AviCodec::Raw => b"00db",
+        }
+    }
+    
+    fn handler(&self) -> &'static [u8; 4] {
+        match self {
+            AviCodec::Mjpeg => b"MJPG",
+            AviCodec::Raw => b"DIB ",
+        }
+    }
+    
+    // biCompression value of the bitmap header
+    fn compression(&self) -> u32 {
+        match self {
+            AviCodec::Mjpeg => u32::from_le_bytes(*b"MJPG"),
+            AviCodec::Raw => 0,
+        }
+    }
+}
+
+// Encode one frame as the payload of a movi chunk
+fn encode_frame(frame: &RgbImage, codec: AviCodec) -> Result<Vec<u8>> {
+    match codec {
+        AviCodec::Mjpeg => {
+            let mut jpeg = Vec::new();
+            JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY).encode_image(frame)?;
+            Ok(jpeg)
+        }
+        AviCodec::Raw => {
+            // Bitmaps are stored bottom-up in BGR order, rows padded to 4 bytes
+            let row_len = raw_row_len(frame.width());
+            let mut bitmap = Vec::with_capacity(row_len * frame.height() as usize);
+            for y in (0..frame.height()).rev() {
+                let row_start = bitmap.len();
+                for x in 0..frame.width() {
+                    let Rgb([r, g, b]) = *frame.get_pixel(x, y);
+                    bitmap.extend_from_slice(&[b, g, r]);
+                }
+                bitmap.resize(row_start + row_len, 0);
+            }
+            Ok(bitmap)
+        }
+    }
+}
+
+fn raw_row_len(width: u32) -> usize {
+    (width as usize * 3).div_ceil(4) * 4
+}
+
+// Largest file RIFF sizes can describe: the size field leaves out the first 8 bytes
+const MAX_FILE_SIZE: u64 = u32::MAX as u64 + 8;
+
+fn write_chunk<W: Write>(out: &mut W, id: &[u8; 4], body: &[u8]) -> Result<()> {
+    let size = u32::try_from(body.len())
+        .map_err(|_| anyhow!("{} byte {} chunk does not fit in an AVI file", body.len(), String::from_utf8_lossy(id)))?;
+    out.write_all(id)?;
+    out.write_all(&size.to_le_bytes())?;
+    out.write_all(body)?;
+    if body.len() % 2 == 1 {
+        out.write_all(&[0])?;
+    }
+    Ok(())
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+// Build the hdrl list body: main header, then one video stream
+fn header_list(metadata: &VideoMetadata, codec: AviCodec, max_frame: u32) -> Result<Vec<u8>> {
+    let fps = if metadata.fps > 0.0 { metadata.fps } else { 10.0 };
+    let (rate, scale) = ((fps * 1000.0).round() as u32, 1000);
+    
+    let total = metadata.frames + metadata.calibration_frames;
+    let frames = u32::try_from(total)
+        .map_err(|_| anyhow!("{} frames do not fit in an AVI header", total))?;
+    
+    let mut avih = Vec::with_capacity(56);
+    push_u32(&mut avih, (1_000_000.0 / fps).round() as u32); // dwMicroSecPerFrame
+    push_u32(&mut avih, (max_frame as f64 * fps) as u32); // dwMaxBytesPerSec
+    push_u32(&mut avih, 0); // dwPaddingGranularity
+    push_u32(&mut avih, AVIF_HASINDEX); // dwFlags
+    push_u32(&mut avih, frames); // dwTotalFrames
+    push_u32(&mut avih, 0); // dwInitialFrames
+    push_u32(&mut avih, 1); // dwStreams
+    push_u32(&mut avih, max_frame); // dwSuggestedBufferSize
+    push_u32(&mut avih, metadata.width); // dwWidth
+    push_u32(&mut avih, metadata.height); // dwHeight
+    avih.extend_from_slice(&[0; 16]); // dwReserved
+    
+    let mut strh = Vec::with_capacity(56);
+    strh.extend_from_slice(b"vids"); // fccType
+    strh.extend_from_slice(codec.handler()); // fccHandler
+    push_u32(&mut strh, 0); // dwFlags
+    push_u16(&mut strh, 0); // wPriority
+    push_u16(&mut strh, 0); // wLanguage
+    push_u32(&mut strh, 0); // dwInitialFrames
+    push_u32(&mut strh, scale); // dwScale
+    push_u32(&mut strh, rate); // dwRate
+    push_u32(&mut strh, 0); // dwStart
+    push_u32(&mut strh, frames); // dwLength
+    push_u32(&mut strh, max_frame); // dwSuggestedBufferSize
+    push_u32(&mut strh, u32::MAX); // dwQuality
+    push_u32(&mut strh, 0); // dwSampleSize
+    push_u16(&mut strh, 0); // rcFrame.left
+    push_u16(&mut strh, 0); // rcFrame.top
+    push_u16(&mut strh, metadata.width as u16); // rcFrame.right
+    push_u16(&mut strh, metadata.height as u16); // rcFrame.bottom
+    
+    let mut strf = Vec::with_capacity(40);
+    push_u32(&mut strf, 40); // biSize
+    push_u32(&mut strf, metadata.width); // biWidth
+    push_u32(&mut strf, metadata.height); // biHeight
+    push_u16(&mut strf, 1); // biPlanes
+    push_u16(&mut strf, 24); // biBitCount
+    push_u32(&mut strf, codec.compression()); // biCompression
+    push_u32(&mut strf, raw_row_len(metadata.width) as u32 * metadata.height); // biSizeImage
+    strf.extend_from_slice(&[0; 16]); // pels per metre, colours used/important
+    
+    let mut strl = b"strl".to_vec();
+    write_chunk(&mut strl, b"strh", &strh)?;
+    write_chunk(&mut strl, b"strf", &strf)?;
+    
+    let mut hdrl = b"hdrl".to_vec();
+    write_chunk(&mut hdrl, b"avih", &avih)?;
+    write_chunk(&mut hdrl, b"LIST", &strl)?;
+    Ok(hdrl)
+}
+
+// Mux every rendered frame into an AVI file
+pub fn write_avi<P: AsRef<Path>>(path: P, data: &Data, settings: &Settings, codec: AviCodec) -> Result<()> {
+    etcher::check_layout(settings, data.out_mode)?;
+    let metadata = VideoMetadata::new(data, settings);
+    
+    let file = File::create(&path)
+        .with_context(|| format!("Failed to create {}", path.as_ref().display()))?;
+    let mut out = BufWriter::new(file);
+    
+    // The header is rewritten once all frames are in and their sizes are known
+    let max_raw = u32::try_from(raw_row_len(metadata.width) * metadata.height as usize)
+        .map_err(|_| anyhow!("{}x{} frames are too large for AVI", metadata.width, metadata.height))?;
+    let header = header_list(&metadata, codec, max_raw)?;
+    
+    out.write_all(b"RIFF")?;
+    out.write_all(&[0; 4])?;
+    out.write_all(b"AVI ")?;
+    write_chunk(&mut out, b"LIST", &header)?;
+    write_chunk(&mut out, METADATA_CHUNK, serde_json::to_string(&metadata)?.as_bytes())?;
+    
+    let movi_start = out.stream_position()?;
+    out.write_all(b"LIST")?;
+    out.write_all(&[0; 4])?;
+    out.write_all(b"movi")?;
+    
+    let frames = etcher::frames(data, settings);
+    let pb = etcher::frame_progress(frames.len());
+    let mut index = Vec::with_capacity(frames.len() * 16);
+    let mut max_frame = 0;
+    
+    for frame in frames {
+        let encoded = encode_frame(&frame, codec)?;
+        let position = out.stream_position()?;
+        
+        // Stop before the file outgrows what the RIFF sizes can hold: this
+        // frame's chunk, then the index with its entry
+        let chunk_len = 8 + encoded.len() as u64 + encoded.len() as u64 % 2;
+        let file_end = position + chunk_len + 8 + index.len() as u64 + 16;
+        if file_end > MAX_FILE_SIZE {
+            drop(out);
+            let _ = std::fs::remove_file(&path);
+            return Err(anyhow!("AVI output would be larger than 4 GiB; use fewer frames or the y4m format"));
+        }
+        
+        // idx1 offsets are relative to the "movi" fourcc
+        let offset = u32::try_from(position - (movi_start + 8))?;
+        let size = u32::try_from(encoded.len())?;
+        index.extend_from_slice(codec.chunk_id());
+        push_u32(&mut index, AVIIF_KEYFRAME);
+        push_u32(&mut index, offset);
+        push_u32(&mut index, size);
+        
+        max_frame = max_frame.max(size);
+        write_chunk(&mut out, codec.chunk_id(), &encoded)?;
+        pb.inc(1);
+    }
+    
+    pb.finish_and_clear();
+    
+    let movi_end = out.stream_position()?;
+    write_chunk(&mut out, b"idx1", &index)?;
+    let file_end = out.stream_position()?;
+    
+    // Patch the sizes now that everything has been written
+    out.seek(SeekFrom::Start(4))?;
+    out.write_all(&u32::try_from(file_end - 8)?.to_le_bytes())?;
+    out.seek(SeekFrom::Start(12))?;
+    write_chunk(&mut out, b"LIST", &header_list(&metadata, codec, max_frame)?)?;
+    out.seek(SeekFrom::Start(movi_start + 4))?;
+    out.write_all(&u32::try_from(movi_end - movi_start - 8)?.to_le_bytes())?;
+    out.flush()?;
+    
+    println!("Video written to: {}", path.as_ref().display());
+    Ok(())
+}
+
+// Frame-by-frame demuxer for AVI files
+pub struct AviReader {
+    input: BufReader<File>,
+    pub width: u32,
+    pub height: u32,
+    codec: AviCodec,
+    metadata: Option<VideoMetadata>,
+    // Length of the file, which no chunk may run past
+    file_len: u64,
+    // Position and end of the chunks still to read in the movi list
+    movi_pos: u64,
+    movi_end: u64,
+}
+
+fn read_u32<R: Read>(input: &mut R) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_fourcc<R: Read>(input: &mut R) -> Result<[u8; 4]> {
+    let mut buf = [0u8; 4];
+    input.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+// Read a chunk body of `size` bytes, refusing sizes that run past `end` (the
+// end of the file) before allocating anything for them
+fn read_body<R: Read + Seek>(input: &mut R, size: u64, end: u64) -> Result<Vec<u8>> {
+    let start = input.stream_position()?;
+    if start + size > end {
+        return Err(anyhow!("AVI chunk of {} bytes runs past the end of the file", size));
+    }
+    let mut body = vec![0u8; size as usize];
+    input.read_exact(&mut body)?;
+    Ok(body)
+}
+
+fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]])
+}
+
+impl AviReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<AviReader> {
+        let file = File::open(&path)
+            .with_context(|| format!("Failed to open {}", path.as_ref().display()))?;
+        let file_len = file.metadata()?.len();
+        let mut input = BufReader::new(file);
+        
+        if &read_fourcc(&mut input)? != b"RIFF" {
+            return Err(anyhow!("Not a RIFF file"));
+        }
+        let riff_end = read_u32(&mut input)? as u64 + 8;
+        if &read_fourcc(&mut input)? != b"AVI " {
+            return Err(anyhow!("RIFF file is not an AVI"));
+        }
+        
+        let mut width = 0;
+        let mut height = 0;
+        let mut codec = None;
+        let mut metadata = None;
+        let mut movi = None;
+        
+        // Walk the top-level chunks, descending into hdrl/strl for the headers
+        let mut pos = 12;
+        let mut list_ends = vec![riff_end];
+        while let Some(&end) = list_ends.last() {
+            if pos + 8 > end {
+                list_ends.pop();
+                continue;
+            }
+            
+            input.seek(SeekFrom::Start(pos))?;
+            let id = match read_fourcc(&mut input) {
+                Ok(id) => id,
+                Err(_) => break,
+            };
+            let size = read_u32(&mut input)? as u64;
+            let body_start = pos + 8;
+            let next = body_start + size + size % 2;
+            
+            match &id {
+                b"LIST" => {
+                    let list_type = read_fourcc(&mut input)?;
+                    match &list_type {
+                        b"hdrl" | b"strl" => {
+                            list_ends.push(body_start + size);
+                            pos = body_start + 4;
+                            continue;
+                        }
+                        b"movi" => movi = Some((body_start + 4, body_start + size)),
+                        _ => (),
+                    }
+                }
+                b"avih" => {
+                    let avih = read_body(&mut input, size, file_len)?;
+                    if avih.len() >= 40 {
+                        width = le_u32(&avih, 32);
+                        height = le_u32(&avih, 36);
+                    }
+                }
+                b"strf" => {
+                    let strf = read_body(&mut input, size, file_len)?;
+                    if strf.len() >= 20 && codec.is_none() {
+                        codec = match &strf[16..20] {
+                            b"MJPG" => Some(AviCodec::Mjpeg),
+                            [0, 0, 0, 0] => Some(AviCodec::Raw),
+                            other => return Err(anyhow!("Unsupported AVI codec: {}", String::from_utf8_lossy(other))),
+                        };
+                    }
+                }
+                id if id == METADATA_CHUNK => {
+                    let json = read_body(&mut input, size, file_len)?;
+                    metadata = serde_json::from_slice(&json).ok();
+                }
+                _ => (),
+            }
+            
+            pos = next;
+        }
+        
+        let (movi_pos, movi_end) = movi.ok_or_else(|| anyhow!("AVI file has no movi list"))?;
+        let codec = codec.ok_or_else(|| anyhow!("AVI file has no video stream format"))?;
+        if width == 0 || height == 0 {
+            return Err(anyhow!("AVI header is missing the frame size"));
+        }
+        
+        Ok(AviReader {
+            input,
+            width,
+            height,
+            codec,
+            metadata,
+            file_len,
+            movi_pos,
+            movi_end,
+        })
+    }
+    
+    // Read the next video frame, or None at the end of the movi list
+    pub fn next_frame(&mut self) -> Result<Option<RgbImage>> {
+        while self.movi_pos + 8 <= self.movi_end {
+            self.input.seek(SeekFrom::Start(self.movi_pos))?;
+            let id = read_fourcc(&mut self.input)?;
+            let size = read_u32(&mut self.input)? as u64;
+            
+            // Frames may be grouped into "rec " lists; step inside them
+            if &id == b"LIST" {
+                self.movi_pos += 12;
+                continue;
+            }
+            
+            self.movi_pos += 8 + size + size % 2;
+            
+            // Only video chunks (##db / ##dc) carry frames
+            if &id[2..4] != b"db" && &id[2..4] != b"dc" {
+                continue;
+            }
+            
+            let body = read_body(&mut self.input, size, self.file_len).context("AVI file ends mid-frame")?;
+            return self.decode_frame(&body).map(Some);
+        }
+        
+        Ok(None)
+    }
+    
+    // Layout recorded by `write_avi`, if this file was written by us
+    pub fn metadata(&self) -> Option<VideoMetadata> {
+        self.metadata.clone()
+    }
+    
+    fn decode_frame(&self, body: &[u8]) -> Result<RgbImage> {
+        match self.codec {
+            AviCodec::Mjpeg => {
+                let img = image::load_from_memory_with_format(body, ImageFormat::Jpeg)
+                    .context("Invalid JPEG frame in AVI")?;
+                Ok(img.to_rgb8())
+            }
+            AviCodec::Raw => {
+                let row_len = raw_row_len(self.width);
+                if body.len() < row_len * self.height as usize {
+                    return Err(anyhow!("Truncated bitmap frame in AVI"));
+                }
+                
+                let mut img = RgbImage::new(self.width, self.height);
+                for (x, y, pixel) in img.enumerate_pixels_mut() {
+                    let row = (self.height - 1 - y) as usize;
+                    let i = row * row_len + x as usize * 3;
+                    *pixel = Rgb([body[i + 2], body[i + 1], body[i]]);
+                }
+                Ok(img)
+            }
+        }
+    }
+}
+
+impl Iterator for AviReader {
+    type Item = Result<RgbImage>;
+    
+    fn next(&mut self) -> Option<Result<RgbImage>> {
+        self.next_frame().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::OutputMode;
+    
+    fn write_and_read(data: &Data, settings: &Settings, codec: AviCodec) -> (Option<VideoMetadata>, Vec<RgbImage>) {
+        let path = std::env::temp_dir().join(format!("sdh_avi_{}_{:?}_{:?}.avi", std::process::id(), data.out_mode, codec));
+        write_avi(&path, data, settings, codec).unwrap();
+        let mut reader = AviReader::open(&path).unwrap();
+        let metadata = reader.metadata();
+        let mut frames = Vec::new();
+        while let Some(frame) = reader.next_frame().unwrap() {
+            frames.push(frame);
+        }
+        std::fs::remove_file(&path).unwrap();
+        (metadata, frames)
+    }
+    
+    fn payload() -> Vec<u8> {
+        (0..4000u32).map(|i| (i * 73 + i / 3) as u8).collect()
+    }
+    
+    // 426 pixels of 3 bytes leave every bitmap row padded
+    #[test]
+    fn raw_round_trip() {
+        let settings = Settings::new(2, 1, 10, 426, 240);
+        let data = Data::new(payload(), OutputMode::Color);
+        let (metadata, frames) = write_and_read(&data, &settings, AviCodec::Raw);
+        
+        let metadata = metadata.unwrap();
+        assert_eq!(metadata.mode, OutputMode::Color);
+        assert_eq!((metadata.width, metadata.height, metadata.data_size), (426, 240, 4000));
+        let expected: Vec<RgbImage> = etcher::frames(&data, &settings).collect();
+        assert_eq!(frames, expected);
+    }
+    
+    #[test]
+    fn mjpeg_round_trip() {
+        let settings = Settings::new(2, 1, 10, 256, 144);
+        let data = Data::new(payload(), OutputMode::Binary);
+        let (metadata, frames) = write_and_read(&data, &settings, AviCodec::Mjpeg);
+        
+        assert_eq!(metadata.unwrap().frames, frames.len() as u64);
+        let expected: Vec<RgbImage> = etcher::frames(&data, &settings).collect();
+        assert_eq!(frames.len(), expected.len());
+        for (frame, expected) in frames.iter().zip(&expected) {
+            assert_eq!(frame.dimensions(), expected.dimensions());
+            let error: u64 = frame.as_raw().iter().zip(expected.as_raw())
+                .map(|(&a, &b)| (a as i32 - b as i32).unsigned_abs() as u64)
+                .sum();
+            assert!(error < frame.as_raw().len() as u64 * 2, "JPEG frames drifted by {} in total", error);
+        }
+    }
+    
+    #[test]
+    fn rejects_chunks_past_the_end_of_the_file() {
+        let settings = Settings::new(4, 1, 10, 256, 144);
+        let data = Data::new(payload(), OutputMode::Binary);
+        let path = std::env::temp_dir().join(format!("sdh_avi_{}_truncated.avi", std::process::id()));
+        write_avi(&path, &data, &settings, AviCodec::Raw).unwrap();
+        
+        // Claim the first frame is nearly 4 GiB long
+        let mut bytes = std::fs::read(&path).unwrap();
+        let movi = bytes.windows(4).position(|w| w == b"movi").unwrap();
+        assert_eq!(&bytes[movi + 4..movi + 8], b"00db");
+        bytes[movi + 8..movi + 12].copy_from_slice(&0xffff_fff0u32.to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        
+        let mut reader = AviReader::open(&path).unwrap();
+        let error = reader.next_frame().unwrap_err();
+        assert!(format!("{:#}", error).contains("past the end of the file"), "{:#}", error);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
\ No newline at end of file
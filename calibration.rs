@@ -0,0 +1,345 @@
+// Calibration frames: whole frames of known colours that let the decoder undo
+// what the codec or player did to the levels of the video.
+//
+// The encoder puts them in front of the data frames (and, if asked, again
+// every few frames). Their data area is tiled with large patches of every
+// colour the mode draws blocks in, plus a gray ramp from black to white.
+// Patches under the corner markers are left out. Their footer carries
+// CALIBRATION_INDEX in place of a frame index, which is how the decoder tells
+// them apart.
+//
+// The decoder measures the patches and fits one lookup table per channel,
+// mapping the value it sees back to the value that was drawn. That corrects
+// gamma, limited-range (16-235) squashing and colour casts for every data frame
+// after it, before the blocks are classified. Periodic calibration frames
+// replace the table, following drift over a long video.
+
+use image::{Rgb, RgbImage};
+use crate::decoder::FrameLayout;
+use crate::etcher;
+use crate::fiducial::Markers;
+use crate::frame_footer::{self, FrameFooter, CALIBRATION_INDEX};
+use crate::frame_header::{self, FrameHeader};
+use crate::gray;
+use crate::palette::Palette;
+use crate::settings::{OutputMode, Settings};
+
+// Patches across the width of the frame
+const PATCH_COLUMNS: u32 = 16;
+// Steps of the gray ramp, black and white included
+const RAMP_STEPS: u32 = 17;
+// Tables whose measured values span less than this are not trusted
+const MIN_RANGE: f64 = 32.0;
+
+// The colours a calibration frame shows: every block colour of `mode`, then
+// the gray ramp
+fn patches(mode: OutputMode) -> Vec<Rgb<u8>> {
+    let mut colors = match mode {
+        OutputMode::Color => Vec::new(),
+        OutputMode::Binary => vec![Rgb([0, 0, 0]), Rgb([255, 255, 255])],
+        OutputMode::Palette8 | OutputMode::Palette16 => Palette::nominal(mode).unwrap().colors().to_vec(),
+        mode => {
+            let levels = mode.levels().unwrap();
+            (0..levels).map(|level| gray::symbol_color(gray::to_gray(level), levels)).collect()
+        }
+    };
+    colors.extend((0..RAMP_STEPS).map(|step| {
+        let value = (step * 255 / (RAMP_STEPS - 1)) as u8;
+        Rgb([value, value, value])
+    }));
+    colors
+}
+
+// Side of a patch and the rows of patches that fit between the strips
+fn patch_grid(width: u32, top: u32, bottom: u32) -> (u32, u32) {
+    let side = width / PATCH_COLUMNS;
+    if side == 0 {
+        return (0, 0);
+    }
+    (side, bottom.saturating_sub(top) / side)
+}
+
+// Render a calibration frame for `mode` with the same header strip as the
+// data frames
+pub fn render_frame(mode: OutputMode, metadata: &etcher::VideoMetadata, settings: &Settings) -> RgbImage {
+    let mut img = RgbImage::new(settings.width as u32, settings.height as u32);
+    frame_header::render(&mut img, &FrameHeader::new(metadata));
+    
+    let colors = patches(mode);
+    let (top, bottom) = (etcher::data_top(settings), etcher::data_bottom(settings));
+    let markers = Markers::new(img.width(), top, bottom);
+    let (side, rows) = patch_grid(img.width(), top, bottom);
+    for patch in 0..rows * PATCH_COLUMNS {
+        let color = colors[patch as usize % colors.len()];
+        let (left, patch_top) = ((patch % PATCH_COLUMNS) * side, top + (patch / PATCH_COLUMNS) * side);
+        if markers.covers(left, patch_top, side, side) {
+            continue;
+        }
+        for y in patch_top..patch_top + side {
+            for x in left..left + side {
+                img.put_pixel(x, y, color);
+            }
+        }
+    }
+    markers.render(&mut img);
+    
+    frame_footer::render(&mut img, FrameFooter {
+        index: CALIBRATION_INDEX,
+        total: metadata.frames as u32,
+        data_crc: 0,
+    });
+    img
+}
+
+// Whether `frame` is a calibration frame
+pub fn is_calibration(frame: &RgbImage) -> bool {
+    frame_footer::read(frame).is_some_and(|footer| footer.index == CALIBRATION_INDEX)
+}
+
+// Number of calibration frames `etcher::frames` puts among `frames` data frames
+pub fn frame_count(frames: u64, settings: &Settings) -> u64 {
+    let periodic = match settings.calibration_interval {
+        0 => 0,
+        interval => frames.saturating_sub(1) / interval as u64,
+    };
+    settings.calibration_frames as u64 + periodic
+}
+
+// Per-channel lookup table from the values seen to the values drawn
+#[derive(Debug, Clone)]
+pub struct Lut {
+    tables: [[u8; 256]; 3],
+    // How the black and white ends of the ramp came out
+    black: [u8; 3],
+    white: [u8; 3],
+}
+
+impl Lut {
+    // Fit the tables to the patches of a calibration frame. None if the frame
+    // holds no usable patches.
+    pub fn measure(frame: &RgbImage, layout: &FrameLayout) -> Option<Lut> {
+        let colors = patches(layout.mode);
+        let (side, rows) = patch_grid(frame.width(), layout.top, layout.bottom);
+        if side == 0 || frame.width() != layout.width || frame.height() != layout.height {
+            return None;
+        }
+        
+        // Mean of the inner half of every patch, summed per colour
+        let markers = layout.markers.then(|| Markers::new(layout.width, layout.top, layout.bottom));
+        let margin = side / 4;
+        let inner = (side - margin * 2).max(1);
+        let mut sums = vec![([0.0f64; 3], 0u32); colors.len()];
+        for patch in 0..rows * PATCH_COLUMNS {
+            let (left, patch_top) = ((patch % PATCH_COLUMNS) * side, layout.top + (patch / PATCH_COLUMNS) * side);
+            if markers.is_some_and(|markers| markers.covers(left, patch_top, side, side)) {
+                continue;
+            }
+            let (left, patch_top) = (left + margin, patch_top + margin);
+            let mut total = [0u64; 3];
+            for y in patch_top..patch_top + inner {
+                for x in left..left + inner {
+                    for (sum, channel) in total.iter_mut().zip(frame.get_pixel(x, y).0) {
+                        *sum += channel as u64;
+                    }
+                }
+            }
+            
+            let entry = &mut sums[patch as usize % colors.len()];
+            for (sum, channel) in entry.0.iter_mut().zip(total) {
+                *sum += channel as f64 / (inner * inner) as f64;
+            }
+            entry.1 += 1;
+        }
+        
+        let mut tables = [[0u8; 256]; 3];
+        for (channel, table) in tables.iter_mut().enumerate() {
+            let points: Vec<(f64, f64)> = sums.iter()
+                .zip(&colors)
+                .filter(|((_, count), _)| *count > 0)
+                .map(|((sum, count), color)| (sum[channel] / *count as f64, color.0[channel] as f64))
+                .collect();
+            *table = fit(points)?;
+        }
+        
+        let measured = |index: usize| {
+            let (sum, count) = sums[index];
+            sum.map(|value| (value / count.max(1) as f64).round() as u8)
+        };
+        Some(Lut {
+            tables,
+            black: measured(colors.len() - RAMP_STEPS as usize),
+            white: measured(colors.len() - 1),
+        })
+    }
+    
+    pub fn apply(&self, frame: &mut RgbImage) {
+        for pixel in frame.pixels_mut() {
+            for (channel, value) in pixel.0.iter_mut().enumerate() {
+                *value = self.tables[channel][*value as usize];
+            }
+        }
+    }
+    
+    // Largest change the tables make to any value
+    pub fn max_correction(&self) -> u8 {
+        self.tables.iter()
+            .flat_map(|table| table.iter().enumerate().map(|(seen, &drawn)| (seen as i32 - drawn as i32).unsigned_abs() as u8))
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+// Monotonic table through (seen, drawn) points: isotonic regression on the
+// points sorted by the value seen, linear between them and flat past the ends.
+// None if the points do not span enough of the range to mean anything.
+fn fit(mut points: Vec<(f64, f64)>) -> Option<[u8; 256]> {
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let (first, last) = (points.first()?.0, points.last()?.0);
+    if last - first < MIN_RANGE {
+        return None;
+    }
+    
+    // Pool adjacent points until the drawn values never go down
+    let mut pooled: Vec<(f64, f64, f64)> = Vec::new(); // seen, drawn, weight
+    for (seen, drawn) in points {
+        pooled.push((seen, drawn, 1.0));
+        while pooled.len() > 1 && pooled[pooled.len() - 2].1 >= pooled[pooled.len() - 1].1 {
+            let (b_seen, b_drawn, b_weight) = pooled.pop().unwrap();
+            let a = pooled.last_mut().unwrap();
+            let weight = a.2 + b_weight;
+            *a = ((a.0 * a.2 + b_seen * b_weight) / weight, (a.1 * a.2 + b_drawn * b_weight) / weight, weight);
+        }
+    }
+    
+    let mut table = [0u8; 256];
+    for (seen, entry) in table.iter_mut().enumerate() {
+        let seen = seen as f64;
+        let next = pooled.iter().position(|point| point.0 >= seen);
+        let drawn = match next {
+            Some(0) => pooled[0].1,
+            None => pooled.last().unwrap().1,
+            Some(i) => {
+                let (a, b) = (pooled[i - 1], pooled[i]);
+                a.1 + (b.1 - a.1) * (seen - a.0) / (b.0 - a.0)
+            }
+        };
+        *entry = drawn.round().clamp(0.0, 255.0) as u8;
+    }
+    Some(table)
+}
+
+// Takes the calibration frames out of a stream of frames and corrects the
+// data frames after them
+pub struct Calibrated<I> {
+    frames: I,
+    layout: FrameLayout,
+    lut: Option<Lut>,
+    seen: u64,
+}
+
+impl<I> Calibrated<I> {
+    pub fn new(frames: I, layout: &FrameLayout) -> Calibrated<I> {
+        Calibrated {
+            frames,
+            layout: layout.clone(),
+            lut: None,
+            seen: 0,
+        }
+    }
+}
+
+impl<I> Iterator for Calibrated<I>
+where
+    I: Iterator<Item = anyhow::Result<RgbImage>>,
+{
+    type Item = anyhow::Result<RgbImage>;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut frame = match self.frames.next()? {
+                Ok(frame) => frame,
+                Err(e) => return Some(Err(e)),
+            };
+            
+            if !is_calibration(&frame) {
+                if let Some(lut) = &self.lut {
+                    lut.apply(&mut frame);
+                }
+                return Some(Ok(frame));
+            }
+            
+            self.seen += 1;
+            match Lut::measure(&frame, &self.layout) {
+                Some(lut) => {
+                    if self.lut.is_none() {
+                        println!(
+                            "Calibration frame: black reads as {:?}, white as {:?}; correcting levels by up to {}",
+                            lut.black, lut.white, lut.max_correction()
+                        );
+                    }
+                    self.lut = Some(lut);
+                }
+                None => println!("Calibration frame {} is unreadable, keeping the previous levels", self.seen),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::LayoutHint;
+    use crate::etcher::VideoMetadata;
+    use crate::settings::Data;
+    
+    fn layout_of(frame: &RgbImage) -> FrameLayout {
+        let header = frame_header::read(frame).unwrap();
+        FrameLayout::resolve(Some(&header), None, &LayoutHint::default(), frame.width(), frame.height()).unwrap()
+    }
+    
+    #[test]
+    fn lut_undoes_gamma_and_limited_range() {
+        let settings = Settings::new(4, 1, 10, 640, 360);
+        let data = Data::new(vec![0; 100], OutputMode::Gray8);
+        let mut frame = render_frame(OutputMode::Gray8, &VideoMetadata::new(&data, &settings), &settings);
+        let layout = layout_of(&frame);
+        
+        // A gamma of 1.3, then squashed into 16-235
+        let distort = |value: u8| (16.0 + 219.0 * (value as f64 / 255.0).powf(1.3)).round() as u8;
+        frame.pixels_mut().for_each(|pixel| pixel.0 = pixel.0.map(distort));
+        
+        let lut = Lut::measure(&frame, &layout).unwrap();
+        assert_eq!((lut.black, lut.white), ([16; 3], [235; 3]));
+        for value in 0..=255u8 {
+            for table in &lut.tables {
+                let restored = table[distort(value) as usize];
+                // The squash merges neighbouring values, so allow the steps it leaves
+                assert!((restored as i32 - value as i32).abs() <= 3, "{} came back as {}", value, restored);
+            }
+        }
+    }
+    
+    #[test]
+    fn repeated_calibration_frames_are_removed() {
+        let mut settings = Settings::new(4, 1, 10, 320, 240);
+        settings.calibration_frames = 1;
+        settings.calibration_interval = 2;
+        let frame_bytes = (etcher::frame_bits(&settings, OutputMode::Binary) / 8) as usize;
+        let data = Data::new(vec![0x3c; frame_bytes * 9 / 2], OutputMode::Binary);
+        
+        // One up front, then before data frames 2 and 4
+        let frames: Vec<RgbImage> = etcher::frames(&data, &settings).collect();
+        assert_eq!(frame_count(5, &settings), 3);
+        assert_eq!(frames.len(), 8);
+        assert_eq!(frames.iter().map(is_calibration).collect::<Vec<_>>(), [true, false, false, true, false, false, true, false]);
+        
+        let layout = layout_of(&frames[1]);
+        let mut calibrated = Calibrated::new(frames.into_iter().map(Ok), &layout);
+        let data_frames: Vec<RgbImage> = calibrated.by_ref().map(Result::unwrap).collect();
+        assert_eq!(calibrated.seen, 3);
+        assert_eq!(data_frames.len(), 5);
+        for (index, frame) in data_frames.iter().enumerate() {
+            assert_eq!(*frame, etcher::render_frame(&data, &settings, index as u64), "data frame {}", index);
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,256 @@
+// Optional compression of the embedded file before it is encrypted and
+// rendered. The codec goes into the container header and `dislodge` undoes it
+// after decryption; the SHA-256 and signature always cover the original file.
+
+use std::borrow::Cow;
+use std::io::{Read, Write};
+use anyhow::{Result, anyhow};
+use flate2::Compression as DeflateLevel;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use serde::{Serialize, Deserialize};
+use crate::container::Container;
+
+pub const DEFAULT_ZSTD_LEVEL: i32 = 19;
+pub const DEFAULT_DEFLATE_LEVEL: i32 = 9;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    None,
+    Zstd,
+    Deflate,
+}
+
+// What --compress and --compress-level asked for
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Options {
+    pub codec: Codec,
+    // Pick zstd, unless the data looks compressed already or does not shrink
+    pub auto: bool,
+    pub level: Option<i32>,
+}
+
+// Magic numbers of formats that are compressed already
+const COMPRESSED_MAGIC: &[&[u8]] = &[
+    b"PK\x03\x04",                   // zip, docx, jar, apk
+    b"\x1f\x8b",                     // gzip
+    b"\x28\xb5\x2f\xfd",             // zstd
+    b"BZh",                          // bzip2
+    b"\xfd7zXZ\x00",                 // xz
+    b"7z\xbc\xaf\x27\x1c",           // 7-Zip
+    b"Rar!\x1a\x07",                 // RAR
+    b"\x89PNG",                      // PNG
+    b"\xff\xd8\xff",                 // JPEG
+    b"GIF8",                         // GIF
+    b"OggS",                         // Ogg
+    b"ID3",                          // MP3
+    b"fLaC",                         // FLAC
+    b"\x1a\x45\xdf\xa3",             // Matroska, WebM
+];
+
+// Whether `data` starts like a format that is already compressed
+fn looks_compressed(data: &[u8]) -> bool {
+    // MP4, MOV and HEIC have their signature at offset 4
+    let iso_media = data.len() >= 8 && &data[4..8] == b"ftyp";
+    let webp = data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP";
+    iso_media || webp || COMPRESSED_MAGIC.iter().any(|magic| data.starts_with(magic))
+}
+
+fn level_for(codec: Codec, level: Option<i32>) -> Result<i32> {
+    match codec {
+        Codec::None => Ok(0),
+        Codec::Zstd => {
+            let level = level.unwrap_or(DEFAULT_ZSTD_LEVEL);
+            if !zstd::compression_level_range().contains(&level) {
+                return Err(anyhow!("zstd compression level must be between 1 and {}", zstd::compression_level_range().end()));
+            }
+            Ok(level)
+        }
+        Codec::Deflate => {
+            let level = level.unwrap_or(DEFAULT_DEFLATE_LEVEL);
+            if !(0..=9).contains(&level) {
+                return Err(anyhow!("deflate compression level must be between 0 and 9"));
+            }
+            Ok(level)
+        }
+    }
+}
+
+fn encode(data: &[u8], codec: Codec, level: i32) -> Result<Vec<u8>> {
+    match codec {
+        Codec::None => Ok(data.to_vec()),
+        Codec::Zstd => Ok(zstd::encode_all(data, level)?),
+        Codec::Deflate => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), DeflateLevel::new(level as u32));
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?)
+        }
+    }
+}
+
+// Compress `data` as `options` ask, returning the bytes to store and the codec used
+pub fn compress<'a>(data: &'a [u8], options: &Options) -> Result<(Cow<'a, [u8]>, Codec)> {
+    let codec = if options.auto {
+        if looks_compressed(data) {
+            println!("Compression: the data looks compressed already, storing it as is");
+            return Ok((Cow::Borrowed(data), Codec::None));
+        }
+        Codec::Zstd
+    } else {
+        options.codec
+    };
+    
+    let level = level_for(codec, options.level)?;
+    if codec == Codec::None {
+        return Ok((Cow::Borrowed(data), Codec::None));
+    }
+    
+    let compressed = encode(data, codec, level)?;
+    if options.auto && compressed.len() >= data.len() {
+        println!("Compression: {:?} does not shrink the data, storing it as is", codec);
+        return Ok((Cow::Borrowed(data), Codec::None));
+    }
+    
+    println!(
+        "Compression: {:?} level {}, {} -> {} bytes ({:.1}%)",
+        codec, level, data.len(), compressed.len(),
+        compressed.len() as f64 * 100.0 / data.len().max(1) as f64
+    );
+    Ok((Cow::Owned(compressed), codec))
+}
+
+// Decompress `data`, which has to come out at exactly `size` bytes. Reading
+// stops one byte past that, so a corrupt or crafted stream cannot expand into
+// more memory than the header announced.
+pub fn decompress(data: &[u8], codec: Codec, size: u64) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match codec {
+        Codec::None => out.extend_from_slice(data),
+        Codec::Zstd => {
+            zstd::stream::read::Decoder::new(data)?
+                .take(size.saturating_add(1))
+                .read_to_end(&mut out)
+                .map_err(|e| anyhow!("zstd decompression failed, the data is corrupt: {}", e))?;
+        }
+        Codec::Deflate => {
+            DeflateDecoder::new(data)
+                .take(size.saturating_add(1))
+                .read_to_end(&mut out)
+                .map_err(|e| anyhow!("deflate decompression failed, the data is corrupt: {}", e))?;
+        }
+    }
+    
+    if out.len() as u64 > size {
+        return Err(anyhow!("{:?} data expands past the {} bytes recorded in the header, the data is corrupt", codec, size));
+    }
+    if out.len() as u64 != size {
+        return Err(anyhow!("{:?} data expands to {} bytes instead of the {} recorded in the header, the data is corrupt", codec, out.len(), size));
+    }
+    Ok(out)
+}
+
+// Decompress a (decrypted) container in place
+pub fn restore(container: &mut Container) -> Result<()> {
+    let Some(header) = &container.header else {
+        return Ok(());
+    };
+    let codec = header.compression;
+    if codec == Codec::None {
+        return Ok(());
+    }
+    let size = header.original_size
+        .ok_or_else(|| anyhow!("The container is {:?} compressed but does not record the original size", codec))?;
+    
+    let stored = container.payload.len();
+    container.payload = decompress(&container.payload, codec, size)?;
+    println!("Decompressed {:?}: {} -> {} bytes", codec, stored, container.payload.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container;
+    use crate::settings::{OutputMode, Settings};
+    
+    fn text() -> Vec<u8> {
+        b"the quick brown fox jumps over the lazy dog\n".repeat(200)
+    }
+    
+    #[test]
+    fn round_trip() {
+        let data = text();
+        for codec in [Codec::None, Codec::Zstd, Codec::Deflate] {
+            let (stored, used) = compress(&data, &Options { codec, ..Default::default() }).unwrap();
+            assert_eq!(used, codec);
+            if codec != Codec::None {
+                assert!(stored.len() < data.len() / 10, "{:?} stored {} bytes", codec, stored.len());
+            }
+            assert_eq!(decompress(&stored, used, data.len() as u64).unwrap(), data);
+        }
+    }
+    
+    #[test]
+    fn auto_skips_compressed_data() {
+        let data = text();
+        let (zstd, codec) = compress(&data, &Options { auto: true, ..Default::default() }).unwrap();
+        assert_eq!(codec, Codec::Zstd);
+        
+        let (stored, codec) = compress(&zstd, &Options { auto: true, ..Default::default() }).unwrap();
+        assert_eq!(codec, Codec::None);
+        assert_eq!(stored, zstd);
+        
+        // Random bytes do not shrink
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 32) as u8
+            })
+            .collect();
+        let (_, codec) = compress(&noise, &Options { auto: true, ..Default::default() }).unwrap();
+        assert_eq!(codec, Codec::None);
+    }
+    
+    #[test]
+    fn rejects_bad_levels_and_corrupt_data() {
+        assert!(compress(&text(), &Options { codec: Codec::Deflate, level: Some(10), ..Default::default() }).is_err());
+        assert!(compress(&text(), &Options { codec: Codec::Zstd, level: Some(99), ..Default::default() }).is_err());
+        
+        let data = text();
+        let (stored, codec) = compress(&data, &Options { codec: Codec::Zstd, ..Default::default() }).unwrap();
+        assert!(decompress(&stored[..stored.len() / 2], codec, data.len() as u64).is_err());
+    }
+    
+    #[test]
+    fn stops_at_the_recorded_size() {
+        // A megabyte of zeros packs into a few dozen bytes
+        let zeros = vec![0u8; 1 << 20];
+        for codec in [Codec::Zstd, Codec::Deflate] {
+            let (stored, _) = compress(&zeros, &Options { codec, ..Default::default() }).unwrap();
+            assert!(stored.len() < 2000);
+            assert!(decompress(&stored, codec, 4096).is_err());
+            assert!(decompress(&stored, codec, (1 << 20) + 1).is_err());
+            assert_eq!(decompress(&stored, codec, 1 << 20).unwrap().len(), 1 << 20);
+        }
+    }
+    
+    #[test]
+    fn survives_a_crafted_original_size() {
+        let settings = Settings::new(2, 1, 10, 256, 144);
+        let options = Options { codec: Codec::Zstd, ..Default::default() };
+        let bytes = container::pack(&text(), "notes.txt", OutputMode::Binary, &settings, &options, &Default::default()).unwrap();
+        
+        let mut crafted = container::unpack(bytes.clone()).unwrap();
+        crafted.header.as_mut().unwrap().original_size = Some(u64::MAX);
+        assert!(restore(&mut crafted).is_err());
+        
+        let mut container = container::unpack(bytes).unwrap();
+        restore(&mut container).unwrap();
+        assert_eq!(container.payload, text());
+    }
+}
\ No newline at end of file
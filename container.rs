@@ -0,0 +1,302 @@
+// Versioned binvid v2 container.
+//
+// Layout: MAGIC (6 bytes) | format version (u16 LE) | header length (u32 LE)
+//         | header (JSON) | payload
+//
+// The same bytes are written as a .binvid file and rendered into video
+// frames, so every output format carries the full header.
+
+use std::borrow::Cow;
+use std::path::Path;
+use anyhow::{Result, anyhow};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use crate::compress::{self, Codec};
+use crate::crypto::{self, Encryption, Protection, Sealed};
+use crate::signing::{self, ContainerSignature};
+use crate::etcher::{self, VideoMetadata};
+use crate::fec;
+use crate::fountain;
+use crate::frame_footer;
+use crate::settings::{OutputMode, Settings};
+
+pub const MAGIC: &[u8; 6] = b"BINVV2";
+pub const FORMAT_VERSION: u16 = 2;
+
+// Magic, version and header length
+const PREAMBLE_LEN: usize = 6 + 2 + 4;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContainerHeader {
+    // Layout of the frames the container is rendered into
+    pub metadata: VideoMetadata,
+    // Name of the embedded file, without its directory
+    pub filename: Option<String>,
+    // CRC32 of the payload
+    pub checksum: u32,
+    // SHA-256 of the embedded file, hex encoded. Every container records it;
+    // only the header of an encrypted one leaves it out, as it is then stored
+    // inside the ciphertext.
+    pub sha256: Option<String>,
+    // How the file was compressed before encryption
+    #[serde(default)]
+    pub compression: Codec,
+    // Length of the file before compression, which caps how far decompressing
+    // it may go; None when it is stored uncompressed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_size: Option<u64>,
+    // Present when the payload is encrypted; the file name and SHA-256 are
+    // then stored inside the ciphertext instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+    // Ed25519 signature over the file and the rest of this header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<ContainerSignature>,
+}
+
+// Outcome of checking a payload against the hashes recorded when it was embedded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Integrity {
+    // The SHA-256 matches
+    Verified,
+    // The payload does not match what was embedded
+    Mismatch { expected: String, actual: String },
+    // No v2 header, so there is nothing to check against
+    Unchecked,
+}
+
+// Result of reading any of our formats
+pub struct Container {
+    // None for legacy BINVID/COLVID files and raw frame data
+    pub header: Option<ContainerHeader>,
+    pub payload: Vec<u8>,
+}
+
+impl Container {
+    // Payload without a v2 header
+    pub fn raw(payload: Vec<u8>) -> Container {
+        Container {
+            header: None,
+            payload,
+        }
+    }
+    
+    // Check the (decrypted) payload against the SHA-256 in its header
+    pub fn verify(&self) -> Integrity {
+        let Some(header) = &self.header else {
+            return Integrity::Unchecked;
+        };
+        
+        let actual = sha256_hex(&self.payload);
+        match &header.sha256 {
+            Some(expected) if actual.eq_ignore_ascii_case(expected) => Integrity::Verified,
+            expected => Integrity::Mismatch {
+                expected: expected.clone().unwrap_or_else(|| "no SHA-256 recorded".to_string()),
+                actual,
+            },
+        }
+    }
+}
+
+// Hex encoded SHA-256 of `bytes`
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    crypto::to_hex(&Sha256::digest(bytes))
+}
+
+fn serialize(header: &ContainerHeader, payload: &[u8]) -> Result<Vec<u8>> {
+    let json = serde_json::to_vec(header)?;
+    
+    let mut bytes = Vec::with_capacity(PREAMBLE_LEN + json.len() + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(json.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&json);
+    bytes.extend_from_slice(payload);
+    Ok(bytes)
+}
+
+// Wrap `payload` in a v2 container laid out for `mode` and `settings`,
+// compressing and encrypting it first if `compression` and `protection` ask for it
+pub fn pack<P: AsRef<Path>>(
+    payload: &[u8],
+    source: P,
+    mode: OutputMode,
+    settings: &Settings,
+    compression: &compress::Options,
+    protection: &Protection,
+) -> Result<Vec<u8>> {
+    let sealed = Sealed {
+        filename: source.as_ref().file_name().map(|name| name.to_string_lossy().to_string()),
+        sha256: sha256_hex(payload),
+    };
+    
+    let original_size = payload.len() as u64;
+    let (payload, codec) = compress::compress(payload, compression)?;
+    let payload = payload.as_ref();
+    
+    // Encryption moves the file name and SHA-256 into the ciphertext
+    let (payload, filename, sha256, encryption) = if protection.is_encrypted() {
+        let (ciphertext, encryption) = crypto::seal(payload, &sealed, protection)?;
+        (Cow::Owned(ciphertext), None, None, Some(encryption))
+    } else {
+        (Cow::Borrowed(payload), sealed.filename.clone(), Some(sealed.sha256.clone()), None)
+    };
+    let payload = payload.as_ref();
+    
+    let mut header = ContainerHeader {
+        metadata: VideoMetadata::for_len(payload.len() as u64, mode, settings),
+        filename,
+        checksum: crc32fast::hash(payload),
+        sha256,
+        compression: codec,
+        original_size: (codec != Codec::None).then_some(original_size),
+        encryption,
+        signature: None,
+    };
+    
+    // The frame count covers the header and the FEC check bytes too, and
+    // writing it can change the header length, so repeat until it settles
+    // (at most a couple of passes)
+    loop {
+        // The signature covers the frame count, so it is redone on every pass
+        if let Some(key) = &protection.signing_key {
+            signing::sign(&mut header, &sealed, key)?;
+        }
+        
+        let bytes = serialize(&header, payload)?;
+        let mut frames = if settings.fountain.is_some() {
+            fountain::frame_count(bytes.len() as u64, mode, settings)?
+        } else {
+            let rendered = fec::encoded_len(bytes.len() as u64, settings.fec_parity);
+            etcher::frame_count_for(rendered, mode, settings)
+        };
+        frames = frames.max(header.metadata.frames);
+        frame_footer::check_frames(frames)?;
+        if frames == header.metadata.frames {
+            return Ok(bytes);
+        }
+        header.metadata.frames = frames;
+    }
+}
+
+// Whether `bytes` start with a v2 container
+pub fn is_container(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// Split a v2 container into header and payload. Trailing bytes (e.g. padding
+// from the last frame) are dropped; the payload is checked by `Container::verify`.
+pub fn unpack(mut bytes: Vec<u8>) -> Result<Container> {
+    if !is_container(&bytes) || bytes.len() < PREAMBLE_LEN {
+        return Err(anyhow!("Not a binvid v2 container"));
+    }
+    
+    let version = u16::from_le_bytes([bytes[6], bytes[7]]);
+    if version != FORMAT_VERSION {
+        return Err(anyhow!("Unsupported binvid format version {}", version));
+    }
+    
+    let header_len = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize;
+    let header_end = PREAMBLE_LEN + header_len;
+    if bytes.len() < header_end {
+        return Err(anyhow!("Container header is truncated"));
+    }
+    
+    let header: ContainerHeader = serde_json::from_slice(&bytes[PREAMBLE_LEN..header_end])
+        .map_err(|e| anyhow!("Invalid container header: {}", e))?;
+    if header.sha256.is_none() && header.encryption.is_none() {
+        return Err(anyhow!("Container header has no SHA-256 of the embedded file"));
+    }
+    
+    let payload_end = header_end as u64 + header.metadata.data_size;
+    if (bytes.len() as u64) < payload_end {
+        return Err(anyhow!(
+            "Payload is truncated: {} of {} bytes present",
+            bytes.len() - header_end, header.metadata.data_size
+        ));
+    }
+    
+    bytes.truncate(payload_end as usize);
+    let payload = bytes.split_off(header_end);
+    
+    Ok(Container {
+        header: Some(header),
+        payload,
+    })
+}
+
+// Interpret decoded frame data: a v2 container when it has the magic,
+// otherwise raw payload from an older encoder
+pub fn from_frames(bytes: Vec<u8>) -> Result<Container> {
+    if is_container(&bytes) {
+        unpack(bytes)
+    } else {
+        Ok(Container::raw(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn settings() -> Settings {
+        let mut settings = Settings::new(2, 1, 10, 256, 144);
+        settings.fec_parity = fec::DEFAULT_PARITY;
+        settings
+    }
+    
+    fn pack_plain(payload: &[u8], settings: &Settings) -> Vec<u8> {
+        pack(payload, "some/dir/report.txt", OutputMode::Binary, settings, &Default::default(), &Default::default()).unwrap()
+    }
+    
+    #[test]
+    fn pack_unpack_round_trip() {
+        let payload: Vec<u8> = (0..5000u32).map(|i| (i * 7) as u8).collect();
+        let mut bytes = pack_plain(&payload, &settings());
+        // Decoded frames end in padding
+        bytes.extend_from_slice(&[0; 100]);
+        
+        let container = from_frames(bytes).unwrap();
+        let header = container.header.as_ref().unwrap();
+        assert_eq!(header.filename.as_deref(), Some("report.txt"));
+        assert_eq!(header.compression, Codec::None);
+        assert_eq!(container.payload, payload);
+        assert_eq!(container.verify(), Integrity::Verified);
+    }
+    
+    #[test]
+    fn frame_count_settles() {
+        let settings = settings();
+        let frame_bytes = (etcher::frame_bits(&settings, OutputMode::Binary) / 8) as usize;
+        
+        // Lengths either side of the point where the container needs a second frame
+        for len in (frame_bytes / 2..frame_bytes + 200).step_by(13) {
+            let bytes = pack_plain(&vec![0xa5; len], &settings);
+            let header = unpack(bytes.clone()).unwrap().header.unwrap();
+            let rendered = fec::encoded_len(bytes.len() as u64, settings.fec_parity);
+            assert_eq!(header.metadata.frames, etcher::frame_count_for(rendered, OutputMode::Binary, &settings), "payload of {} bytes", len);
+            assert_eq!(header.metadata.data_size, len as u64);
+        }
+    }
+    
+    #[test]
+    fn detects_damage() {
+        let mut bytes = pack_plain(b"hello, world", &settings());
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(matches!(unpack(bytes.clone()).unwrap().verify(), Integrity::Mismatch { .. }));
+        
+        bytes.truncate(last);
+        assert!(unpack(bytes.clone()).is_err());
+        
+        bytes[6] = 9;
+        assert!(unpack(bytes).is_err());
+        
+        // Every container has to record the file's SHA-256
+        let container = unpack(pack_plain(b"hello, world", &settings())).unwrap();
+        let mut header = container.header.unwrap();
+        header.sha256 = None;
+        assert!(unpack(serialize(&header, &container.payload).unwrap()).is_err());
+        assert!(matches!(from_frames(b"raw data".to_vec()).unwrap().verify(), Integrity::Unchecked));
+    }
+}
\ No newline at end of file
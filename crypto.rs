@@ -0,0 +1,491 @@
+// Authenticated encryption of the embedded file.
+//
+// The file is sealed with ChaCha20-Poly1305 under either a key derived from a
+// password with Argon2id, or a random file key wrapped for every X25519
+// recipient (as in age: an ephemeral key per recipient, HKDF-SHA256 over the
+// shared secret, and the file key sealed under the result). The salt, Argon2
+// parameters, wrapped keys and nonce go into the container header; the file
+// name and SHA-256 move inside the ciphertext so the clear header says nothing
+// about the contents:
+//
+//   plaintext: sealed header length (u32 LE) | sealed header (JSON) | file
+//
+// The container CRC32 covers the ciphertext, which lets the decoder tell a
+// wrong password (intact ciphertext that does not authenticate) from damage.
+
+use std::fs;
+use anyhow::{Result, anyhow};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{AeadCore, ChaCha20Poly1305, KeyInit, Nonce};
+use chacha20poly1305::aead::{Aead, OsRng, Payload};
+use chacha20poly1305::aead::rand_core::RngCore;
+use hkdf::Hkdf;
+use serde::{Serialize, Deserialize};
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use ed25519_dalek::SigningKey;
+use crate::container::Container;
+
+pub const CIPHER: &str = "chacha20poly1305";
+pub const KDF: &str = "argon2id";
+
+// Argon2id cost, RFC 9106's second recommended option
+const MEMORY_KIB: u32 = 64 * 1024;
+const ITERATIONS: u32 = 3;
+const PARALLELISM: u32 = 4;
+
+// Refuse headers asking for more than this, so a crafted file cannot exhaust
+// memory or keep the decoder busy for hours
+const MAX_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+const MAX_ITERATIONS: u32 = 32;
+const MAX_PARALLELISM: u32 = 16;
+
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+// Text forms of X25519 keys: the prefix followed by the key in hex
+pub const PUBLIC_KEY_PREFIX: &str = "sdh-pub-";
+pub const SECRET_KEY_PREFIX: &str = "SDH-SECRET-KEY-";
+
+// Domain separation for wrapping file keys
+const WRAP_INFO: &[u8] = b"steganographic_data_handling x25519 file key";
+
+// How the payload should be protected at embed time
+#[derive(Default)]
+pub struct Protection {
+    pub password: Option<String>,
+    pub recipients: Vec<PublicKey>,
+    // Sign the container, whether or not it is encrypted
+    pub signing_key: Option<SigningKey>,
+}
+
+impl Protection {
+    pub fn is_encrypted(&self) -> bool {
+        self.password.is_some() || !self.recipients.is_empty()
+    }
+}
+
+// What the user offered to decrypt with
+#[derive(Default)]
+pub struct Keys {
+    pub password: Option<String>,
+    pub identity: Option<StaticSecret>,
+}
+
+// Argon2id settings needed to derive the key again
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KdfParams {
+    pub algorithm: String,
+    // Hex encoded
+    pub salt: String,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+// The file key wrapped for one X25519 recipient
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Recipient {
+    // Ephemeral public key, hex encoded
+    pub ephemeral: String,
+    // File key sealed under the shared secret, hex encoded
+    pub wrapped_key: String,
+}
+
+// Encryption details stored in the container header
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Encryption {
+    pub cipher: String,
+    // Hex encoded
+    pub nonce: String,
+    // Set for password encryption
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf: Option<KdfParams>,
+    // Set for public-key encryption, one entry per recipient
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<Recipient>,
+}
+
+// Container header fields that are only stored inside the ciphertext
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sealed {
+    pub filename: Option<String>,
+    pub sha256: String,
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(text: &str) -> Result<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return Err(anyhow!("Invalid hex string"));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(|_| anyhow!("Invalid hex string")))
+        .collect()
+}
+
+// The password from --password or --password-file, if either was given
+pub fn read_password(password: Option<String>, password_file: Option<String>) -> Result<Option<String>> {
+    if let Some(path) = password_file {
+        let text = fs::read_to_string(&path).map_err(|e| anyhow!("Cannot read password file {}: {}", path, e))?;
+        // Editors and `echo` leave a trailing newline that is not part of the password
+        let password = text.strip_suffix('\n').unwrap_or(&text);
+        let password = password.strip_suffix('\r').unwrap_or(password);
+        return Ok(Some(password.to_string()));
+    }
+    Ok(password)
+}
+
+pub fn format_public_key(key: &PublicKey) -> String {
+    format!("{}{}", PUBLIC_KEY_PREFIX, to_hex(key.as_bytes()))
+}
+
+pub fn format_secret_key(key: &StaticSecret) -> String {
+    format!("{}{}", SECRET_KEY_PREFIX, to_hex(key.as_bytes()).to_uppercase())
+}
+
+// Parse `prefix` followed by a 32 byte key in hex
+pub fn key_bytes(text: &str, prefix: &str, kind: &str) -> Result<[u8; KEY_LEN]> {
+    let hex = text.trim()
+        .strip_prefix(prefix)
+        .ok_or_else(|| anyhow!("{} must start with {}", kind, prefix))?;
+    from_hex(hex)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow!("{} must be {} followed by {} hex digits", kind, prefix, KEY_LEN * 2))
+}
+
+// Parse a --recipient value
+pub fn parse_public_key(text: &str) -> Result<PublicKey> {
+    Ok(PublicKey::from(key_bytes(text, PUBLIC_KEY_PREFIX, "Recipient")?))
+}
+
+// The key in a file written by `keygen`: its first line that is not a comment
+pub fn read_key_file(path: &str, kind: &str) -> Result<String> {
+    let text = fs::read_to_string(path).map_err(|e| anyhow!("Cannot read {} file {}: {}", kind, path, e))?;
+    text.lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("No key found in {} file {}", kind, path))
+}
+
+// Read the secret key from an identity file written by `keygen`
+pub fn read_identity(path: &str) -> Result<StaticSecret> {
+    let line = read_key_file(path, "identity")?;
+    Ok(StaticSecret::from(key_bytes(&line, SECRET_KEY_PREFIX, "Identity")?))
+}
+
+// Key that seals the file key for one recipient
+fn wrap_key(shared: &[u8; 32], ephemeral: &PublicKey, recipient: &PublicKey) -> [u8; KEY_LEN] {
+    let mut salt = Vec::with_capacity(64);
+    salt.extend_from_slice(ephemeral.as_bytes());
+    salt.extend_from_slice(recipient.as_bytes());
+    
+    let mut key = [0u8; KEY_LEN];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(WRAP_INFO, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+// Every wrap key is used once, so a fixed nonce is safe
+fn wrap_cipher(key: [u8; KEY_LEN]) -> (ChaCha20Poly1305, Nonce) {
+    (ChaCha20Poly1305::new(&key.into()), Nonce::default())
+}
+
+fn wrap_for(file_key: &[u8; KEY_LEN], recipient: &PublicKey) -> Result<Recipient> {
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral = PublicKey::from(&secret);
+    let shared = secret.diffie_hellman(recipient);
+    if !shared.was_contributory() {
+        return Err(anyhow!("Recipient {} is not a usable public key", format_public_key(recipient)));
+    }
+    
+    let (cipher, nonce) = wrap_cipher(wrap_key(shared.as_bytes(), &ephemeral, recipient));
+    let wrapped = cipher.encrypt(&nonce, file_key.as_slice())
+        .map_err(|_| anyhow!("Wrapping the file key failed"))?;
+    
+    Ok(Recipient {
+        ephemeral: to_hex(ephemeral.as_bytes()),
+        wrapped_key: to_hex(&wrapped),
+    })
+}
+
+// The file key from whichever recipient entry `identity` can open
+fn unwrap_for(recipients: &[Recipient], identity: &StaticSecret) -> Option<[u8; KEY_LEN]> {
+    let public = PublicKey::from(identity);
+    recipients.iter().find_map(|recipient| {
+        let ephemeral: [u8; 32] = from_hex(&recipient.ephemeral).ok()?.try_into().ok()?;
+        let ephemeral = PublicKey::from(ephemeral);
+        let shared = identity.diffie_hellman(&ephemeral);
+        
+        let (cipher, nonce) = wrap_cipher(wrap_key(shared.as_bytes(), &ephemeral, &public));
+        let wrapped = from_hex(&recipient.wrapped_key).ok()?;
+        cipher.decrypt(&nonce, wrapped.as_slice()).ok()?.try_into().ok()
+    })
+}
+
+fn derive_key(password: &str, kdf: &KdfParams) -> Result<[u8; KEY_LEN]> {
+    if kdf.algorithm != KDF {
+        return Err(anyhow!("Unsupported key derivation function {}", kdf.algorithm));
+    }
+    if kdf.memory_kib > MAX_MEMORY_KIB {
+        return Err(anyhow!("Key derivation asks for {} KiB of memory, more than the {} KiB allowed", kdf.memory_kib, MAX_MEMORY_KIB));
+    }
+    if kdf.iterations > MAX_ITERATIONS {
+        return Err(anyhow!("Key derivation asks for {} iterations, more than the {} allowed", kdf.iterations, MAX_ITERATIONS));
+    }
+    if kdf.parallelism > MAX_PARALLELISM {
+        return Err(anyhow!("Key derivation asks for {} lanes, more than the {} allowed", kdf.parallelism, MAX_PARALLELISM));
+    }
+    
+    let params = Params::new(kdf.memory_kib, kdf.iterations, kdf.parallelism, Some(KEY_LEN))
+        .map_err(|e| anyhow!("Invalid Argon2 parameters: {}", e))?;
+    let salt = from_hex(&kdf.salt)?;
+    
+    let mut key = [0u8; KEY_LEN];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(password.as_bytes(), &salt, &mut key)
+        .map_err(|e| anyhow!("Key derivation failed: {}", e))?;
+    Ok(key)
+}
+
+// The encryption parameters are authenticated along with the data
+fn associated_data(encryption: &Encryption) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(encryption)?)
+}
+
+// Encrypt `payload` together with the header fields in `sealed`
+pub fn seal(payload: &[u8], sealed: &Sealed, protection: &Protection) -> Result<(Vec<u8>, Encryption)> {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut encryption = Encryption {
+        cipher: CIPHER.to_string(),
+        nonce: to_hex(&nonce),
+        kdf: None,
+        recipients: Vec::new(),
+    };
+    
+    let key = match &protection.password {
+        Some(password) => {
+            let mut salt = [0u8; SALT_LEN];
+            OsRng.fill_bytes(&mut salt);
+            let kdf = KdfParams {
+                algorithm: KDF.to_string(),
+                salt: to_hex(&salt),
+                memory_kib: MEMORY_KIB,
+                iterations: ITERATIONS,
+                parallelism: PARALLELISM,
+            };
+            
+            println!("Deriving the encryption key (Argon2id, {} MiB)...", MEMORY_KIB / 1024);
+            let key = derive_key(password, &kdf)?;
+            encryption.kdf = Some(kdf);
+            key
+        }
+        None => {
+            let mut file_key = [0u8; KEY_LEN];
+            OsRng.fill_bytes(&mut file_key);
+            for recipient in &protection.recipients {
+                encryption.recipients.push(wrap_for(&file_key, recipient)?);
+            }
+            println!("Encrypting to {} recipient(s)", encryption.recipients.len());
+            file_key
+        }
+    };
+    
+    let inner = serde_json::to_vec(sealed)?;
+    let mut plaintext = Vec::with_capacity(4 + inner.len() + payload.len());
+    plaintext.extend_from_slice(&(inner.len() as u32).to_le_bytes());
+    plaintext.extend_from_slice(&inner);
+    plaintext.extend_from_slice(payload);
+    
+    let aad = associated_data(&encryption)?;
+    let ciphertext = ChaCha20Poly1305::new(&key.into())
+        .encrypt(&nonce, Payload { msg: &plaintext, aad: &aad })
+        .map_err(|_| anyhow!("Encryption failed"))?;
+    
+    Ok((ciphertext, encryption))
+}
+
+// Decrypt an encrypted container in place, restoring its file name and SHA-256
+pub fn open(container: &mut Container, keys: &Keys) -> Result<()> {
+    let Some(header) = container.header.as_mut() else {
+        return Ok(());
+    };
+    let Some(encryption) = header.encryption.clone() else {
+        return Ok(());
+    };
+    
+    if encryption.cipher != CIPHER {
+        return Err(anyhow!("Unsupported cipher {}", encryption.cipher));
+    }
+    
+    // Without an intact ciphertext every password would fail, so say so up front
+    if crc32fast::hash(&container.payload) != header.checksum {
+        return Err(anyhow!("The encrypted data is corrupt and cannot be decrypted"));
+    }
+    
+    let (key, failure) = match &encryption.kdf {
+        Some(kdf) => {
+            let Some(password) = &keys.password else {
+                return Err(anyhow!("The data is encrypted with a password; pass --password or --password-file"));
+            };
+            println!("Deriving the decryption key (Argon2id, {} MiB)...", kdf.memory_kib / 1024);
+            (derive_key(password, kdf)?, "Wrong password: the data could not be decrypted")
+        }
+        None => {
+            let Some(identity) = &keys.identity else {
+                return Err(anyhow!(
+                    "The data is encrypted to {} recipient(s); pass --identity with one of their key files",
+                    encryption.recipients.len()
+                ));
+            };
+            let key = unwrap_for(&encryption.recipients, identity).ok_or_else(|| anyhow!(
+                "Wrong identity: {} is not one of the {} recipient(s)",
+                format_public_key(&PublicKey::from(identity)), encryption.recipients.len()
+            ))?;
+            (key, "The data could not be decrypted with the recipient's file key")
+        }
+    };
+    
+    let nonce = from_hex(&encryption.nonce)?;
+    if nonce.len() != NONCE_LEN {
+        return Err(anyhow!("Invalid nonce length {}", nonce.len()));
+    }
+    
+    let aad = associated_data(&encryption)?;
+    let plaintext = ChaCha20Poly1305::new(&key.into())
+        .decrypt(Nonce::from_slice(&nonce), Payload { msg: &container.payload, aad: &aad })
+        .map_err(|_| anyhow!(failure))?;
+    
+    if plaintext.len() < 4 {
+        return Err(anyhow!("Decrypted data is too short"));
+    }
+    let inner_len = u32::from_le_bytes(plaintext[0..4].try_into().unwrap()) as usize;
+    let inner_end = 4 + inner_len;
+    if plaintext.len() < inner_end {
+        return Err(anyhow!("Decrypted header is truncated"));
+    }
+    let sealed: Sealed = serde_json::from_slice(&plaintext[4..inner_end])
+        .map_err(|e| anyhow!("Invalid decrypted header: {}", e))?;
+    
+    header.filename = sealed.filename;
+    header.sha256 = Some(sealed.sha256);
+    container.payload = plaintext[inner_end..].to_vec();
+    println!("Decrypted {} bytes", container.payload.len());
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container;
+    use crate::settings::{OutputMode, Settings};
+    
+    // Pack `payload` into a container with `protection` and read it back
+    fn pack(payload: &[u8], protection: &Protection) -> Container {
+        let settings = Settings::new(2, 1, 10, 256, 144);
+        let bytes = container::pack(payload, "secret.txt", OutputMode::Binary, &settings, &Default::default(), protection).unwrap();
+        container::unpack(bytes).unwrap()
+    }
+    
+    #[test]
+    fn password_round_trip() {
+        let payload = b"the launch codes".to_vec();
+        let protection = Protection {
+            password: Some("correct horse".to_string()),
+            ..Default::default()
+        };
+        let sealed = pack(&payload, &protection);
+        
+        // Nothing about the file is left in the clear
+        let header = sealed.header.as_ref().unwrap();
+        assert_eq!((header.filename.as_ref(), header.sha256.as_ref()), (None, None));
+        assert!(header.encryption.as_ref().unwrap().kdf.is_some());
+        assert_ne!(sealed.payload, payload);
+        
+        let mut opened = Container { header: sealed.header.clone(), payload: sealed.payload.clone() };
+        open(&mut opened, &Keys { password: Some("correct horse".to_string()), identity: None }).unwrap();
+        assert_eq!(opened.payload, payload);
+        assert_eq!(opened.header.as_ref().unwrap().filename.as_deref(), Some("secret.txt"));
+        assert_eq!(opened.verify(), container::Integrity::Verified);
+        
+        let mut wrong = Container { header: sealed.header.clone(), payload: sealed.payload.clone() };
+        let error = open(&mut wrong, &Keys { password: Some("battery staple".to_string()), identity: None }).unwrap_err();
+        assert!(error.to_string().contains("Wrong password"));
+        
+        let mut missing = sealed;
+        assert!(open(&mut missing, &Keys::default()).is_err());
+    }
+    
+    #[test]
+    fn recipients_round_trip() {
+        let payload = b"for your eyes only".to_vec();
+        let alice = StaticSecret::random_from_rng(OsRng);
+        let bob = StaticSecret::random_from_rng(OsRng);
+        let mallory = StaticSecret::random_from_rng(OsRng);
+        let protection = Protection {
+            recipients: vec![PublicKey::from(&alice), PublicKey::from(&bob)],
+            ..Default::default()
+        };
+        let sealed = pack(&payload, &protection);
+        assert_eq!(sealed.header.as_ref().unwrap().encryption.as_ref().unwrap().recipients.len(), 2);
+        
+        for identity in [alice.clone(), bob] {
+            let mut opened = Container { header: sealed.header.clone(), payload: sealed.payload.clone() };
+            open(&mut opened, &Keys { password: None, identity: Some(identity) }).unwrap();
+            assert_eq!(opened.payload, payload);
+            assert_eq!(opened.verify(), container::Integrity::Verified);
+        }
+        
+        let mut wrong = Container { header: sealed.header.clone(), payload: sealed.payload.clone() };
+        let error = open(&mut wrong, &Keys { password: None, identity: Some(mallory) }).unwrap_err();
+        assert!(error.to_string().contains("Wrong identity"));
+        
+        // The wrapped keys are authenticated along with the data
+        let mut tampered = sealed;
+        let encryption = tampered.header.as_mut().unwrap().encryption.as_mut().unwrap();
+        encryption.recipients.swap(0, 1);
+        let error = open(&mut tampered, &Keys { password: None, identity: Some(alice) }).unwrap_err();
+        assert!(error.to_string().contains("could not be decrypted"));
+    }
+    
+    #[test]
+    fn key_text_round_trip() {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        assert_eq!(parse_public_key(&format_public_key(&public)).unwrap(), public);
+        assert_eq!(key_bytes(&format_secret_key(&secret), SECRET_KEY_PREFIX, "Identity").unwrap(), secret.to_bytes());
+        assert!(parse_public_key("sdh-pub-1234").is_err());
+    }
+    
+    #[test]
+    fn derived_keys_depend_on_password_and_salt() {
+        let kdf = KdfParams {
+            algorithm: KDF.to_string(),
+            salt: to_hex(&[1; SALT_LEN]),
+            memory_kib: 64,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let key = derive_key("password", &kdf).unwrap();
+        assert_eq!(derive_key("password", &kdf).unwrap(), key);
+        assert_ne!(derive_key("Password", &kdf).unwrap(), key);
+        
+        let salted = KdfParams { salt: to_hex(&[2; SALT_LEN]), ..kdf.clone() };
+        assert_ne!(derive_key("password", &salted).unwrap(), key);
+        
+        let greedy = KdfParams { memory_kib: MAX_MEMORY_KIB + 1, ..kdf.clone() };
+        assert!(derive_key("password", &greedy).is_err());
+        let slow = KdfParams { iterations: MAX_ITERATIONS + 1, ..kdf.clone() };
+        assert!(derive_key("password", &slow).is_err());
+        let wide = KdfParams { parallelism: MAX_PARALLELISM + 1, memory_kib: 8 * (MAX_PARALLELISM + 1), ..kdf };
+        assert!(derive_key("password", &wide).is_err());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,839 @@
+// Frame-based decoding: pulls frames out of any supported video and turns
+// the blocks back into the payload.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use anyhow::{Result, anyhow};
+use image::{RgbImage, Rgb};
+use crate::avi::{self, AviReader};
+use crate::calibration::Calibrated;
+use crate::etcher::{self, BlockGrid, VideoMetadata};
+use crate::fec;
+use crate::fiducial::{self, Rectified};
+use crate::interleave;
+use crate::ffmpeg::FfmpegReader;
+use crate::fountain;
+use crate::frame_footer::{self, FrameFooter};
+use crate::frame_header::{self, FrameHeader};
+use crate::gray::Calibration;
+use crate::palette::{self, Palette};
+use crate::png_sequence::PngSequenceReader;
+use crate::settings::{BitWriter, OutputMode};
+use crate::sampling::{self, Sampler, Sampling};
+use crate::threshold::{self, Strategy};
+use crate::y4m::{self, Y4mReader};
+
+// Layout details given on the command line, for videos that lost theirs
+// (e.g. after a re-upload strips the container metadata), and how to read
+// the blocks
+#[derive(Debug, Default, Clone)]
+pub struct LayoutHint {
+    pub mode: Option<OutputMode>,
+    pub block_size: Option<u32>,
+    pub data_size: Option<u64>,
+    pub fec_parity: Option<u8>,
+    pub threshold: Strategy,
+    pub sampling: Sampling,
+    // Decode with every sampling strategy and keep the one with the fewest errors
+    pub compare_sampling: bool,
+}
+
+// Everything needed to turn frames back into bytes
+#[derive(Debug, Clone)]
+pub struct FrameLayout {
+    pub mode: OutputMode,
+    pub width: u32,
+    pub height: u32,
+    pub block_size: u32,
+    // First pixel row of the data blocks, below the header strip
+    pub top: u32,
+    // Pixel row the data blocks end at, above the footer strip
+    pub bottom: u32,
+    // Payload length in bytes; None decodes every block of every frame
+    pub data_size: Option<u64>,
+    // Reed–Solomon check bytes per codeword, 0 when there are none
+    pub fec_parity: u8,
+    // Whether the codewords are interleaved across frames
+    pub interleaved: bool,
+    // Whether every frame carries its own fountain-coded packet
+    pub fountain: bool,
+    // Whether the data area carries the corner markers from fiducial.rs
+    pub markers: bool,
+    // How binary blocks are told apart
+    pub threshold: Strategy,
+    // How the colour of a block is read from its pixels
+    pub sampling: Sampling,
+}
+
+impl FrameLayout {
+    // Merge the in-frame header, the container metadata and the hint.
+    // The hint wins, then the in-frame header, then the container.
+    pub fn resolve(header: Option<&FrameHeader>, embedded: Option<&VideoMetadata>, hint: &LayoutHint, width: u32, height: u32) -> Result<FrameLayout> {
+        let mode = hint.mode
+            .or(header.map(|h| h.mode))
+            .or(embedded.map(|m| m.mode))
+            .ok_or_else(|| anyhow!("The video does not say which mode it uses; pass --mode"))?;
+        let block_size = hint.block_size
+            .or(header.map(|h| h.block_size))
+            .or(embedded.map(|m| m.block_size))
+            .ok_or_else(|| anyhow!("The video does not say which block size it uses; pass --block-size"))?;
+        
+        let encoded = header.map(|h| (h.width, h.height)).or(embedded.map(|m| (m.width, m.height)));
+        if let Some((encoded_width, encoded_height)) = encoded {
+            if encoded_width != width || encoded_height != height {
+                return Err(anyhow!("Video was encoded at {}x{} but its frames are {}x{}", encoded_width, encoded_height, width, height));
+            }
+        }
+        
+        // Every frame has the header and footer strips, whether or not they
+        // could be read from this one
+        let top = frame_header::height(width);
+        let bottom = height.saturating_sub(frame_footer::height(width));
+        
+        if block_size == 0 || block_size > width || top + block_size > bottom {
+            return Err(anyhow!("Block size {} does not fit in a {}x{} frame", block_size, width, height));
+        }
+        
+        Ok(FrameLayout {
+            mode,
+            width,
+            height,
+            block_size,
+            top,
+            bottom,
+            data_size: hint.data_size
+                .or(header.map(|h| h.data_size))
+                .or(embedded.map(|m| m.data_size)),
+            fec_parity: hint.fec_parity
+                .or(header.map(|h| h.fec_parity))
+                .or(embedded.map(|m| m.fec_parity))
+                .unwrap_or(0),
+            // Without any record, assume the current encoder: interleaved whenever there is FEC
+            interleaved: header.map(|h| h.interleaved)
+                .or(embedded.map(|m| m.interleaved))
+                .unwrap_or(hint.fec_parity.is_some_and(|parity| parity > 0)),
+            fountain: header.map(|h| h.fountain)
+                .or(embedded.map(|m| m.fountain))
+                .unwrap_or(false),
+            markers: header.map(|h| h.markers)
+                .or(embedded.map(|m| m.markers))
+                .unwrap_or(true),
+            threshold: hint.threshold,
+            sampling: hint.sampling,
+        })
+    }
+    
+    // Where the blocks are, as the encoder laid them out
+    pub fn grid(&self) -> BlockGrid {
+        BlockGrid::new(self.width, self.top, self.bottom, self.block_size, self.mode, self.markers)
+    }
+    
+    // Bits of the rendered stream carried by one frame
+    pub fn frame_bits(&self) -> u64 {
+        self.grid().group_count() * self.mode.bits_per_group() as u64
+    }
+}
+
+// A stream of decoded frames plus whatever layout the container recorded
+pub struct FrameSource {
+    pub width: u32,
+    pub height: u32,
+    pub metadata: Option<VideoMetadata>,
+    frames: Box<dyn Iterator<Item = Result<RgbImage>>>,
+}
+
+impl Iterator for FrameSource {
+    type Item = Result<RgbImage>;
+    
+    fn next(&mut self) -> Option<Result<RgbImage>> {
+        self.frames.next()
+    }
+}
+
+const PNG_MAGIC: &[u8] = b"\x89PNG";
+const JPEG_MAGIC: &[u8] = &[0xFF, 0xD8, 0xFF];
+
+// Open a video (or PNG frame directory, or a single picture of a frame) with
+// the best available reader. Y4M, AVI, PNG sequences and stills are read
+// natively, everything else via ffmpeg.
+pub fn open_frames<P: AsRef<Path>>(path: P) -> Result<FrameSource> {
+    let path = path.as_ref();
+    
+    if path.is_dir() {
+        let reader = PngSequenceReader::open(path)?;
+        return Ok(FrameSource {
+            width: reader.width,
+            height: reader.height,
+            metadata: reader.metadata(),
+            frames: Box::new(reader),
+        });
+    }
+    
+    let mut magic = [0u8; 9];
+    let read = File::open(path)?.read(&mut magic)?;
+    let magic = &magic[..read];
+    
+    if magic.starts_with(y4m::MAGIC) {
+        let reader = Y4mReader::open(path)?;
+        return Ok(FrameSource {
+            width: reader.width,
+            height: reader.height,
+            metadata: reader.metadata(),
+            frames: Box::new(reader),
+        });
+    }
+    
+    if magic.starts_with(PNG_MAGIC) || magic.starts_with(JPEG_MAGIC) {
+        let frame = image::open(path)
+            .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?
+            .to_rgb8();
+        return Ok(FrameSource {
+            width: frame.width(),
+            height: frame.height(),
+            metadata: None,
+            frames: Box::new(std::iter::once(Ok(frame))),
+        });
+    }
+    
+    if magic.starts_with(avi::MAGIC) {
+        let reader = AviReader::open(path)?;
+        return Ok(FrameSource {
+            width: reader.width,
+            height: reader.height,
+            metadata: reader.metadata(),
+            frames: Box::new(reader),
+        });
+    }
+    
+    let reader = FfmpegReader::open(path)?;
+    Ok(FrameSource {
+        width: reader.width,
+        height: reader.height,
+        metadata: reader.metadata(),
+        frames: Box::new(reader),
+    })
+}
+
+// How many frames to look through for a readable header strip
+const HEADER_SCAN_FRAMES: usize = 10;
+
+// Decode the payload carried by the frames of a video
+pub fn read_video<P: AsRef<Path>>(path: P, hint: &LayoutHint) -> Result<Vec<u8>> {
+    let (layout, frames) = open_video(&path, hint)?;
+    
+    // Fountain frames are independent, so they are decoded one at a time
+    if layout.fountain {
+        if hint.compare_sampling {
+            println!("Sampling strategies can only be compared on videos without fountain coding, using {:?}", layout.sampling);
+        }
+        return fountain::decode(frames, &layout);
+    }
+    
+    match layout.data_size {
+        Some(size) => println!("  Expecting {} bytes", size),
+        None => println!("  Payload size unknown, decoding every block"),
+    }
+    
+    if !hint.compare_sampling {
+        return decode_stream(frames, &layout)?.finish(&layout);
+    }
+    if layout.block_size < sampling::MIN_SAMPLED_BLOCK_SIZE {
+        println!("Blocks of {0}x{0} pixels are read from their centre by every sampling strategy, nothing to compare", layout.block_size);
+        return decode_stream(frames, &layout)?.finish(&layout);
+    }
+    
+    // Every strategy gets a pass over the whole video, and the one that
+    // needed the fewest corrections is kept
+    let mut frames = Some(frames);
+    let mut best: Option<(FrameLayout, Decoded)> = None;
+    for sampling in Sampling::ALL {
+        let layout = FrameLayout { sampling, ..layout.clone() };
+        let decoded = match frames.take() {
+            Some(frames) => decode_stream(frames, &layout)?,
+            None => decode_stream(reopen_video(&path, &layout)?, &layout)?,
+        };
+        println!("Sampling {:?}: {}", sampling, decoded.errors());
+        if best.as_ref().is_none_or(|(_, best)| decoded.error_rank() < best.error_rank()) {
+            best = Some((layout, decoded));
+        }
+    }
+    
+    let (layout, decoded) = best.expect("at least one sampling strategy");
+    println!("Lowest error rate with {:?} sampling", layout.sampling);
+    decoded.finish(&layout)
+}
+
+// Open a video and work out its layout from the first readable header. The
+// frames come back rectified and calibrated, ready for read_frame.
+fn open_video<P: AsRef<Path>>(path: P, hint: &LayoutHint) -> Result<(FrameLayout, Frames)> {
+    let mut source = open_frames(&path)?;
+    
+    // Every frame repeats the header, so skip past frames where it is damaged.
+    // The frames looked at are kept and decoded along with the rest.
+    let mut scanned = Vec::new();
+    let mut header = None;
+    while header.is_none() && scanned.len() < HEADER_SCAN_FRAMES {
+        let Some(frame) = source.next().transpose()? else {
+            break;
+        };
+        header = frame_header::read(&frame);
+        
+        // The frame may have been rescaled, or re-captured with its corner
+        // markers still showing
+        let sizes: Vec<(u32, u32)> = source.metadata.iter()
+            .map(|m| (m.width, m.height))
+            .chain([(source.width, source.height)])
+            .collect();
+        if header.is_none() {
+            header = fiducial::find_scaled_header(&frame, &sizes);
+            if let Some(h) = &header {
+                println!("Found the frame header by scaling the {}x{} frame back to {}x{}", frame.width(), frame.height(), h.width, h.height);
+            }
+        }
+        if header.is_none() {
+            header = fiducial::find_header(&frame, &sizes);
+            if header.is_some() {
+                println!("Found the frame header through the corner markers");
+            }
+        }
+        scanned.push(frame);
+    }
+    
+    match &header {
+        Some(h) => println!(
+            "Found frame header: {:?}, block size {}, {}x{}, {} frames, {} bytes",
+            h.mode, h.block_size, h.width, h.height, h.frames, h.data_size
+        ),
+        None => println!("No frame header found, using the container metadata"),
+    }
+    
+    // Frames are put back into the size they were rendered at, which may not
+    // be the size they come in at
+    let (width, height) = header.as_ref().map(|h| (h.width, h.height))
+        .or(source.metadata.as_ref().map(|m| (m.width, m.height)))
+        .unwrap_or((source.width, source.height));
+    let layout = FrameLayout::resolve(header.as_ref(), source.metadata.as_ref(), hint, width, height)?;
+    
+    println!("Decoding {:?} frames: {}x{}, block size {}", layout.mode, layout.width, layout.height, layout.block_size);
+    let resized = (width, height) != (source.width, source.height);
+    let frames = prepare_frames(Box::new(scanned.into_iter().map(Ok).chain(source)), &layout, resized);
+    Ok((layout, frames))
+}
+
+// Open a video again from its first frame, for a layout already worked out
+fn reopen_video<P: AsRef<Path>>(path: P, layout: &FrameLayout) -> Result<Frames> {
+    let source = open_frames(&path)?;
+    let resized = (layout.width, layout.height) != (source.width, source.height);
+    Ok(prepare_frames(Box::new(source), layout, resized))
+}
+
+type Frames = Calibrated<Box<dyn Iterator<Item = Result<RgbImage>>>>;
+
+fn prepare_frames(mut frames: Box<dyn Iterator<Item = Result<RgbImage>>>, layout: &FrameLayout, resized: bool) -> Frames {
+    if layout.markers || resized {
+        frames = Box::new(Rectified::new(frames, layout.width, layout.height, layout.markers));
+    }
+    // Calibration frames are taken out here and correct the levels of the frames after them
+    Calibrated::new(frames, layout)
+}
+
+// The payload read from a video's frames, before it is checked
+struct Decoded {
+    bytes: Vec<u8>,
+    // Length of the stream the frames gave, check bytes included
+    stream_len: usize,
+    frames: FrameReport,
+    // Default when the video has no error correction
+    fec: fec::FecReport,
+}
+
+impl Decoded {
+    // How badly the frames were read, for comparing sampling strategies
+    fn errors(&self) -> String {
+        if self.fec.codewords == 0 {
+            return format!("{} of {} frames corrupt", self.frames.corrupt, self.frames.received);
+        }
+        let mut errors = format!(
+            "{} of {} bytes corrected ({:.3}%)",
+            self.fec.corrected_symbols, self.stream_len,
+            100.0 * self.fec.corrected_symbols as f64 / self.stream_len.max(1) as f64
+        );
+        if self.fec.failed_codewords > 0 {
+            errors += &format!(", {} codewords beyond repair", self.fec.failed_codewords);
+        }
+        errors
+    }
+    
+    // Lower is better: codewords lost first, then bytes corrected, then frames corrupt
+    fn error_rank(&self) -> (usize, usize, u64) {
+        (self.fec.failed_codewords, self.fec.corrected_symbols, self.frames.corrupt)
+    }
+    
+    // Report on the decoding and hand over the payload, if it survived
+    fn finish(self, layout: &FrameLayout) -> Result<Vec<u8>> {
+        let Decoded { bytes, frames: report, fec: fec_report, .. } = self;
+        report.print();
+        
+        if layout.fec_parity == 0 {
+            if report.damaged() {
+                return Err(anyhow!(
+                    "{} frames are missing or corrupt and the video has no error correction to repair them",
+                    report.missing + report.corrupt
+                ));
+            }
+            return Ok(bytes);
+        }
+        
+        println!(
+            "Reed–Solomon FEC: corrected {} symbols in {} of {} codewords",
+            fec_report.corrected_symbols, fec_report.repaired_codewords, fec_report.codewords
+        );
+        if fec_report.erased_codewords > 0 {
+            println!("{} codewords were only recovered by erasing their least reliable bytes", fec_report.erased_codewords);
+        }
+        if fec_report.failed_codewords > 0 {
+            return Err(anyhow!(
+                "{} of {} codewords had more than {} errors and could not be corrected",
+                fec_report.failed_codewords, fec_report.codewords, layout.fec_parity / 2
+            ));
+        }
+        
+        Ok(bytes)
+    }
+}
+
+// Rebuild the stream from the frames and undo its error correction
+fn decode_stream<I>(frames: I, layout: &FrameLayout) -> Result<Decoded>
+where
+    I: Iterator<Item = Result<RgbImage>>,
+{
+    let (mut bytes, mut reliability, report) = decode_frames(frames, layout)?;
+    let stream_len = bytes.len();
+    if layout.fec_parity == 0 {
+        return Ok(Decoded { bytes, stream_len, frames: report, fec: Default::default() });
+    }
+    
+    if layout.interleaved {
+        bytes = interleave::deinterleave(&bytes, fec::CODEWORD_LEN);
+        reliability = interleave::deinterleave(&reliability, fec::CODEWORD_LEN);
+    }
+    
+    let (bytes, fec) = fec::decode(&bytes, Some(&reliability), layout.fec_parity)?;
+    Ok(Decoded { bytes, stream_len, frames: report, fec })
+}
+
+// How sure the decoder was of the blocks it read, for modes that can tell
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Confidence {
+    pub blocks: u64,
+    pub total: f64,
+    // Blocks below palette::LOW_CONFIDENCE
+    pub doubtful: u64,
+}
+
+impl Confidence {
+    pub fn add(&mut self, confidence: f32) {
+        self.blocks += 1;
+        self.total += confidence as f64;
+        if confidence < palette::LOW_CONFIDENCE {
+            self.doubtful += 1;
+        }
+    }
+    
+    pub fn merge(&mut self, other: &Confidence) {
+        self.blocks += other.blocks;
+        self.total += other.total;
+        self.doubtful += other.doubtful;
+    }
+    
+    pub fn mean(&self) -> f64 {
+        self.total / self.blocks.max(1) as f64
+    }
+}
+
+// The blocks of one frame and its footer
+pub struct FrameData {
+    // The frame's share of the rendered stream, `bits_per_group` bits per
+    // block (or block group), least significant bit first
+    pub bytes: Vec<u8>,
+    // None when the video has no footers or this one is unreadable
+    pub footer: Option<FrameFooter>,
+    // Empty for modes that do not rate their blocks
+    pub confidence: Confidence,
+    // How sure the decoder is of every bit of `bytes`, from 0 for a guess to
+    // 255 for certain; empty for modes that do not rate their blocks
+    pub soft: Vec<u8>,
+}
+
+impl FrameData {
+    // Whether the blocks match the CRC recorded in the footer
+    pub fn intact(&self) -> bool {
+        self.footer.is_some_and(|footer| footer.data_crc == crc32fast::hash(&self.bytes))
+    }
+    
+    // Reliability of every byte the frame's bits fill when they start `shift`
+    // bits into a byte: that of its least sure bit
+    pub fn byte_reliability(&self, shift: u64) -> Vec<u8> {
+        let bits = self.bytes.len() as u64 * 8;
+        let mut reliability = vec![u8::MAX; (shift + bits).div_ceil(8) as usize];
+        for (bit, &soft) in self.soft.iter().enumerate() {
+            let byte = &mut reliability[((shift + bit as u64) / 8) as usize];
+            *byte = (*byte).min(soft);
+        }
+        reliability
+    }
+}
+
+// Confidence from 0 to 1 as a soft bit
+fn soft_bit(confidence: f32) -> u8 {
+    (confidence.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+// Read the blocks of a frame by sampling every block (see sampling.rs).
+// Binary blocks are thresholded on luma (see threshold.rs), gray blocks are
+// matched against the frame's swatches, colour blocks give one byte per
+// channel, and palette groups take the nearest palette colours.
+pub fn read_frame(frame: &RgbImage, layout: &FrameLayout) -> Result<FrameData> {
+    if frame.width() != layout.width || frame.height() != layout.height {
+        return Err(anyhow!(
+            "Frame is {}x{}, expected {}x{}",
+            frame.width(), frame.height(), layout.width, layout.height
+        ));
+    }
+    
+    let block_size = layout.block_size;
+    let side = layout.mode.group_side();
+    
+    let bits = layout.mode.bits_per_group();
+    let mut stream = BitWriter::default();
+    let mut confidence = Confidence::default();
+    
+    // Gray levels and palette colours are told apart using this frame's
+    // swatches, which sit in the header strip
+    let calibration = layout.mode.levels()
+        .filter(|_| layout.mode != OutputMode::Binary)
+        .map(|levels| Calibration::measure(frame, levels));
+    let palette = Palette::measure(frame, layout.mode);
+    
+    // Colour of block `i` (in reading order) of the group at (`left`, `top`)
+    let mut sampler = Sampler::new(layout.sampling, block_size);
+    let mut block = |left: u32, top: u32, i: u32| {
+        sampler.sample(frame, left + (i % side) * block_size, top + (i / side) * block_size)
+    };
+    
+    // Binary blocks are only decided once the whole frame has been seen
+    let mut binary = Vec::new();
+    let mut soft = Vec::new();
+    
+    for (left, top) in layout.grid().groups() {
+        if let Some(palette) = &palette {
+            let pixels = std::array::from_fn(|i| block(left, top, i as u32));
+            let (symbol, confidences) = palette.read_group(pixels);
+            confidences.into_iter().for_each(|c| confidence.add(c));
+            let least = confidences.into_iter().fold(1.0f32, f32::min);
+            soft.extend(std::iter::repeat_n(soft_bit(least), bits as usize));
+            stream.push(symbol, bits);
+            continue;
+        }
+        
+        let Rgb([r, g, b]) = block(left, top, 0);
+        let symbol = match &calibration {
+            Some(calibration) => calibration.symbol(luma(r, g, b) as f64),
+            None if layout.mode == OutputMode::Binary => {
+                binary.push((left, top, luma(r, g, b) as f64));
+                continue;
+            }
+            None => r as u32 | (g as u32) << 8 | (b as u32) << 16,
+        };
+        stream.push(symbol, bits);
+    }
+    
+    for (bit, sureness) in threshold::read_bits(layout.threshold, frame, layout, &binary) {
+        confidence.add(sureness);
+        soft.push(soft_bit(sureness));
+        stream.push(bit as u32, 1);
+    }
+    
+    let footer = frame_footer::read(frame);
+    Ok(FrameData { bytes: stream.bytes, footer, confidence, soft })
+}
+
+// Longest damage report printed before the rest is summarised
+const REPORT_LINES: usize = 20;
+
+// Frames the video may run on past what was received: the footers and the
+// header are only believed this far (see `decode_frames`)
+const MISSING_MARGIN: u64 = 16;
+
+// What happened to the frames of a video on the way back
+#[derive(Debug, Default)]
+pub struct FrameReport {
+    pub expected: u64,
+    pub received: u64,
+    pub missing: u64,
+    pub duplicated: u64,
+    pub corrupt: u64,
+    pub out_of_order: u64,
+    // One line per problem, in the order they were found
+    pub problems: Vec<String>,
+    // How sure the decoder was of the blocks of every frame read
+    pub confidence: Confidence,
+}
+
+impl FrameReport {
+    // Whether any data was lost or damaged (duplicates and reordering are harmless)
+    pub fn damaged(&self) -> bool {
+        self.missing > 0 || self.corrupt > 0
+    }
+    
+    pub fn print(&self) {
+        if self.confidence.blocks > 0 {
+            println!(
+                "Block confidence: mean {:.2}, {} of {} blocks below {}",
+                self.confidence.mean(), self.confidence.doubtful, self.confidence.blocks, palette::LOW_CONFIDENCE
+            );
+        }
+        
+        for line in self.problems.iter().take(REPORT_LINES) {
+            println!("  {}", line);
+        }
+        if self.problems.len() > REPORT_LINES {
+            println!("  ... and {} more", self.problems.len() - REPORT_LINES);
+        }
+        
+        println!(
+            "Frame check: {} expected, {} received, {} missing, {} duplicated, {} corrupt, {} out of order",
+            self.expected, self.received, self.missing, self.duplicated, self.corrupt, self.out_of_order
+        );
+    }
+}
+
+// Rebuild the rendered stream from the frames. Each frame goes where its
+// footer says, so reordered and repeated frames are put right; missing frames
+// are left as zeros for the error correction to deal with. Alongside comes
+// the reliability of every byte of the stream (see `FrameData::soft`), 0 for
+// the bytes of missing frames.
+pub fn decode_frames<I>(frames: I, layout: &FrameLayout) -> Result<(Vec<u8>, Vec<u8>, FrameReport)>
+where
+    I: Iterator<Item = Result<RgbImage>>,
+{
+    let mut report = FrameReport::default();
+    let frame_bits = layout.frame_bits();
+    // Every frame's data with the reliability of the stream bytes it fills
+    let mut slots: BTreeMap<u64, (FrameData, Vec<u8>)> = BTreeMap::new();
+    let mut total = None;
+    let mut next_index = 0;
+    let mut highest = None;
+    
+    for frame in frames {
+        let mut data = read_frame(&frame?, layout)?;
+        report.received += 1;
+        report.confidence.merge(&data.confidence);
+        
+        // Frames without a readable footer are assumed to follow the previous one
+        let index = match data.footer {
+            Some(footer) => {
+                total.get_or_insert(footer.total as u64);
+                footer.index as u64
+            }
+            None => {
+                report.problems.push(format!(
+                    "Frame {}: footer unreadable, assuming it follows the previous frame",
+                    next_index
+                ));
+                next_index
+            }
+        };
+        next_index = index + 1;
+        
+        if highest.is_some_and(|highest| index < highest) {
+            report.out_of_order += 1;
+            report.problems.push(format!("Frame {}: out of order (arrived after frame {})", index, highest.unwrap()));
+        }
+        highest = highest.max(Some(index));
+        
+        // Only the bytes' reliability is kept, not every bit's
+        let reliability = data.byte_reliability(index * frame_bits % 8);
+        data.soft = Vec::new();
+        
+        match slots.get(&index) {
+            Some((existing, _)) => {
+                report.duplicated += 1;
+                if !existing.intact() && data.intact() {
+                    report.problems.push(format!("Frame {}: duplicated, the intact copy replaces a corrupt one", index));
+                    slots.insert(index, (data, reliability));
+                } else {
+                    report.problems.push(format!("Frame {}: duplicated", index));
+                }
+            }
+            None => {
+                slots.insert(index, (data, reliability));
+            }
+        }
+    }
+    
+    // The frame count and the payload length come from the video itself, so
+    // they are held to what was received: no more frames can be missing than
+    // arrived, past the highest index seen, or nothing could repair them
+    let seen = next_index.max(highest.map_or(0, |h| h + 1));
+    if seen > 2 * report.received + MISSING_MARGIN {
+        return Err(anyhow!(
+            "Frame footers number frames up to {} but only {} frames were received; the footers are corrupt",
+            seen, report.received
+        ));
+    }
+    
+    report.expected = total
+        .or(layout.data_size.map(|size| size.saturating_mul(8).div_ceil(frame_bits)))
+        .unwrap_or(seen);
+    if report.expected > seen + report.received + MISSING_MARGIN {
+        return Err(anyhow!(
+            "The video claims {} frames but only {} were received, up to frame {}",
+            report.expected, report.received, seen
+        ));
+    }
+    
+    let capacity = report.expected * frame_bits / 8;
+    let stream_len = layout.data_size.unwrap_or(capacity);
+    if stream_len > capacity {
+        return Err(anyhow!(
+            "The video claims {} bytes of data but its {} frames only hold {}",
+            stream_len, report.expected, capacity
+        ));
+    }
+    let mut stream = vec![0u8; usize::try_from(stream_len)?];
+    let mut stream_reliability = vec![u8::MAX; stream.len()];
+    
+    for index in 0..report.expected {
+        let first_byte = (index * frame_bits / 8) as usize;
+        match slots.get(&index) {
+            Some((data, reliability)) => {
+                if !data.intact() {
+                    report.corrupt += 1;
+                    report.problems.push(format!("Frame {}: corrupt (data does not match its CRC)", index));
+                }
+                etcher::place_bits(&mut stream, index * frame_bits, &data.bytes, frame_bits);
+                for (byte, &value) in stream_reliability.iter_mut().skip(first_byte).zip(reliability) {
+                    *byte = (*byte).min(value);
+                }
+            }
+            None => {
+                report.missing += 1;
+                report.problems.push(format!("Frame {}: missing", index));
+                let last_byte = ((index + 1) * frame_bits).div_ceil(8) as usize;
+                for byte in stream_reliability.iter_mut().take(last_byte).skip(first_byte) {
+                    *byte = 0;
+                }
+            }
+        }
+    }
+    
+    Ok((stream, stream_reliability, report))
+}
+
+// BT.601 luma of an RGB pixel
+pub fn luma(r: u8, g: u8, b: u8) -> u8 {
+    ((r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::{Data, Settings};
+    
+    // Frames of a payload three and a half frames long, with their layout
+    fn render() -> (Vec<u8>, Vec<RgbImage>, FrameLayout) {
+        let settings = Settings::new(4, 1, 10, 256, 144);
+        let frame_bytes = etcher::frame_bits(&settings, OutputMode::Binary) / 8;
+        let payload: Vec<u8> = (0..frame_bytes * 7 / 2).map(|i| (i * 41 + i / 9) as u8).collect();
+        let data = Data::new(payload.clone(), OutputMode::Binary);
+        let frames: Vec<RgbImage> = etcher::frames(&data, &settings).collect();
+        assert_eq!(frames.len(), 4);
+        
+        let header = frame_header::read(&frames[0]).unwrap();
+        let layout = FrameLayout::resolve(Some(&header), None, &LayoutHint::default(), 256, 144).unwrap();
+        (payload, frames, layout)
+    }
+    
+    fn decode(frames: Vec<RgbImage>, layout: &FrameLayout) -> Result<(Vec<u8>, FrameReport)> {
+        let (stream, _, report) = decode_frames(frames.into_iter().map(Ok), layout)?;
+        Ok((stream, report))
+    }
+    
+    fn counts(report: &FrameReport) -> [u64; 6] {
+        [report.expected, report.received, report.missing, report.duplicated, report.corrupt, report.out_of_order]
+    }
+    
+    #[test]
+    fn intact_frames() {
+        let (payload, frames, layout) = render();
+        let (stream, report) = decode(frames, &layout).unwrap();
+        assert_eq!(stream, payload);
+        assert_eq!(counts(&report), [4, 4, 0, 0, 0, 0]);
+        assert!(report.problems.is_empty() && !report.damaged());
+    }
+    
+    #[test]
+    fn missing_and_duplicated_frames() {
+        let (payload, frames, layout) = render();
+        let frames = vec![frames[0].clone(), frames[1].clone(), frames[1].clone(), frames[3].clone()];
+        let (stream, report) = decode(frames, &layout).unwrap();
+        assert_eq!(counts(&report), [4, 4, 1, 1, 0, 0]);
+        assert!(report.damaged());
+        
+        // Frame 2 is left as zeros, the rest is in place
+        let bits = layout.frame_bits() as usize;
+        let (start, end) = (bits * 2, bits * 3);
+        assert_eq!(&stream[..start / 8], &payload[..start / 8]);
+        assert!(stream[start.div_ceil(8)..end / 8].iter().all(|&byte| byte == 0));
+        assert_eq!(&stream[end.div_ceil(8)..], &payload[end.div_ceil(8)..]);
+    }
+    
+    #[test]
+    fn reordered_frames() {
+        let (payload, mut frames, layout) = render();
+        frames.swap(1, 2);
+        let (stream, report) = decode(frames, &layout).unwrap();
+        assert_eq!(stream, payload);
+        assert_eq!(counts(&report), [4, 4, 0, 0, 0, 1]);
+        assert!(!report.damaged());
+    }
+    
+    #[test]
+    fn corrupt_frame() {
+        let (_, mut frames, layout) = render();
+        // Invert one block in the middle of frame 2
+        let (x, y) = (layout.width / 2, (layout.top + layout.bottom) / 2);
+        let (x, y) = (x - x % layout.block_size, y - (y - layout.top) % layout.block_size);
+        for dy in 0..layout.block_size {
+            for dx in 0..layout.block_size {
+                let Rgb([v, _, _]) = *frames[2].get_pixel(x + dx, y + dy);
+                frames[2].put_pixel(x + dx, y + dy, Rgb([255 - v; 3]));
+            }
+        }
+        let (_, report) = decode(frames, &layout).unwrap();
+        assert_eq!(counts(&report), [4, 4, 0, 0, 1, 0]);
+        assert!(report.damaged());
+    }
+    
+    #[test]
+    fn rejects_counts_the_frames_do_not_bear_out() {
+        let (_, mut frames, layout) = render();
+        
+        // A footer claiming four billion frames
+        let crc = frame_footer::read(&frames[0]).unwrap().data_crc;
+        frame_footer::render(&mut frames[0], FrameFooter { index: 0, total: u32::MAX, data_crc: crc });
+        assert!(decode(frames.clone(), &layout).is_err());
+        
+        // A footer numbering a frame far past the others
+        frame_footer::render(&mut frames[0], FrameFooter { index: 1 << 30, total: 1 << 31, data_crc: crc });
+        assert!(decode(frames, &layout).is_err());
+        
+        // A payload longer than the frames can hold
+        let (_, frames, mut layout) = render();
+        layout.data_size = Some(u64::MAX / 2);
+        assert!(decode(frames, &layout).is_err());
+    }
+}
\ No newline at end of file
@@ -1,5 +1,10 @@
+use std::path::Path;
 use crate::args::DislodgeParams;
-use crate::etcher;
+use crate::decoder::LayoutHint;
+use anyhow::anyhow;
+use crate::container::{self, Integrity};
+use crate::crypto::{self, Sealed};
+use crate::{compress, etcher, signing};
 
 pub async fn run_dislodge(args: DislodgeParams) -> anyhow::Result<()> {
     println!("Starting dislodge process...");
@@ -10,17 +15,99 @@ pub async fn run_dislodge(args: DislodgeParams) -> anyhow::Result<()> {
         "output.binvid".to_string()
     });
     
+    println!("Reading from: {}", in_path);
+    
+    let hint = LayoutHint {
+        mode: args.mode.map(Into::into),
+        block_size: args.block_size,
+        data_size: args.data_size,
+        fec_parity: args.fec_parity,
+        threshold: args.threshold.map(Into::into).unwrap_or_default(),
+        sampling: args.sampling.map(Into::into).unwrap_or_default(),
+        compare_sampling: args.compare_sampling,
+    };
+    
+    // Read the encoded data and extract it
+    let mut container = etcher::read(&in_path, &hint)?;
+    
+    let keys = crypto::Keys {
+        password: crypto::read_password(args.password, args.password_file)?,
+        identity: args.identity.as_deref().map(crypto::read_identity).transpose()?,
+    };
+    let verify_key = args.verify_key.as_deref().map(signing::read_verifying_key).transpose()?;
+    
+    // The signature covers the header as stored, before decryption fills in the file name and hash
+    let stored_header = container.header.clone();
+    crypto::open(&mut container, &keys)?;
+    compress::restore(&mut container)?;
+    
+    if let Some(header) = &container.header {
+        println!("Format version: {}", container::FORMAT_VERSION);
+        if let Some(filename) = &header.filename {
+            println!("Original file name: {}", filename);
+        }
+    }
+    
+    // Compare against the hash recorded at embed time before writing anything
+    match container.verify() {
+        Integrity::Verified => println!("Integrity check passed: SHA-256 matches ({} bytes)", container.payload.len()),
+        Integrity::Unchecked => println!("Integrity check skipped: the data carries no hash to compare against"),
+        Integrity::Mismatch { expected, actual } => {
+            println!("Integrity check FAILED");
+            println!("  Expected: {}", expected);
+            println!("  Actual:   {}", actual);
+            if !args.force {
+                return Err(anyhow!("Extracted data does not match the embedded file; use --force to write it anyway"));
+            }
+            println!("Writing the output anyway (--force)");
+        }
+    }
+    
+    // A signature is only trusted against a key the user supplied
+    match (&stored_header, &verify_key) {
+        (Some(header), Some(key)) => {
+            let file = Sealed {
+                filename: container.header.as_ref().and_then(|h| h.filename.clone()),
+                sha256: container::sha256_hex(&container.payload),
+            };
+            signing::verify(header, &file, key)?;
+            println!("Signature OK: signed by {}", signing::format_public_key(key));
+        }
+        (None, Some(_)) => return Err(anyhow!("The data has no container header to carry a signature, but --verify-key was given")),
+        (Some(header), None) => {
+            if let Some(signature) = &header.signature {
+                println!("Signed by {}{} (not checked; pass --verify-key to check it)", signing::PUBLIC_KEY_PREFIX, signature.public_key);
+            }
+        }
+        (None, None) => (),
+    }
+    
+    // Default to the original file name when the container recorded one.
+    // The name comes from the video, so it never replaces an existing file
+    // unless asked to.
+    let explicit = args.out_path.is_some();
     let out_path = args.out_path.unwrap_or_else(|| {
-        println!("No output path specified, using extracted_file.bin");
-        "extracted_file.bin".to_string()
+        // Only the last path component is used, so a crafted name cannot escape the current directory
+        let filename = container.header.as_ref()
+            .and_then(|h| h.filename.as_deref())
+            .and_then(|name| Path::new(name).file_name())
+            .map(|name| name.to_string_lossy().to_string());
+        
+        match filename {
+            Some(filename) => filename,
+            None => {
+                println!("No output path specified, using extracted_file.bin");
+                "extracted_file.bin".to_string()
+            }
+        }
     });
     
-    println!("Reading from: {}", in_path);
     println!("Writing to: {}", out_path);
-    
-    // Read the encoded data and extract it
-    let out_data = etcher::read(&in_path, 1)?;
-    etcher::write_bytes(&out_path, out_data)?;
+    if explicit || args.overwrite {
+        etcher::write_bytes(&out_path, container.payload)?;
+    } else {
+        etcher::write_new_bytes(&out_path, container.payload)?;
+    }
     
     println!("Dislodge process completed successfully!");
     println!("Extracted data written to: {}", out_path);
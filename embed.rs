@@ -1,6 +1,9 @@
 use crate::{
-    args::{EmbedParams, EmbedPreset},
-    etcher,
+    args::{EmbedOutputFormat, EmbedParams, EmbedPreset},
+    avi::{self, AviCodec},
+    compress, container, crypto::{self, Protection}, etcher, fec, fountain, interleave,
+    ffmpeg::{self, FfmpegCodec},
+    png_sequence, signing, y4m::{self, Y4mColor},
     settings::{Data, OutputMode, Settings},
 };
 
@@ -10,6 +13,7 @@ pub async fn run_embed(args: EmbedParams) -> anyhow::Result<()> {
     //Should use enums
     let mut settings = Settings::default();
     let mut output_mode = OutputMode::Binary;
+    let mut quality = ffmpeg::Quality::default();
     
     match args.preset {
         Some(EmbedPreset::MaxEfficiency) => {
@@ -19,6 +23,8 @@ pub async fn run_embed(args: EmbedParams) -> anyhow::Result<()> {
             settings.fps = 10.0;
             settings.width = 256;
             settings.height = 144;
+            quality = ffmpeg::Quality { crf: 0, preset: "veryslow" };
+            settings.fec_parity = 8;
         }
         Some(EmbedPreset::Optimal) => {
             output_mode = OutputMode::Binary;
@@ -27,6 +33,8 @@ pub async fn run_embed(args: EmbedParams) -> anyhow::Result<()> {
             settings.fps = 10.0;
             settings.width = 1280;
             settings.height = 720;
+            quality = ffmpeg::Quality { crf: 18, preset: "slow" };
+            settings.fec_parity = 32;
         }
         Some(EmbedPreset::Paranoid) => {
             output_mode = OutputMode::Binary;
@@ -35,6 +43,8 @@ pub async fn run_embed(args: EmbedParams) -> anyhow::Result<()> {
             settings.fps = 10.0;
             settings.width = 1280;
             settings.height = 720;
+            quality = ffmpeg::Quality { crf: 23, preset: "medium" };
+            settings.fec_parity = 64;
         }
         _ => (),
     }
@@ -60,8 +70,13 @@ pub async fn run_embed(args: EmbedParams) -> anyhow::Result<()> {
         }
     }
     
+    let format = args.format.unwrap_or_default();
     if let Some(mode) = args.mode {
         output_mode = mode.into();
+    } else if output_mode == OutputMode::Color && !carries_color(&args, format) {
+        // Sixteen gray levels are the densest mode left once the colour channels are lost
+        println!("{:?} output cannot carry COLOR mode, using GRAY16 instead", format);
+        output_mode = OutputMode::Gray16;
     }
     
     if let Some(bs) = args.block_size {
@@ -75,6 +90,25 @@ pub async fn run_embed(args: EmbedParams) -> anyhow::Result<()> {
     if let Some(fps) = args.fps {
         settings.fps = fps as f64;
     }
+    
+    if let Some(parity) = args.fec_parity {
+        settings.fec_parity = parity;
+    } else if args.preset.is_none() {
+        settings.fec_parity = fec::DEFAULT_PARITY;
+    }
+    
+    // Fall back to the interactive defaults for anything still unset
+    if settings.size <= 0 {
+        settings.size = 2;
+    }
+    
+    if settings.fps <= 0.0 {
+        settings.fps = 10.0;
+    }
+    
+    if settings.threads == 0 {
+        settings.threads = 8;
+    }
 
     // Get the input path or use a default
     let input_path = args.in_path.unwrap_or_else(|| {
@@ -83,23 +117,145 @@ pub async fn run_embed(args: EmbedParams) -> anyhow::Result<()> {
     });
 
     match output_mode {
-        OutputMode::Color => {
-            println!("Using COLOR mode with block size: {}", settings.size);
-            let bytes = etcher::rip_bytes(&input_path)?;
-            let data = Data::from_color(bytes);
-            etcher::etch("output.binvid", data, settings)?;
+        OutputMode::Color => println!("Using COLOR mode with block size: {}", settings.size),
+        OutputMode::Binary => println!("Using BINARY mode with block size: {}", settings.size),
+        OutputMode::Palette8 | OutputMode::Palette16 => println!(
+            "Using PALETTE mode ({} bits per 2x2 block group) with block size: {}",
+            output_mode.bits_per_group(), settings.size
+        ),
+        mode => println!(
+            "Using GRAYSCALE mode ({} levels, {} bits per block) with block size: {}",
+            mode.levels().unwrap(), mode.bits_per_group(), settings.size
+        ),
+    }
+    
+    let payload = etcher::rip_bytes(&input_path)?;
+    
+    let protection = Protection {
+        password: crypto::read_password(args.password, args.password_file)?,
+        recipients: args.recipient.iter()
+            .map(|key| crypto::parse_public_key(key))
+            .collect::<anyhow::Result<_>>()?,
+        signing_key: args.sign_key.as_deref().map(signing::read_signing_key).transpose()?,
+    };
+    
+    let compression = compress::Options {
+        level: args.compress_level,
+        ..args.compress.map(Into::into).unwrap_or_default()
+    };
+    
+    // The legacy header has nowhere to keep the codec, salt, nonce, wrapped keys or signature
+    let compressed = compression.auto || compression.codec != compress::Codec::None;
+    if matches!(format, EmbedOutputFormat::BinvidV1) && (compressed || protection.is_encrypted() || protection.signing_key.is_some()) {
+        return Err(anyhow::anyhow!("Compression, encryption and signing need the v2 container; they are not available with --format binvid-v1"));
+    }
+    if protection.is_encrypted() {
+        println!("Encrypting the data with ChaCha20-Poly1305");
+    }
+    if let Some(key) = &protection.signing_key {
+        println!("Signing with {}", signing::format_public_key(&key.verifying_key()));
+    }
+    
+    // Files are stored byte for byte, only video frames need error correction
+    if matches!(format, EmbedOutputFormat::Binvid | EmbedOutputFormat::BinvidV1) {
+        settings.fec_parity = 0;
+    } else {
+        settings.fountain = args.fountain;
+        settings.calibration_frames = args.calibration_frames.unwrap_or(1);
+        settings.calibration_interval = args.calibration_interval.unwrap_or(0);
+    }
+    
+    if settings.fec_parity > 0 {
+        println!(
+            "Reed–Solomon FEC: {} check bytes per 255 byte codeword (fixes up to {} bad bytes each)",
+            settings.fec_parity, settings.fec_parity / 2
+        );
+    }
+    
+    if let Some(overhead) = settings.fountain {
+        println!("Fountain coding: {}% repair symbols, every frame decodes on its own", overhead);
+    }
+    
+    if settings.calibration_frames > 0 || settings.calibration_interval > 0 {
+        print!("Calibration frames: {} in front", settings.calibration_frames);
+        if settings.calibration_interval > 0 {
+            print!(", then one every {} frames", settings.calibration_interval);
+        }
+        println!();
+    }
+    
+    // Legacy files hold the bare payload, every other format a v2 container
+    let data = match format {
+        EmbedOutputFormat::BinvidV1 => Data::new(payload, output_mode),
+        _ => {
+            let packed = container::pack(&payload, &input_path, output_mode, &settings, &compression, &protection)?;
+            
+            if settings.fountain.is_some() {
+                // Fountain frames carry their own FEC, confined to the frame
+                Data::new(fountain::encode(&packed, output_mode, &settings)?, output_mode)
+            } else {
+                let mut protected = fec::encode(&packed, settings.fec_parity);
+                
+                // Spread every codeword over all frames so bursts of damage stay correctable
+                if settings.fec_parity > 0 {
+                    protected = interleave::interleave(&protected, fec::CODEWORD_LEN);
+                }
+                Data::new(protected, output_mode)
+            }
+        }
+    };
+    
+    let out_path = args.out_path.unwrap_or_else(|| match format {
+        EmbedOutputFormat::Binvid | EmbedOutputFormat::BinvidV1 => "output.binvid".to_string(),
+        EmbedOutputFormat::Png => "output_frames".to_string(),
+        EmbedOutputFormat::Y4m => "output.y4m".to_string(),
+        EmbedOutputFormat::Avi => "output.avi".to_string(),
+        EmbedOutputFormat::Mp4 => "output.mp4".to_string(),
+        EmbedOutputFormat::Mkv => "output.mkv".to_string(),
+    });
+    
+    match format {
+        EmbedOutputFormat::Binvid => etcher::etch(&out_path, data, settings)?,
+        EmbedOutputFormat::BinvidV1 => etcher::etch_legacy(&out_path, data, settings)?,
+        EmbedOutputFormat::Png => png_sequence::write_sequence(&out_path, &data, &settings)?,
+        EmbedOutputFormat::Y4m => {
+            let color = args.y4m_color.map(Into::into).unwrap_or(Y4mColor::Bt601);
+            // Rounding to YCbCr and back moves raw bytes in the colour channels
+            if color == Y4mColor::Bt601 && output_mode == OutputMode::Color {
+                return Err(anyhow::anyhow!("COLOR mode does not survive YCbCr; use --y4m-color rct (such files only decode with this tool)"));
+            }
+            y4m::write_y4m(&out_path, &data, &settings, color)?
+        }
+        EmbedOutputFormat::Avi => {
+            let codec: AviCodec = args.avi_codec.map(Into::into).unwrap_or(AviCodec::Mjpeg);
+            // JPEG cannot carry raw bytes in the colour channels
+            if codec == AviCodec::Mjpeg && output_mode == OutputMode::Color {
+                return Err(anyhow::anyhow!("COLOR mode does not survive MJPEG; use --avi-codec raw"));
+            }
+            avi::write_avi(&out_path, &data, &settings, codec)?
         }
-        OutputMode::Binary => {
-            println!("Using BINARY mode with block size: {}", settings.size);
-            let bytes = etcher::rip_bytes(&input_path)?;
-            let binary = etcher::rip_binary(bytes)?;
-            let data = Data::from_binary(binary);
-            etcher::etch("output.binvid", data, settings)?;
+        EmbedOutputFormat::Mp4 => {
+            // H.264 cannot carry raw bytes in the colour channels
+            if output_mode == OutputMode::Color {
+                return Err(anyhow::anyhow!("COLOR mode does not survive H.264; use --format mkv"));
+            }
+            ffmpeg::write_video(&out_path, &data, &settings, FfmpegCodec::H264, &quality)?
         }
+        EmbedOutputFormat::Mkv => ffmpeg::write_video(&out_path, &data, &settings, FfmpegCodec::Ffv1, &quality)?,
     }
     
     println!("Embed process completed successfully!");
-    println!("Output file: output.binvid");
+    println!("Output: {}", out_path);
     
     Ok(())
+}
+
+// Whether `format` keeps the raw bytes that COLOR mode puts in the colour channels
+fn carries_color(args: &EmbedParams, format: EmbedOutputFormat) -> bool {
+    match format {
+        EmbedOutputFormat::Avi => args.avi_codec.map(Into::into) == Some(AviCodec::Raw),
+        EmbedOutputFormat::Y4m => args.y4m_color.map(Into::into) == Some(Y4mColor::Rct),
+        EmbedOutputFormat::Mp4 => false,
+        _ => true,
+    }
 }
\ No newline at end of file
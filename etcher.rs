@@ -2,9 +2,18 @@ use std::fs;
 use std::io::{Write, Read};
 use std::path::Path;
 use anyhow::{Result, anyhow};
-use crate::settings::{Data, Settings, OutputMode};
+use crate::settings::{BitWriter, Data, Settings, OutputMode};
+use crate::calibration;
+use crate::container::{self, Container};
+use crate::decoder::{self, LayoutHint};
+use crate::fiducial::Markers;
+use crate::frame_footer::{self, FrameFooter};
+use crate::frame_header::{self, FrameHeader};
+use crate::gray;
+use crate::palette::Palette;
 use image::{RgbImage, Rgb};
 use serde::{Serialize, Deserialize};
+use indicatif::{ProgressBar, ProgressStyle};
 
 /// Read a file and convert it to bytes
 pub fn rip_bytes<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
@@ -12,20 +21,6 @@ pub fn rip_bytes<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
     Ok(bytes)
 }
 
-/// Convert bytes to binary (boolean vector)
-pub fn rip_binary(bytes: Vec<u8>) -> Result<Vec<bool>> {
-    let mut binary = Vec::with_capacity(bytes.len() * 8);
-    
-    for byte in bytes {
-        for i in 0..8 {
-            let bit = (byte >> i) & 1 == 1;
-            binary.push(bit);
-        }
-    }
-    
-    Ok(binary)
-}
-
 /// Write bytes to a file
 pub fn write_bytes<P: AsRef<Path>>(path: P, bytes: Vec<u8>) -> Result<()> {
     let mut file = fs::File::create(path)?;
@@ -33,30 +28,103 @@ pub fn write_bytes<P: AsRef<Path>>(path: P, bytes: Vec<u8>) -> Result<()> {
     Ok(())
 }
 
-#[derive(Serialize, Deserialize)]
-struct VideoMetadata {
-    mode: String,
-    frames: u32,
-    width: u32,
-    height: u32,
-    block_size: u32,
-    data_size: usize,
+/// Write bytes to a file that must not exist yet
+pub fn write_new_bytes<P: AsRef<Path>>(path: P, bytes: Vec<u8>) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::AlreadyExists => anyhow!(
+                "{} already exists; choose the output with -o, or pass --overwrite to replace it",
+                path.as_ref().display()
+            ),
+            _ => e.into(),
+        })?;
+    file.write_all(&bytes)?;
+    Ok(())
+}
+
+/// Layout of the rendered frames, enough to turn them back into the payload
+/// (every frame also has the strips from `frame_header` and `frame_footer`)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VideoMetadata {
+    pub mode: OutputMode,
+    pub frames: u64,
+    pub width: u32,
+    pub height: u32,
+    pub block_size: u32,
+    pub fps: f64,
+    /// Payload length in bytes
+    pub data_size: u64,
+    /// Reed–Solomon check bytes per codeword, 0 when the data is unprotected
+    #[serde(default)]
+    pub fec_parity: u8,
+    /// Whether the codewords are interleaved across frames (see interleave.rs)
+    #[serde(default)]
+    pub interleaved: bool,
+    /// Whether every frame carries its own fountain-coded packet (see fountain.rs)
+    #[serde(default)]
+    pub fountain: bool,
+    /// Calibration frames mixed in with the data frames (see calibration.rs)
+    #[serde(default)]
+    pub calibration_frames: u64,
+    /// Whether the data area carries corner markers (see fiducial.rs)
+    #[serde(default)]
+    pub markers: bool,
 }
 
-/// Read binary data from the encoded format
-pub fn read<P: AsRef<Path>>(path: P, _mode: i32) -> Result<Vec<u8>> {
+impl VideoMetadata {
+    pub fn new(data: &Data, settings: &Settings) -> VideoMetadata {
+        VideoMetadata::for_len(data.len_bytes(), data.out_mode, settings)
+    }
+    
+    /// Metadata for `len` bytes rendered in `mode`
+    pub fn for_len(len: u64, mode: OutputMode, settings: &Settings) -> VideoMetadata {
+        let frames = frame_count_for(len, mode, settings);
+        VideoMetadata {
+            mode,
+            frames,
+            width: settings.width as u32,
+            height: settings.height as u32,
+            block_size: settings.size as u32,
+            fps: settings.fps,
+            data_size: len,
+            fec_parity: settings.fec_parity,
+            interleaved: settings.fec_parity > 0 && settings.fountain.is_none(),
+            fountain: settings.fountain.is_some(),
+            calibration_frames: calibration::frame_count(frames, settings),
+            markers: true,
+        }
+    }
+}
+
+/// Read binary data from the encoded format, or decode it from video frames
+pub fn read<P: AsRef<Path>>(path: P, hint: &LayoutHint) -> Result<Container> {
     println!("Reading encoded data from: {}", path.as_ref().display());
+    
+    // Frame sequences are stored as directories
+    if path.as_ref().is_dir() {
+        return container::from_frames(decoder::read_video(&path, hint)?);
+    }
+    
     let mut file = fs::File::open(&path)?;
     
     // Read the first 10 bytes to determine the format
     let mut header = [0u8; 10];
-    file.read_exact(&mut header)?;
-    
-    let header_str = std::str::from_utf8(&header[0..6])
-        .map_err(|_| anyhow!("Invalid header format"))?;
+    if file.read_exact(&mut header).is_err() {
+        return Err(anyhow!("Unknown file format"));
+    }
     
-    match header_str {
-        "BINVID" => {
+    match &header[0..6] {
+        magic if magic == container::MAGIC => {
+            // Versioned container, stored byte for byte
+            let mut bytes = header.to_vec();
+            file.read_to_end(&mut bytes)?;
+            
+            container::unpack(bytes)
+        },
+        b"BINVID" => {
             // Binary mode
             let mut length_bytes = [0u8; 4];
             length_bytes.copy_from_slice(&header[6..10]);
@@ -115,9 +183,9 @@ pub fn read<P: AsRef<Path>>(path: P, _mode: i32) -> Result<Vec<u8>> {
                 bytes.push(current_byte);
             }
             
-            Ok(bytes)
+            Ok(Container::raw(bytes))
         },
-        "COLVID" => {
+        b"COLVID" => {
             // Color mode
             let mut length_bytes = [0u8; 4];
             length_bytes.copy_from_slice(&header[6..10]);
@@ -127,176 +195,433 @@ pub fn read<P: AsRef<Path>>(path: P, _mode: i32) -> Result<Vec<u8>> {
             let mut data = Vec::with_capacity(data_length);
             file.read_to_end(&mut data)?;
             
-            Ok(data)
+            Ok(Container::raw(data))
         },
-        _ => Err(anyhow!("Unknown file format")),
+        // Anything else is treated as a video and decoded frame by frame
+        _ => container::from_frames(decoder::read_video(&path, hint)?),
     }
 }
 
-/// Encode data to our custom format which simulates how we would encode it to video frames
-pub fn etch<P: AsRef<Path> + Clone>(path: P, data: Data, settings: Settings) -> Result<()> {
+/// First pixel row below the frame header strip, where the data blocks start
+pub fn data_top(settings: &Settings) -> u32 {
+    frame_header::height(settings.width.max(0) as u32)
+}
+
+/// First pixel row of the frame footer strip, where the data blocks end
+pub fn data_bottom(settings: &Settings) -> u32 {
+    (settings.height.max(0) as u32).saturating_sub(frame_footer::height(settings.width.max(0) as u32))
+}
+
+/// Where the data blocks of a frame go: block groups (single blocks outside
+/// the palette modes) left to right, top to bottom between the header and
+/// footer strips, skipping any that a corner marker covers. Palette modes only
+/// use whole 2x2 groups, so an odd last row or column stays empty.
+#[derive(Debug, Clone)]
+pub struct BlockGrid {
+    top: u32,
+    // Side of a group in pixels
+    group: u32,
+    columns: u32,
+    rows: u32,
+    markers: Option<Markers>,
+}
+
+impl BlockGrid {
+    pub fn new(width: u32, top: u32, bottom: u32, block_size: u32, mode: OutputMode, markers: bool) -> BlockGrid {
+        let group = block_size * mode.group_side();
+        let (columns, rows) = match group {
+            0 => (0, 0),
+            _ => (width / group, bottom.saturating_sub(top) / group),
+        };
+        BlockGrid {
+            top,
+            group,
+            columns,
+            rows,
+            markers: markers.then(|| Markers::new(width, top, bottom)),
+        }
+    }
+    
+    /// Top left pixel of every group that carries data, in stream order
+    pub fn groups(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        (0..self.rows)
+            .flat_map(move |row| (0..self.columns).map(move |column| (column * self.group, self.top + row * self.group)))
+            .filter(|&(x, y)| !self.markers.is_some_and(|markers| markers.covers(x, y, self.group, self.group)))
+    }
+    
+    /// Number of groups that carry data
+    pub fn group_count(&self) -> u64 {
+        self.groups().count() as u64
+    }
+}
+
+/// Grid of data blocks of the frames rendered with these settings
+pub fn block_grid(settings: &Settings, mode: OutputMode) -> BlockGrid {
+    let width = settings.width.max(0) as u32;
+    BlockGrid::new(width, data_top(settings), data_bottom(settings), settings.size.max(0) as u32, mode, true)
+}
+
+/// Number of data blocks in one frame
+pub fn blocks_per_frame(settings: &Settings, mode: OutputMode) -> usize {
+    let side = mode.group_side() as u64;
+    (block_grid(settings, mode).group_count() * side * side) as usize
+}
+
+/// Bits of the rendered stream one frame carries
+pub fn frame_bits(settings: &Settings, mode: OutputMode) -> u64 {
+    block_grid(settings, mode).group_count() * mode.bits_per_group() as u64
+}
+
+/// Make sure the header and footer strips, the corner markers and at least
+/// one block (or block group) fit in a frame with these settings
+pub fn check_layout(settings: &Settings, mode: OutputMode) -> Result<()> {
+    let width = settings.width.max(0) as u32;
+    frame_header::check(width, settings.height.max(0) as u32, settings.size.max(0) as u32)?;
+    let (top, bottom) = (data_top(settings), data_bottom(settings));
+    if !Markers::new(width, top, bottom).fit(width, top, bottom) {
+        return Err(anyhow!("A {}x{} frame is too small for the corner markers", settings.width, settings.height));
+    }
+    if frame_bits(settings, mode) == 0 {
+        return Err(anyhow!(
+            "Block size {} does not fit {} in a {}x{} frame",
+            settings.size,
+            if mode.group_side() > 1 { "a group of 2x2 blocks" } else { "a block" },
+            settings.width, settings.height
+        ));
+    }
+    Ok(())
+}
+
+/// Number of frames needed to render all of `data`
+pub fn frame_count(data: &Data, settings: &Settings) -> u64 {
+    frame_count_for(data.len_bytes(), data.out_mode, settings)
+}
+
+/// Number of frames needed to render `len` bytes in the given mode
+pub fn frame_count_for(len: u64, mode: OutputMode, settings: &Settings) -> u64 {
+    let bits = frame_bits(settings, mode);
+    if bits == 0 {
+        return 0;
+    }
+    
+    // Always emit at least one frame so empty payloads still produce a video
+    (len * 8).div_ceil(bits).max(1)
+}
+
+/// Iterator over the rendered frames of a payload, in playback order, with
+/// the calibration frames in their places
+pub struct Frames<'a> {
+    data: &'a Data,
+    settings: &'a Settings,
+    next: u64,
+    total: u64,
+    // Rendered once and repeated wherever a calibration frame goes
+    calibration: Option<RgbImage>,
+    // Calibration frames due before the next data frame
+    pending_calibration: u64,
+}
+
+impl Iterator for Frames<'_> {
+    type Item = RgbImage;
+    
+    fn next(&mut self) -> Option<RgbImage> {
+        if self.pending_calibration > 0 {
+            self.pending_calibration -= 1;
+            return self.calibration.clone();
+        }
+        if self.next >= self.total {
+            return None;
+        }
+        
+        let frame = render_frame(self.data, self.settings, self.next);
+        self.next += 1;
+        
+        let interval = self.settings.calibration_interval as u64;
+        if interval > 0 && self.next < self.total && self.next.is_multiple_of(interval) {
+            self.pending_calibration = 1;
+        }
+        Some(frame)
+    }
+    
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // Periodic calibration frames go before data frames interval, 2 * interval, ...
+        let interval = self.settings.calibration_interval as u64;
+        let periodic = match interval {
+            0 => 0,
+            _ => self.total.saturating_sub(1) / interval - self.next.min(self.total.saturating_sub(1)) / interval,
+        };
+        let remaining = (self.total - self.next + self.pending_calibration + periodic) as usize;
+        (remaining, Some(remaining))
+    }
+}
+
+impl ExactSizeIterator for Frames<'_> {}
+
+/// Render every frame of `data` laid out in `settings.size` blocks
+pub fn frames<'a>(data: &'a Data, settings: &'a Settings) -> Frames<'a> {
+    let total = frame_count(data, settings);
+    let calibration = (calibration::frame_count(total, settings) > 0)
+        .then(|| calibration::render_frame(data.out_mode, &VideoMetadata::new(data, settings), settings));
+    Frames {
+        data,
+        settings,
+        next: 0,
+        total,
+        calibration,
+        pending_calibration: settings.calibration_frames as u64,
+    }
+}
+
+/// Render a single frame: the header strip, the corner markers, blocks filled
+/// in `BlockGrid` order, then the footer strip. Palette modes fill 2x2 groups
+/// of blocks in that order instead. Blocks past the end of the payload are
+/// left black.
+pub fn render_frame(data: &Data, settings: &Settings, index: u64) -> RgbImage {
+    let img_width = settings.width as u32;
+    let mut img = RgbImage::new(img_width, settings.height as u32);
+    
+    let metadata = VideoMetadata::new(data, settings);
+    frame_header::render(&mut img, &FrameHeader::new(&metadata));
+    let palette = Palette::nominal(data.out_mode);
+    if let Some(levels) = data.out_mode.levels() {
+        gray::render_swatches(&mut img, levels);
+    }
+    if let Some(palette) = &palette {
+        palette.render_swatches(&mut img);
+    }
+    
+    let top = data_top(settings);
+    Markers::new(img_width, top, data_bottom(settings)).render(&mut img);
+    
+    let block_size = settings.size as u32;
+    let side = data.out_mode.group_side();
+    let grid = block_grid(settings, data.out_mode);
+    let bits = data.out_mode.bits_per_group();
+    let first_bit = index * grid.group_count() * bits as u64;
+    
+    // The blocks as the decoder will read them back, for the footer CRC
+    let mut frame_data = BitWriter::default();
+    
+    for (group, (group_left, group_top)) in grid.groups().enumerate() {
+        // Zero padded at the end of the payload
+        let symbol = data.bits(first_bit + group as u64 * bits as u64, bits);
+        frame_data.push(symbol, bits);
+        
+        let colors = match &palette {
+            Some(palette) => palette.group_colors(symbol),
+            None => [match data.out_mode {
+                OutputMode::Binary => {
+                    if symbol == 1 {
+                        Rgb([255, 255, 255]) // White for 1
+                    } else {
+                        Rgb([0, 0, 0]) // Black for 0
+                    }
+                }
+                // Three bytes for RGB
+                OutputMode::Color => Rgb([symbol as u8, (symbol >> 8) as u8, (symbol >> 16) as u8]),
+                mode => gray::symbol_color(symbol, mode.levels().unwrap()),
+            }; 4],
+        };
+        
+        // Fill the block (or each block of the group) with its color
+        for (i, color) in colors.iter().take((side * side) as usize).enumerate() {
+            let left = group_left + (i as u32 % side) * block_size;
+            let block_top = group_top + (i as u32 / side) * block_size;
+            for by in 0..block_size {
+                for bx in 0..block_size {
+                    img.put_pixel(left + bx, block_top + by, *color);
+                }
+            }
+        }
+    }
+    
+    frame_footer::render(&mut img, FrameFooter {
+        index: index as u32,
+        total: metadata.frames as u32,
+        data_crc: crc32fast::hash(&frame_data.bytes),
+    });
+    
+    img
+}
+
+/// Write the first `bits` bits of `bytes` into `stream` starting at bit `offset`,
+/// least significant bit first. Bits past the end of `stream` are dropped; the
+/// target bits are expected to be clear.
+pub fn place_bits(stream: &mut [u8], offset: u64, bytes: &[u8], bits: u64) {
+    let end = (offset + bits).min(stream.len() as u64 * 8);
+    if end <= offset {
+        return;
+    }
+    let mut index = offset;
+    
+    // Whole bytes can be copied straight across when the offset is aligned
+    if offset.is_multiple_of(8) {
+        let whole = ((end - offset) / 8) as usize;
+        let start = (offset / 8) as usize;
+        stream[start..start + whole].copy_from_slice(&bytes[..whole]);
+        index += whole as u64 * 8;
+    }
+    
+    while index < end {
+        let bit = index - offset;
+        if (bytes[(bit / 8) as usize] >> (bit % 8)) & 1 == 1 {
+            stream[(index / 8) as usize] |= 1 << (index % 8);
+        }
+        index += 1;
+    }
+}
+
+/// Progress bar shared by the frame writers
+pub fn frame_progress(frames: usize) -> ProgressBar {
+    let pb = ProgressBar::new(frames as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} frames ({eta})")
+            .unwrap()
+            .progress_chars("#>-"),
+    );
+    pb
+}
+
+/// Print the settings and save the first rendered frame as PNG next to `path`
+fn write_sample<P: AsRef<Path>>(path: P, data: &Data, settings: &Settings) -> Result<()> {
     println!("Encoding data with the following settings:");
     println!("  Size: {}", settings.size);
     println!("  Resolution: {}x{}", settings.width, settings.height);
     println!("  FPS: {}", settings.fps);
     
-    // Calculate how many pixels we can fit per frame
-    let pixels_per_frame = (settings.width / settings.size) * (settings.height / settings.size);
-    println!("  Pixels per frame: {}", pixels_per_frame);
+    // Calculate how many blocks we can fit per frame
+    check_layout(settings, data.out_mode)?;
+    println!("  Blocks per frame: {}", blocks_per_frame(settings, data.out_mode));
+    
+    let mut frame_iter = frames(data, settings);
+    println!("  Frames needed: {}", frame_iter.len());
+    
+    // Save the first rendered frame as PNG to show what it looks like
+    let sample_path = format!("{}_sample.png", path.as_ref().to_string_lossy());
+    if let Some(img) = frame_iter.next() {
+        img.save(&sample_path)?;
+        println!("  Saved sample frame to: {}", sample_path);
+    }
+    
+    Ok(())
+}
+
+/// Write a v2 .binvid file. `data` holds a packed container (see `container::pack`),
+/// stored byte for byte so the file matches what the video frames carry.
+pub fn etch<P: AsRef<Path> + Clone>(path: P, data: Data, settings: Settings) -> Result<()> {
+    write_sample(path.clone(), &data, &settings)?;
     
-    // Store path as a string for sample file
-    let path_str = path.as_ref().to_string_lossy().to_string();
+    write_bytes(path.clone(), data.bytes)?;
     
-    match data.out_mode {
+    println!("Data encoded successfully to: {}", path.as_ref().display());
+    Ok(())
+}
+
+/// Encode data to the legacy BINVID/COLVID format understood by older readers
+pub fn etch_legacy<P: AsRef<Path> + Clone>(path: P, data: Data, settings: Settings) -> Result<()> {
+    // The legacy formats store the length as a u32, so refuse anything that
+    // would wrap instead of silently writing a truncated length
+    let (magic, data_len): (&[u8], u64) = match data.out_mode {
         OutputMode::Binary => {
             println!("Using binary (black/white) mode");
-            let total_bits = data.binary.len();
+            let total_bits = data.len_bytes() * 8;
             println!("  Total bits to encode: {}", total_bits);
-            
-            // Calculate how many frames we need
-            let bits_per_frame = pixels_per_frame;
-            let frames_needed = (total_bits as f64 / bits_per_frame as f64).ceil() as u32;
-            println!("  Frames needed: {}", frames_needed);
-            
-            // Generate a sample image to demonstrate the encoding
-            let img_width = settings.width as u32;
-            let img_height = settings.height as u32;
-            let mut img = RgbImage::new(img_width, img_height);
-            
-            // Fill the image with the binary data pattern
-            let block_size = settings.size as u32;
-            let mut bit_index = 0;
-            
-            for y in (0..img_height).step_by(block_size as usize) {
-                for x in (0..img_width).step_by(block_size as usize) {
-                    if bit_index < data.binary.len() {
-                        let color = if data.binary[bit_index] {
-                            Rgb([255, 255, 255]) // White for 1
-                        } else {
-                            Rgb([0, 0, 0]) // Black for 0
-                        };
-                        
-                        // Fill the block with the color
-                        for by in 0..block_size {
-                            for bx in 0..block_size {
-                                if y + by < img_height && x + bx < img_width {
-                                    img.put_pixel(x + bx, y + by, color);
-                                }
-                            }
-                        }
-                        
-                        bit_index += 1;
-                    }
-                }
-            }
-            
-            // Save a sample frame as PNG to show what it looks like
-            let sample_path = format!("{}_sample.png", path_str);
-            img.save(&sample_path)?;
-            println!("  Saved sample frame to: {}", sample_path);
-            
-            // For the actual data storage, we create our custom file format
-            let mut output_bytes = Vec::new();
-            
-            // Add header to indicate binary mode
-            output_bytes.extend_from_slice(b"BINVID");
-            
-            // Add data size as a u32 (4 bytes)
-            let data_len = (data.binary.len() as u32).to_le_bytes();
-            output_bytes.extend_from_slice(&data_len);
-            
-            // Pack boolean values into bytes (8 booleans per byte)
-            let mut current_byte = 0u8;
-            let mut bit_count = 0;
-            
-            for bit in data.binary {
-                if bit {
-                    current_byte |= 1 << bit_count;
-                }
-                
-                bit_count += 1;
-                
-                if bit_count == 8 {
-                    output_bytes.push(current_byte);
-                    current_byte = 0;
-                    bit_count = 0;
-                }
-            }
-            
-            // Push the final byte if there are remaining bits
-            if bit_count > 0 {
-                output_bytes.push(current_byte);
-            }
-            
-            // Write the encoded data to the file
-            write_bytes(path.clone(), output_bytes)?;
+            (b"BINVID", total_bits)
         }
         OutputMode::Color => {
             println!("Using color mode");
-            let total_bytes = data.bytes.len();
+            let total_bytes = data.len_bytes();
             println!("  Total bytes to encode: {}", total_bytes);
-            
-            // In color mode, each pixel can store 3 bytes (RGB)
-            let bytes_per_pixel = 3;
-            let bytes_per_frame = pixels_per_frame * bytes_per_pixel;
-            let frames_needed = (total_bytes as f64 / bytes_per_frame as f64).ceil() as u32;
-            println!("  Frames needed: {}", frames_needed);
-            
-            // Generate a sample image to demonstrate the encoding
-            let img_width = settings.width as u32;
-            let img_height = settings.height as u32;
-            let mut img = RgbImage::new(img_width, img_height);
-            
-            // Fill the image with the color data pattern
-            let block_size = settings.size as u32;
-            let mut byte_index = 0;
-            
-            for y in (0..img_height).step_by(block_size as usize) {
-                for x in (0..img_width).step_by(block_size as usize) {
-                    if byte_index + 2 < data.bytes.len() {
-                        // Use 3 bytes for RGB
-                        let r = data.bytes[byte_index];
-                        let g = data.bytes[byte_index + 1];
-                        let b = data.bytes[byte_index + 2];
-                        let color = Rgb([r, g, b]);
-                        
-                        // Fill the block with the color
-                        for by in 0..block_size {
-                            for bx in 0..block_size {
-                                if y + by < img_height && x + bx < img_width {
-                                    img.put_pixel(x + bx, y + by, color);
-                                }
-                            }
-                        }
-                        
-                        byte_index += 3;
-                    }
-                }
-            }
-            
-            // Save a sample frame as PNG to show what it looks like
-            let sample_path = format!("{}_sample.png", path_str);
-            img.save(&sample_path)?;
-            println!("  Saved sample frame to: {}", sample_path);
-            
-            // For the actual data storage, we create our custom file format
-            let mut output_bytes = Vec::new();
-            
-            // Add header to indicate color mode
-            output_bytes.extend_from_slice(b"COLVID");
-            
-            // Add data size as a u32 (4 bytes)
-            let data_len = (data.bytes.len() as u32).to_le_bytes();
-            output_bytes.extend_from_slice(&data_len);
-            
-            // Add the actual data
-            output_bytes.extend_from_slice(&data.bytes);
-            
-            // Write the encoded data to the file
-            write_bytes(path.clone(), output_bytes)?;
+            (b"COLVID", total_bytes)
         }
-    }
+        mode => return Err(anyhow!("{:?} mode needs the v2 binvid format; the legacy format only knows binary and color", mode)),
+    };
+    
+    let data_len = u32::try_from(data_len).map_err(|_| anyhow!(
+        "Payload is too large for the legacy {} format ({} {}, max {}); use the v2 binvid format instead",
+        String::from_utf8_lossy(magic),
+        data_len,
+        if data.out_mode == OutputMode::Binary { "bits" } else { "bytes" },
+        u32::MAX
+    ))?;
+    
+    // Only once the data is known to fit, so a rejected file leaves nothing behind
+    write_sample(path.clone(), &data, &settings)?;
+    
+    // For the actual data storage, we create our custom file format
+    let mut output_bytes = Vec::with_capacity(magic.len() + 4 + data.bytes.len());
+    
+    // Add header to indicate the mode
+    output_bytes.extend_from_slice(magic);
+    
+    // Add data size as a u32 (4 bytes)
+    output_bytes.extend_from_slice(&data_len.to_le_bytes());
+    
+    // Binary data is already packed 8 bits per byte, least significant first
+    output_bytes.extend_from_slice(&data.bytes);
+    
+    // Write the encoded data to the file
+    write_bytes(path.clone(), output_bytes)?;
     
     println!("Data encoded successfully to: {}", path.as_ref().display());
     Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::FrameLayout;
+    
+    /// Render `len` bytes and read every frame's blocks back
+    fn round_trip(mode: OutputMode, len: usize) {
+        let settings = Settings::new(4, 1, 10, 320, 240);
+        let payload: Vec<u8> = (0..len).map(|i| (i * 89 + i / 7) as u8).collect();
+        let data = Data::new(payload.clone(), mode);
+        
+        let bits = frame_bits(&settings, mode);
+        let expected = (len as u64 * 8).div_ceil(bits).max(1);
+        assert_eq!(frame_count(&data, &settings), expected);
+        
+        let frames: Vec<RgbImage> = frames(&data, &settings).collect();
+        assert_eq!(frames.len() as u64, expected);
+        
+        let mut stream = vec![0u8; (expected * bits).div_ceil(8) as usize];
+        for (index, frame) in frames.iter().enumerate() {
+            let header = frame_header::read(frame).expect("frame header");
+            assert_eq!((header.mode, header.frames, header.data_size), (mode, expected, len as u64));
+            let layout = FrameLayout::resolve(Some(&header), None, &LayoutHint::default(), 320, 240).unwrap();
+            
+            let read = decoder::read_frame(frame, &layout).unwrap();
+            assert!(read.intact(), "frame {} does not match its CRC", index);
+            let footer = read.footer.unwrap();
+            assert_eq!((footer.index as usize, footer.total as u64), (index, expected));
+            place_bits(&mut stream, index as u64 * bits, &read.bytes, bits);
+        }
+        
+        // The last frame is only partly filled, the rest of it is black
+        assert_eq!(&stream[..len], &payload[..]);
+        assert!(stream[len..].iter().all(|&byte| byte == 0));
+    }
+    
+    #[test]
+    fn binary_frames_read_back() {
+        round_trip(OutputMode::Binary, 1000);
+    }
+    
+    #[test]
+    fn gray_and_palette_frames_read_back() {
+        round_trip(OutputMode::Gray4, 4000);
+        round_trip(OutputMode::Palette16, 3000);
+    }
+    
+    #[test]
+    fn color_frames_read_back() {
+        round_trip(OutputMode::Color, 20000);
+    }
+    
+    #[test]
+    fn empty_payload_renders_one_frame() {
+        round_trip(OutputMode::Binary, 0);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,471 @@
+// Reed–Solomon forward error correction over GF(256).
+//
+// The rendered bytes are split into chunks of 255 - parity data bytes and each
+// chunk is followed by `parity` check bytes, forming a systematic RS(255, k)
+// codeword (the last one shortened). A codeword survives up to parity / 2
+// corrupted bytes anywhere in it.
+//
+// When the decoder knows which bytes it was unsure of (blocks close to the
+// threshold, frames that never arrived) a codeword that fails on its own is
+// tried again with its least reliable bytes marked as erasures. An erasure
+// costs one check byte instead of two, so e erasures and (parity - e) / 2
+// errors can still be fixed.
+//
+// Field: x^8 + x^4 + x^3 + x^2 + 1 (0x11d), generator alpha = 2, first
+// consecutive root alpha^0.
+
+use anyhow::{Result, anyhow};
+
+// Parity bytes per codeword when neither a preset nor --fec-parity picks one
+pub const DEFAULT_PARITY: u8 = 32;
+
+// Longest codeword the field allows
+pub const CODEWORD_LEN: usize = 255;
+
+const PRIMITIVE: u16 = 0x11d;
+
+// Bytes less reliable than this (out of 255) are candidates for erasure
+const DOUBTFUL: u8 = 64;
+
+struct Tables {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+const fn build_tables() -> Tables {
+    let mut exp = [0u8; 512];
+    let mut log = [0u8; 256];
+    
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= PRIMITIVE;
+        }
+        i += 1;
+    }
+    
+    // Doubled so products of two logs never need a modulo
+    while i < 512 {
+        exp[i] = exp[i - 255];
+        i += 1;
+    }
+    
+    Tables { exp, log }
+}
+
+static GF: Tables = build_tables();
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    GF.exp[GF.log[a as usize] as usize + GF.log[b as usize] as usize]
+}
+
+fn div(a: u8, b: u8) -> u8 {
+    if a == 0 {
+        return 0;
+    }
+    GF.exp[GF.log[a as usize] as usize + 255 - GF.log[b as usize] as usize]
+}
+
+// alpha^power, for any non-negative power
+fn alpha_pow(power: usize) -> u8 {
+    GF.exp[power % 255]
+}
+
+// Generator polynomial (x - a^0)(x - a^1)...(x - a^(parity-1)), highest degree first
+fn generator(parity: usize) -> Vec<u8> {
+    let mut g = vec![1u8];
+    for i in 0..parity {
+        let root = alpha_pow(i);
+        let mut next = vec![0u8; g.len() + 1];
+        for (j, &coef) in g.iter().enumerate() {
+            next[j] ^= coef;
+            next[j + 1] ^= mul(coef, root);
+        }
+        g = next;
+    }
+    g
+}
+
+// Evaluate a polynomial stored lowest degree first
+fn eval_low(poly: &[u8], x: u8) -> u8 {
+    poly.iter().rev().fold(0, |acc, &coef| mul(acc, x) ^ coef)
+}
+
+// Number of data bytes per codeword
+fn data_len(parity: u8) -> usize {
+    CODEWORD_LEN - parity as usize
+}
+
+// Length of `len` bytes once protected with `parity` check bytes per codeword
+pub fn encoded_len(len: u64, parity: u8) -> u64 {
+    if parity == 0 {
+        return len;
+    }
+    let k = data_len(parity) as u64;
+    let tail = len % k;
+    (len / k) * CODEWORD_LEN as u64 + if tail > 0 { tail + parity as u64 } else { 0 }
+}
+
+// Append `parity` check bytes to every chunk of `data`
+pub fn encode(data: &[u8], parity: u8) -> Vec<u8> {
+    if parity == 0 {
+        return data.to_vec();
+    }
+    
+    let nsym = parity as usize;
+    let g = generator(nsym);
+    let mut out = Vec::with_capacity(encoded_len(data.len() as u64, parity) as usize);
+    
+    for chunk in data.chunks(data_len(parity)) {
+        // Remainder of chunk * x^nsym divided by the generator
+        let mut remainder = vec![0u8; nsym];
+        for &byte in chunk {
+            let feedback = byte ^ remainder[0];
+            remainder.rotate_left(1);
+            remainder[nsym - 1] = 0;
+            if feedback != 0 {
+                for (r, &coef) in remainder.iter_mut().zip(&g[1..]) {
+                    *r ^= mul(feedback, coef);
+                }
+            }
+        }
+        
+        out.extend_from_slice(chunk);
+        out.extend_from_slice(&remainder);
+    }
+    
+    out
+}
+
+// What the decoder had to do to recover the data
+#[derive(Debug, Default, Clone)]
+pub struct FecReport {
+    pub codewords: usize,
+    // Codewords that needed at least one correction
+    pub repaired_codewords: usize,
+    // Bytes that were wrong and have been fixed
+    pub corrected_symbols: usize,
+    // Codewords that were only fixed by erasing their doubtful bytes
+    pub erased_codewords: usize,
+    // Codewords with more errors than the parity can fix, left as received
+    pub failed_codewords: usize,
+}
+
+// Strip the check bytes from `bytes`, correcting whatever errors they allow.
+// `reliability`, if known, rates every byte of `bytes` from 0 to 255 and is
+// used to pick erasures for codewords that cannot be fixed otherwise.
+pub fn decode(bytes: &[u8], reliability: Option<&[u8]>, parity: u8) -> Result<(Vec<u8>, FecReport)> {
+    let mut report = FecReport::default();
+    if parity == 0 {
+        return Ok((bytes.to_vec(), report));
+    }
+    
+    let nsym = parity as usize;
+    let mut out = Vec::with_capacity(bytes.len());
+    
+    for (index, chunk) in bytes.chunks(CODEWORD_LEN).enumerate() {
+        if chunk.len() <= nsym {
+            return Err(anyhow!(
+                "Error-corrected data ends with a {} byte codeword, shorter than its {} check bytes",
+                chunk.len(), nsym
+            ));
+        }
+        
+        let mut codeword = chunk.to_vec();
+        report.codewords += 1;
+        let mut result = correct(&mut codeword, nsym, &[]);
+        
+        // Erase the doubtful bytes, least reliable first, in a few amounts:
+        // erasing a byte that was right wastes a check byte, leaving one that
+        // was wrong costs two. A quarter of the check bytes are always kept
+        // back, as with none left any guess would pass for a codeword.
+        let start = index * CODEWORD_LEN;
+        let doubtful = reliability
+            .and_then(|reliability| reliability.get(start..start + chunk.len()))
+            .filter(|_| result.is_none())
+            .map(|reliability| {
+                let mut doubtful: Vec<usize> = (0..chunk.len()).filter(|&i| reliability[i] < DOUBTFUL).collect();
+                doubtful.sort_by_key(|&i| reliability[i]);
+                doubtful
+            })
+            .unwrap_or_default();
+        let mut counts: Vec<usize> = [nsym * 3 / 4, nsym / 2, nsym / 4]
+            .into_iter()
+            .map(|count| count.min(doubtful.len()))
+            .filter(|&count| count > 0)
+            .collect();
+        counts.dedup();
+        for count in counts {
+            if result.is_some() {
+                break;
+            }
+            let mut attempt = chunk.to_vec();
+            if let Some(fixed) = correct(&mut attempt, nsym, &doubtful[..count]) {
+                report.erased_codewords += 1;
+                codeword = attempt;
+                result = Some(fixed);
+            }
+        }
+        
+        match result {
+            Some(0) => (),
+            Some(fixed) => {
+                report.repaired_codewords += 1;
+                report.corrected_symbols += fixed;
+            }
+            None => report.failed_codewords += 1,
+        }
+        
+        out.extend_from_slice(&codeword[..codeword.len() - nsym]);
+    }
+    
+    Ok((out, report))
+}
+
+// Syndromes S_j = r(a^j), lowest first
+fn syndromes(codeword: &[u8], nsym: usize) -> Vec<u8> {
+    (0..nsym)
+        .map(|j| {
+            let x = alpha_pow(j);
+            codeword.iter().fold(0, |acc, &byte| mul(acc, x) ^ byte)
+        })
+        .collect()
+}
+
+// Fix `codeword` in place, treating the bytes at `erasures` as unknown.
+// Returns how many bytes were corrected, or None if there are more errors
+// than the code can handle.
+fn correct(codeword: &mut [u8], nsym: usize, erasures: &[usize]) -> Option<usize> {
+    let synd = syndromes(codeword, nsym);
+    if synd.iter().all(|&s| s == 0) {
+        return Some(0);
+    }
+    if erasures.len() > nsym {
+        return None;
+    }
+    
+    // Erasure locator Gamma(x) = product of (1 + X x) over the erased bytes,
+    // where X = a^(n-1-i) for byte i (see the Chien search below)
+    let n = codeword.len();
+    let mut erasure_locator = vec![1u8];
+    for &i in erasures {
+        let x = alpha_pow(n - 1 - i);
+        let mut next = erasure_locator.clone();
+        next.push(0);
+        for (j, &coef) in erasure_locator.iter().enumerate() {
+            next[j + 1] ^= mul(coef, x);
+        }
+        erasure_locator = next;
+    }
+    
+    // Berlekamp–Massey: shortest LFSR generating the syndromes, which is the
+    // errata locator Lambda(x) (lowest degree first). Starting it from the
+    // erasure locator makes it find the errors on top of the erasures.
+    let known = erasures.len();
+    let mut locator = erasure_locator.clone();
+    let mut previous = erasure_locator;
+    let mut errors = known;
+    let mut shift = 1;
+    let mut last_discrepancy = 1u8;
+    
+    for n in known..nsym {
+        let mut discrepancy = synd[n];
+        for i in 1..locator.len().min(n + 1) {
+            discrepancy ^= mul(locator[i], synd[n - i]);
+        }
+        
+        if discrepancy == 0 {
+            shift += 1;
+            continue;
+        }
+        
+        let scale = div(discrepancy, last_discrepancy);
+        let mut updated = locator.clone();
+        if updated.len() < previous.len() + shift {
+            updated.resize(previous.len() + shift, 0);
+        }
+        for (i, &coef) in previous.iter().enumerate() {
+            updated[i + shift] ^= mul(scale, coef);
+        }
+        
+        if 2 * errors <= n + known {
+            errors = n + 1 + known - errors;
+            previous = std::mem::replace(&mut locator, updated);
+            last_discrepancy = discrepancy;
+            shift = 1;
+        } else {
+            locator = updated;
+            shift += 1;
+        }
+    }
+    
+    locator.truncate(errors + 1);
+    if errors * 2 - known > nsym {
+        return None;
+    }
+    
+    // Chien search: byte i holds the coefficient of x^(n-1-i), and an error
+    // there makes a^-(n-1-i) a root of the locator
+    let positions: Vec<usize> = (0..n)
+        .filter(|&i| eval_low(&locator, alpha_pow(255 - (n - 1 - i) % 255)) == 0)
+        .collect();
+    if positions.len() != errors {
+        return None;
+    }
+    
+    // Forney: Omega(x) = S(x) Lambda(x) mod x^nsym, and with the first root
+    // at a^0 the error value is X * Omega(X^-1) / Lambda'(X^-1)
+    let mut omega = vec![0u8; nsym];
+    for (i, &s) in synd.iter().enumerate() {
+        for (j, &l) in locator.iter().enumerate() {
+            if i + j < nsym {
+                omega[i + j] ^= mul(s, l);
+            }
+        }
+    }
+    
+    // Formal derivative: only odd powers survive in characteristic 2
+    let derivative: Vec<u8> = locator.iter()
+        .enumerate()
+        .skip(1)
+        .map(|(i, &coef)| if i % 2 == 1 { coef } else { 0 })
+        .collect();
+    
+    // Erased bytes that held the right value after all need no correction
+    let mut corrected = 0;
+    for &i in &positions {
+        let power = n - 1 - i;
+        let x = alpha_pow(power);
+        let x_inv = alpha_pow(255 - power % 255);
+        let denominator = eval_low(&derivative, x_inv);
+        if denominator == 0 {
+            return None;
+        }
+        let value = mul(x, div(eval_low(&omega, x_inv), denominator));
+        if value != 0 {
+            codeword[i] ^= value;
+            corrected += 1;
+        }
+    }
+    
+    // A result that still is not a codeword means we were past capacity
+    if syndromes(codeword, nsym).iter().any(|&s| s != 0) {
+        return None;
+    }
+    
+    Some(corrected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 37 + i / 255) as u8).collect()
+    }
+    
+    // Corrupt `count` bytes of every codeword, spread out over it
+    fn corrupt(bytes: &mut [u8], count: usize) {
+        for codeword in bytes.chunks_mut(CODEWORD_LEN) {
+            let step = codeword.len() / count;
+            for i in 0..count {
+                codeword[i * step] ^= 0x5a;
+            }
+        }
+    }
+    
+    #[test]
+    fn round_trip_without_errors() {
+        let data = data(1000);
+        let encoded = encode(&data, 32);
+        assert_eq!(encoded.len() as u64, encoded_len(1000, 32));
+        
+        let (decoded, report) = decode(&encoded, None, 32).unwrap();
+        assert_eq!(decoded, data);
+        assert_eq!(report.codewords, 5);
+        assert_eq!(report.corrected_symbols, 0);
+        
+        assert_eq!(encode(&data, 0), data);
+        assert_eq!(decode(&data, None, 0).unwrap().0, data);
+    }
+    
+    #[test]
+    fn corrects_up_to_half_the_parity() {
+        for parity in [8u8, 32, 64] {
+            // The last codeword is shortened
+            let data = data(600);
+            let mut encoded = encode(&data, parity);
+            corrupt(&mut encoded, parity as usize / 2);
+            
+            let (decoded, report) = decode(&encoded, None, parity).unwrap();
+            assert_eq!(decoded, data, "parity {}", parity);
+            assert_eq!(report.failed_codewords, 0);
+            assert_eq!(report.repaired_codewords, report.codewords);
+            assert_eq!(report.corrected_symbols, report.codewords * parity as usize / 2);
+        }
+    }
+    
+    #[test]
+    fn rejects_too_many_errors() {
+        let data = data(223);
+        let mut encoded = encode(&data, 32);
+        corrupt(&mut encoded, 17);
+        
+        let (_, report) = decode(&encoded, None, 32).unwrap();
+        assert_eq!(report.failed_codewords, 1);
+        assert!(decode(&encoded[..20], None, 32).is_err());
+    }
+    
+    #[test]
+    fn erasures_stretch_the_parity() {
+        let data = data(223);
+        let encoded = encode(&data, 32);
+        
+        // 20 wrong bytes are more than 32 check bytes can find, but not more
+        // than they can fill in once the decoder knows where they are
+        let mut damaged = encoded.clone();
+        let mut reliability = vec![255u8; damaged.len()];
+        for i in (0..200).step_by(10) {
+            damaged[i] ^= 0xa5;
+            reliability[i] = 0;
+        }
+        assert_eq!(decode(&damaged, None, 32).unwrap().1.failed_codewords, 1);
+        
+        let (decoded, report) = decode(&damaged, Some(&reliability), 32).unwrap();
+        assert_eq!(decoded, data);
+        assert_eq!(report.erased_codewords, 1);
+        assert_eq!(report.failed_codewords, 0);
+        assert_eq!(report.corrected_symbols, 20);
+    }
+    
+    #[test]
+    fn corrects_errors_and_erasures_together() {
+        let encoded = encode(&data(223), 32);
+        // 2 x errors + erasures may use up all 32 check bytes
+        for (errors, erasures) in [(16, 0), (11, 10), (6, 20), (0, 32)] {
+            let mut codeword = encoded.clone();
+            let erased: Vec<usize> = (0..erasures).map(|i| i * 7).collect();
+            for &i in &erased {
+                codeword[i] = 0;
+            }
+            for i in 0..errors {
+                codeword[3 + i * 7] ^= 0x33;
+            }
+            let wrong = codeword.iter().zip(&encoded).filter(|(a, b)| a != b).count();
+            assert_eq!(correct(&mut codeword, 32, &erased), Some(wrong), "{} errors, {} erasures", errors, erasures);
+            assert_eq!(codeword, encoded);
+        }
+        
+        let mut codeword = encoded.clone();
+        codeword[0] ^= 1;
+        let erased: Vec<usize> = (0..33).collect();
+        assert_eq!(correct(&mut codeword, 32, &erased), None);
+    }
+}
\ No newline at end of file
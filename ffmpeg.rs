@@ -0,0 +1,264 @@
+// Encoding and decoding through external ffmpeg/ffprobe processes.
+//
+// Rendered frames are piped to ffmpeg's stdin as raw RGB24 and ffmpeg does
+// the actual compression, either H.264 in MP4 or lossless FFV1 in MKV.
+// Reading goes the other way: ffmpeg decodes any video to raw RGB24 on stdout.
+
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use image::RgbImage;
+use anyhow::{Result, Context, anyhow};
+use crate::etcher::{self, VideoMetadata};
+use crate::settings::{Data, Settings};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FfmpegCodec {
+    // H.264 in an MP4 container
+    H264,
+    // Lossless FFV1 in a Matroska container
+    Ffv1,
+}
+
+// Rate control handed to the encoder
+#[derive(Debug, Clone)]
+pub struct Quality {
+    pub crf: u8,
+    pub preset: &'static str,
+}
+
+impl Default for Quality {
+    fn default() -> Self {
+        Quality {
+            crf: 18,
+            preset: "slow",
+        }
+    }
+}
+
+// Make sure ffmpeg can be started, with a helpful error when it cannot
+pub fn check_ffmpeg() -> Result<()> {
+    check_tool("ffmpeg")
+}
+
+fn check_tool(tool: &str) -> Result<()> {
+    match Command::new(tool).arg("-version").output() {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(_) => Err(anyhow!("{} is installed but failed to run", tool)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Err(anyhow!(
+            "{} was not found in PATH. Install ffmpeg (e.g. sudo apt install ffmpeg) or use the y4m / avi formats",
+            tool
+        )),
+        Err(e) => Err(anyhow!("Failed to run {}: {}", tool, e)),
+    }
+}
+
+fn codec_args(codec: FfmpegCodec, quality: &Quality) -> Vec<String> {
+    let crf = quality.crf.to_string();
+    let args = match codec {
+        FfmpegCodec::H264 => vec![
+            "-c:v", "libx264",
+            "-preset", quality.preset,
+            "-crf", &crf,
+            "-pix_fmt", "yuv420p",
+            "-movflags", "+faststart",
+        ],
+        FfmpegCodec::Ffv1 => vec!["-c:v", "ffv1", "-level", "3", "-pix_fmt", "bgr0"],
+    };
+    args.into_iter().map(String::from).collect()
+}
+
+// Stream every rendered frame into ffmpeg and let it write the video
+pub fn write_video<P: AsRef<Path>>(path: P, data: &Data, settings: &Settings, codec: FfmpegCodec, quality: &Quality) -> Result<()> {
+    etcher::check_layout(settings, data.out_mode)?;
+    check_ffmpeg()?;
+    
+    let metadata = VideoMetadata::new(data, settings);
+    let size = format!("{}x{}", settings.width, settings.height);
+    
+    let mut child = Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-y"])
+        .args(["-f", "rawvideo", "-pix_fmt", "rgb24", "-s", &size])
+        .args(["-r", &settings.fps.to_string(), "-i", "-"])
+        .args(codec_args(codec, quality))
+        .args(["-threads", &settings.threads.to_string()])
+        // The layout travels in the container's comment tag
+        .args(["-metadata", &format!("comment={}", serde_json::to_string(&metadata)?)])
+        .arg(path.as_ref())
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("Failed to start ffmpeg")?;
+    
+    let mut stdin = child.stdin.take().ok_or_else(|| anyhow!("Failed to open ffmpeg stdin"))?;
+    
+    let frames = etcher::frames(data, settings);
+    let pb = etcher::frame_progress(frames.len());
+    let mut write_result = Ok(());
+    
+    for frame in frames {
+        if let Err(e) = stdin.write_all(frame.as_raw()) {
+            write_result = Err(e);
+            break;
+        }
+        pb.inc(1);
+    }
+    
+    pb.finish_and_clear();
+    
+    // Closing stdin tells ffmpeg the stream is over
+    drop(stdin);
+    let status = child.wait().context("Failed to wait for ffmpeg")?;
+    
+    if !status.success() {
+        return Err(anyhow!("ffmpeg failed with {}", status));
+    }
+    write_result.context("Failed to pipe frames to ffmpeg")?;
+    
+    println!("Video written to: {}", path.as_ref().display());
+    Ok(())
+}
+
+// Decodes any video ffmpeg understands into RGB frames
+pub struct FfmpegReader {
+    pub width: u32,
+    pub height: u32,
+    comment: Option<String>,
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl FfmpegReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<FfmpegReader> {
+        check_tool("ffprobe")?;
+        check_ffmpeg()?;
+        
+        let probe = Command::new("ffprobe")
+            .args(["-v", "error", "-select_streams", "v:0"])
+            .args(["-show_entries", "stream=width,height:format_tags=comment", "-of", "json"])
+            .arg(path.as_ref())
+            .output()
+            .context("Failed to run ffprobe")?;
+        
+        if !probe.status.success() {
+            let error = String::from_utf8_lossy(&probe.stderr);
+            return Err(anyhow!("ffprobe could not read {}: {}", path.as_ref().display(), error.trim()));
+        }
+        
+        let info: serde_json::Value = serde_json::from_slice(&probe.stdout)
+            .context("Invalid ffprobe output")?;
+        let stream = &info["streams"][0];
+        let width = stream["width"].as_u64().unwrap_or(0) as u32;
+        let height = stream["height"].as_u64().unwrap_or(0) as u32;
+        if width == 0 || height == 0 {
+            return Err(anyhow!("No video stream found in {}", path.as_ref().display()));
+        }
+        let comment = info["format"]["tags"]["comment"].as_str().map(str::to_string);
+        
+        let mut child = Command::new("ffmpeg")
+            .args(["-hide_banner", "-loglevel", "error", "-i"])
+            .arg(path.as_ref())
+            .args(["-map", "0:v:0", "-f", "rawvideo", "-pix_fmt", "rgb24", "-"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("Failed to start ffmpeg")?;
+        let stdout = child.stdout.take().ok_or_else(|| anyhow!("Failed to open ffmpeg stdout"))?;
+        
+        Ok(FfmpegReader {
+            width,
+            height,
+            comment,
+            child,
+            stdout,
+        })
+    }
+    
+    // Layout written by `write_video`, if the container kept its comment tag
+    pub fn metadata(&self) -> Option<VideoMetadata> {
+        serde_json::from_str(self.comment.as_ref()?).ok()
+    }
+    
+    // Read the next frame, or None once ffmpeg has finished
+    pub fn next_frame(&mut self) -> Result<Option<RgbImage>> {
+        let mut buf = vec![0u8; self.width as usize * self.height as usize * 3];
+        let mut filled = 0;
+        while filled < buf.len() {
+            let read = self.stdout.read(&mut buf[filled..])?;
+            if read == 0 {
+                break;
+            }
+            filled += read;
+        }
+        
+        if filled == 0 {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(anyhow!("ffmpeg failed with {}", status));
+            }
+            return Ok(None);
+        }
+        if filled < buf.len() {
+            return Err(anyhow!("ffmpeg output ends mid-frame"));
+        }
+        
+        RgbImage::from_raw(self.width, self.height, buf)
+            .map(Some)
+            .ok_or_else(|| anyhow!("Invalid frame from ffmpeg"))
+    }
+}
+
+impl Iterator for FfmpegReader {
+    type Item = Result<RgbImage>;
+    
+    fn next(&mut self) -> Option<Result<RgbImage>> {
+        self.next_frame().transpose()
+    }
+}
+
+// Stop ffmpeg when the reader is dropped before the end of the video, rather
+// than leaving it blocked on a full pipe
+impl Drop for FfmpegReader {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::OutputMode;
+    
+    // These need ffmpeg and ffprobe in PATH and are skipped without them
+    fn have_ffmpeg() -> bool {
+        let found = check_ffmpeg().is_ok() && check_tool("ffprobe").is_ok();
+        if !found {
+            eprintln!("ffmpeg or ffprobe not found, skipping");
+        }
+        found
+    }
+    
+    #[test]
+    fn ffv1_round_trip() {
+        if !have_ffmpeg() {
+            return;
+        }
+        let settings = Settings::new(2, 1, 10, 256, 144);
+        let payload: Vec<u8> = (0..6000u32).map(|i| (i * 37 + i / 5) as u8).collect();
+        let data = Data::new(payload, OutputMode::Color);
+        let path = std::env::temp_dir().join(format!("sdh_ffv1_{}.mkv", std::process::id()));
+        write_video(&path, &data, &settings, FfmpegCodec::Ffv1, &Quality::default()).unwrap();
+        
+        let reader = FfmpegReader::open(&path).unwrap();
+        assert_eq!(reader.metadata().unwrap().data_size, 6000);
+        let frames: Vec<RgbImage> = reader.map(Result::unwrap).collect();
+        let expected: Vec<RgbImage> = etcher::frames(&data, &settings).collect();
+        assert_eq!(frames, expected);
+        
+        // Dropping a reader part way through stops its ffmpeg
+        let mut reader = FfmpegReader::open(&path).unwrap();
+        reader.next_frame().unwrap().unwrap();
+        drop(reader);
+        
+        std::fs::remove_file(&path).unwrap();
+    }
+}
\ No newline at end of file
@@ -0,0 +1,555 @@
+// Corner markers for re-captured video: phone recordings of a screen, crops,
+// letterboxed or rescaled re-uploads.
+//
+// Every frame carries four QR-style finder patterns at the corners of its
+// data area: a dark 7x7 module square ring, a light ring and a dark 3x3
+// centre, with a one module light border. Read across the centre in any
+// direction they give runs of 1:1:3:1:1, whatever the scale, so they can be
+// found in a picture of the frame. The blocks they cover carry no data.
+//
+// When the header strip cannot be read where it should be, the decoder looks
+// for the markers, fits the homography that takes the frame as rendered onto
+// the picture, and resamples the picture back into the rendered geometry
+// before anything else reads it.
+//
+// Frames that were only rescaled (a platform serving a 720p upload at 480p
+// or 1080p) need none of that: the header strip still reads once the frame is
+// scaled back to the size it names, and the blocks are read from the frame as
+// scaled back. That also works for videos from before the markers. Scaling
+// back cannot restore detail the downscale averaged away, so blocks need to
+// stay at least a couple of pixels wide at the smaller size: block size 4 or
+// more survives 720p served at 480p, block size 2 does not.
+
+use image::{Rgb, RgbImage};
+use crate::decoder;
+use crate::frame_footer;
+use crate::frame_header::{self, FrameHeader};
+
+// Modules across a marker, light border included
+const MODULES: u32 = 9;
+
+// Sizes tried when a picture of a frame does not say what it was rendered at
+const COMMON_SIZES: [(u32, u32); 8] = [
+    (1280, 720), (854, 480), (640, 360), (426, 240), (256, 144), (1920, 1080), (2560, 1440), (3840, 2160),
+];
+
+// Where the markers go in a frame as rendered
+#[derive(Debug, Clone, Copy)]
+pub struct Markers {
+    module: u32,
+    // Top left pixel of the top left, top right, bottom left and bottom right markers
+    corners: [(u32, u32); 4],
+}
+
+impl Markers {
+    // Markers for a frame `width` wide whose data area runs from pixel row
+    // `top` to `bottom`
+    pub fn new(width: u32, top: u32, bottom: u32) -> Markers {
+        let module = (frame_header::cell_size(width) / 2).max(1);
+        let size = module * MODULES;
+        let (right, low) = (width.saturating_sub(size), bottom.saturating_sub(size));
+        Markers {
+            module,
+            corners: [(0, top), (right, top), (0, low), (right, low)],
+        }
+    }
+    
+    // Markers of a `width` x `height` frame with the header and footer strips
+    pub fn for_frame(width: u32, height: u32) -> Markers {
+        Markers::new(width, frame_header::height(width), height.saturating_sub(frame_footer::height(width)))
+    }
+    
+    pub fn size(&self) -> u32 {
+        self.module * MODULES
+    }
+    
+    // Whether the `w` x `h` pixel rectangle at (`x`, `y`) overlaps a marker
+    pub fn covers(&self, x: u32, y: u32, w: u32, h: u32) -> bool {
+        let size = self.size();
+        self.corners.iter().any(|&(left, top)| x < left + size && left < x + w && y < top + size && top < y + h)
+    }
+    
+    // Whether the markers fit in the data area without running into each other
+    pub fn fit(&self, width: u32, top: u32, bottom: u32) -> bool {
+        let size = self.size();
+        width >= size * 2 && bottom.saturating_sub(top) >= size * 2
+    }
+    
+    // Marker centres, in the same order as the corners
+    fn centres(&self) -> [(f64, f64); 4] {
+        let half = self.size() as f64 / 2.0;
+        self.corners.map(|(left, top)| (left as f64 + half, top as f64 + half))
+    }
+    
+    pub fn render(&self, img: &mut RgbImage) {
+        for &(left, top) in &self.corners {
+            for y in 0..self.size() {
+                for x in 0..self.size() {
+                    // Distance in modules from the centre of the marker: 0-1 centre,
+                    // 2 light ring, 3 dark ring, 4 light border
+                    let ring = (x / self.module).abs_diff(MODULES / 2).max((y / self.module).abs_diff(MODULES / 2));
+                    let dark = ring <= 1 || ring == 3;
+                    let color = if dark { Rgb([0, 0, 0]) } else { Rgb([255, 255, 255]) };
+                    img.put_pixel(left + x, top + y, color);
+                }
+            }
+        }
+    }
+}
+
+// Projective transform between two planes
+#[derive(Debug, Clone, Copy)]
+pub struct Homography([f64; 9]);
+
+impl Homography {
+    // The transform taking each of `from` onto the matching point of `to`,
+    // None if the points are degenerate
+    pub fn from_points(from: &[(f64, f64); 4], to: &[(f64, f64); 4]) -> Option<Homography> {
+        // Eight equations in the first eight entries, the last one fixed at 1
+        let mut rows = [[0.0f64; 9]; 8];
+        for (i, (&(x, y), &(u, v))) in from.iter().zip(to).enumerate() {
+            rows[i * 2] = [x, y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y, u];
+            rows[i * 2 + 1] = [0.0, 0.0, 0.0, x, y, 1.0, -v * x, -v * y, v];
+        }
+        
+        // Gaussian elimination with partial pivoting
+        for column in 0..8 {
+            let pivot = (column..8).max_by(|&a, &b| rows[a][column].abs().total_cmp(&rows[b][column].abs()))?;
+            if rows[pivot][column].abs() < 1e-9 {
+                return None;
+            }
+            rows.swap(column, pivot);
+            let pivot_row = rows[column];
+            for (i, row) in rows.iter_mut().enumerate() {
+                if i != column {
+                    let factor = row[column] / pivot_row[column];
+                    for (value, pivot_value) in row.iter_mut().zip(pivot_row).skip(column) {
+                        *value -= factor * pivot_value;
+                    }
+                }
+            }
+        }
+        
+        let mut h = [1.0; 9];
+        for (i, row) in rows.iter().enumerate() {
+            h[i] = row[8] / row[i];
+        }
+        Some(Homography(h))
+    }
+    
+    // Plain rescaling from a frame of size `from` to one of size `to`
+    pub fn scale(from: (u32, u32), to: (u32, u32)) -> Homography {
+        let (sx, sy) = (to.0 as f64 / from.0 as f64, to.1 as f64 / from.1 as f64);
+        Homography([sx, 0.0, 0.0, 0.0, sy, 0.0, 0.0, 0.0, 1.0])
+    }
+    
+    pub fn map(&self, x: f64, y: f64) -> (f64, f64) {
+        let h = &self.0;
+        let w = h[6] * x + h[7] * y + h[8];
+        ((h[0] * x + h[1] * y + h[2]) / w, (h[3] * x + h[4] * y + h[5]) / w)
+    }
+}
+
+// Bilinear sample of `frame` at pixel coordinates (`x`, `y`), clamped to the edges
+fn sample(frame: &RgbImage, x: f64, y: f64) -> Rgb<u8> {
+    let max_x = (frame.width() - 1) as f64;
+    let max_y = (frame.height() - 1) as f64;
+    let (x, y) = (x.clamp(0.0, max_x), y.clamp(0.0, max_y));
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as u32, y0 as u32);
+    let (x1, y1) = ((x0 + 1).min(max_x as u32), (y0 + 1).min(max_y as u32));
+    
+    let [a, b, c, d] = [(x0, y0), (x1, y0), (x0, y1), (x1, y1)].map(|(x, y)| frame.get_pixel(x, y).0);
+    Rgb(std::array::from_fn(|i| {
+        let top = a[i] as f64 * (1.0 - fx) + b[i] as f64 * fx;
+        let bottom = c[i] as f64 * (1.0 - fx) + d[i] as f64 * fx;
+        (top * (1.0 - fy) + bottom * fy).round() as u8
+    }))
+}
+
+// Resample the part of `frame` that `homography` maps a `width` x `height`
+// frame onto
+pub fn warp(frame: &RgbImage, homography: &Homography, width: u32, height: u32) -> RgbImage {
+    RgbImage::from_fn(width, height, |x, y| {
+        let (u, v) = homography.map(x as f64 + 0.5, y as f64 + 0.5);
+        sample(frame, u - 0.5, v - 0.5)
+    })
+}
+
+// Luma of a frame with a light/dark cut-off halfway between its darkest and
+// lightest few percent
+struct Binarized {
+    width: u32,
+    height: u32,
+    dark: Vec<bool>,
+}
+
+impl Binarized {
+    fn new(frame: &RgbImage) -> Binarized {
+        let luma: Vec<u8> = frame.pixels().map(|&Rgb([r, g, b])| decoder::luma(r, g, b)).collect();
+        let mut histogram = [0usize; 256];
+        for &value in &luma {
+            histogram[value as usize] += 1;
+        }
+        let percentile = |fraction: f64| {
+            let target = (luma.len() as f64 * fraction) as usize;
+            let mut seen = 0;
+            histogram.iter().position(|&count| {
+                seen += count;
+                seen > target
+            }).unwrap_or(255)
+        };
+        let threshold = ((percentile(0.02) + percentile(0.98)) / 2) as u8;
+        
+        Binarized {
+            width: frame.width(),
+            height: frame.height(),
+            dark: luma.iter().map(|&value| value < threshold).collect(),
+        }
+    }
+    
+    fn dark(&self, x: i64, y: i64) -> Option<bool> {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            return None;
+        }
+        Some(self.dark[(y as u32 * self.width + x as u32) as usize])
+    }
+    
+    // The five runs through (`x`, `y`) along (`dx`, `dy`), centred on the dark
+    // run there, and the offset of that run's middle from (`x`, `y`)
+    fn runs(&self, x: i64, y: i64, dx: i64, dy: i64, limit: u32) -> Option<([u32; 5], f64)> {
+        if self.dark(x, y) != Some(true) {
+            return None;
+        }
+        let count = |sign: i64, skip: u32, dark: bool| {
+            let mut run = 0;
+            while run <= limit && self.dark(x + dx * sign * (skip + run) as i64, y + dy * sign * (skip + run) as i64) == Some(dark) {
+                run += 1;
+            }
+            run
+        };
+        
+        let (back, ahead) = (count(-1, 1, true), count(1, 1, true));
+        let centre = back + ahead + 1;
+        let light_back = count(-1, back + 1, false);
+        let light_ahead = count(1, ahead + 1, false);
+        let dark_back = count(-1, back + 1 + light_back, true);
+        let dark_ahead = count(1, ahead + 1 + light_ahead, true);
+        
+        let runs = [dark_back, light_back, centre, light_ahead, dark_ahead];
+        if runs.iter().any(|&run| run == 0 || run > limit) {
+            return None;
+        }
+        Some((runs, (ahead as f64 - back as f64) / 2.0))
+    }
+}
+
+// Whether five runs look like 1:1:3:1:1, and the module size if so
+fn finder_ratio(runs: &[u32; 5]) -> Option<f64> {
+    let total: u32 = runs.iter().sum();
+    if total < 7 {
+        return None;
+    }
+    let module = total as f64 / 7.0;
+    let tolerance = module * 0.5;
+    let fits = runs.iter().zip([1.0, 1.0, 3.0, 1.0, 1.0]).all(|(&run, modules)| (run as f64 - module * modules).abs() < tolerance * modules);
+    fits.then_some(module)
+}
+
+// A group of nearby sightings of one marker
+#[derive(Debug, Clone, Copy)]
+struct Sighting {
+    x: f64,
+    y: f64,
+    module: f64,
+    count: u32,
+}
+
+// Find the centres of the four markers in a picture of a frame: top left, top
+// right, bottom left, bottom right. None unless all four are seen.
+pub fn locate(frame: &RgbImage) -> Option<[(f64, f64); 4]> {
+    let image = Binarized::new(frame);
+    let limit = frame.width().max(frame.height()) / 8;
+    let mut sightings: Vec<Sighting> = Vec::new();
+    
+    for y in 0..image.height as i64 {
+        // Run lengths along the row, starting with whatever the first pixel is
+        let mut runs: Vec<(u32, u32, bool)> = Vec::new(); // start, length, dark
+        for x in 0..image.width {
+            let dark = image.dark(x as i64, y).unwrap();
+            match runs.last_mut() {
+                Some(run) if run.2 == dark => run.1 += 1,
+                _ => runs.push((x, 1, dark)),
+            }
+        }
+        
+        for window in runs.windows(5) {
+            if !window[0].2 {
+                continue;
+            }
+            let lengths = [window[0].1, window[1].1, window[2].1, window[3].1, window[4].1];
+            let Some(module) = finder_ratio(&lengths) else {
+                continue;
+            };
+            
+            // Check down the column through the middle, then along the row
+            // again through the middle of that
+            let x = window[2].0 as i64 + window[2].1 as i64 / 2;
+            let Some((vertical, dy)) = image.runs(x, y, 0, 1, limit) else {
+                continue;
+            };
+            let Some(vertical_module) = finder_ratio(&vertical) else {
+                continue;
+            };
+            if vertical_module > module * 2.0 || module > vertical_module * 2.0 {
+                continue;
+            }
+            let centre_y = y as f64 + dy;
+            let Some((horizontal, dx)) = image.runs(x, centre_y as i64, 1, 0, limit) else {
+                continue;
+            };
+            if finder_ratio(&horizontal).is_none() {
+                continue;
+            }
+            
+            let (cx, cy) = (x as f64 + dx + 0.5, centre_y + 0.5);
+            let module = (module + vertical_module) / 2.0;
+            match sightings.iter_mut().find(|s| (s.x - cx).abs() < s.module * 2.0 && (s.y - cy).abs() < s.module * 2.0) {
+                Some(s) => {
+                    let n = s.count as f64;
+                    s.x = (s.x * n + cx) / (n + 1.0);
+                    s.y = (s.y * n + cy) / (n + 1.0);
+                    s.module = (s.module * n + module) / (n + 1.0);
+                    s.count += 1;
+                }
+                None => sightings.push(Sighting { x: cx, y: cy, module, count: 1 }),
+            }
+        }
+    }
+    
+    // The markers are seen on every row through their centres; stray matches
+    // in the data are seen a few times at most
+    sightings.sort_by_key(|s| std::cmp::Reverse(s.count));
+    let found: Vec<Sighting> = sightings.into_iter().take(4).filter(|s| s.count >= 2).collect();
+    if found.len() < 4 {
+        return None;
+    }
+    let (smallest, largest) = found.iter().fold((f64::MAX, 0.0f64), |(lo, hi), s| (lo.min(s.module), hi.max(s.module)));
+    if largest > smallest * 3.0 {
+        return None;
+    }
+    
+    let pick = |key: fn(&Sighting) -> f64| {
+        let s = found.iter().min_by(|a, b| key(a).total_cmp(&key(b))).unwrap();
+        (s.x, s.y)
+    };
+    let corners = [
+        pick(|s| s.x + s.y),
+        pick(|s| s.y - s.x),
+        pick(|s| s.x - s.y),
+        pick(|s| -(s.x + s.y)),
+    ];
+    for (i, a) in corners.iter().enumerate() {
+        if corners[i + 1..].contains(a) {
+            return None;
+        }
+    }
+    Some(corners)
+}
+
+// The homography taking a `width` x `height` frame as rendered onto a picture
+// of it whose markers are at `found`
+fn fit(found: &[(f64, f64); 4], width: u32, height: u32) -> Option<Homography> {
+    Homography::from_points(&Markers::for_frame(width, height).centres(), found)
+}
+
+// Read the header strip of `frame` through the homography `place` gives for
+// each size the frame might have been rendered at. That is not known yet, so
+// `sizes` and a few common ones are tried until the header comes out intact
+// (its CRC says when).
+fn read_header<F>(frame: &RgbImage, sizes: &[(u32, u32)], place: F) -> Option<FrameHeader>
+where
+    F: Fn(u32, u32) -> Option<Homography>,
+{
+    let read = |width: u32, height: u32| {
+        let homography = place(width, height)?;
+        frame_header::read(&warp(frame, &homography, width, frame_header::height(width)))
+    };
+    
+    for &(width, height) in sizes.iter().chain(&COMMON_SIZES) {
+        let Some(header) = read(width, height) else {
+            continue;
+        };
+        // A size close to the real one can be enough to read the header; make
+        // sure it also reads at the size it gives
+        if (header.width, header.height) == (width, height) {
+            return Some(header);
+        }
+        let size = (header.width, header.height);
+        if let Some(header) = read(size.0, size.1).filter(|h| (h.width, h.height) == size) {
+            return Some(header);
+        }
+    }
+    None
+}
+
+// Read the header strip out of a frame that was rescaled
+pub fn find_scaled_header(frame: &RgbImage, sizes: &[(u32, u32)]) -> Option<FrameHeader> {
+    read_header(frame, sizes, |width, height| Some(Homography::scale((width, height), frame.dimensions())))
+}
+
+// Read the header strip out of a picture of a frame using its markers
+pub fn find_header(frame: &RgbImage, sizes: &[(u32, u32)]) -> Option<FrameHeader> {
+    let found = locate(frame)?;
+    read_header(frame, sizes, |width, height| fit(&found, width, height))
+}
+
+// Puts every frame back into the geometry it was rendered at, using its
+// markers (or the last frame's when they cannot be found), or by rescaling it
+// when that is all that happened to it
+pub struct Rectified<I> {
+    frames: I,
+    width: u32,
+    height: u32,
+    // Whether the frames carry markers to look for
+    markers: bool,
+    last: Option<Homography>,
+    // Whether the way frames are put back has been reported yet
+    reported_scale: bool,
+    reported_markers: bool,
+}
+
+impl<I> Rectified<I> {
+    pub fn new(frames: I, width: u32, height: u32, markers: bool) -> Rectified<I> {
+        Rectified {
+            frames,
+            width,
+            height,
+            markers,
+            last: None,
+            reported_scale: false,
+            reported_markers: false,
+        }
+    }
+}
+
+impl<I> Iterator for Rectified<I>
+where
+    I: Iterator<Item = anyhow::Result<RgbImage>>,
+{
+    type Item = anyhow::Result<RgbImage>;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        let frame = match self.frames.next()? {
+            Ok(frame) => frame,
+            Err(e) => return Some(Err(e)),
+        };
+        
+        // Frames that come back the way they were rendered are left alone
+        if frame.dimensions() == (self.width, self.height) && frame_header::read(&frame).is_some() {
+            return Some(Ok(frame));
+        }
+        
+        // A frame whose header and footer both read once it is scaled back
+        // was only rescaled; plain scaling puts it back more exactly than the
+        // markers can
+        let scale = Homography::scale((self.width, self.height), frame.dimensions());
+        let scaled = warp(&frame, &scale, self.width, self.height);
+        let rescaled = frame_header::read(&scaled).is_some() && frame_footer::read(&scaled).is_some();
+        
+        let located = (self.markers && !rescaled)
+            .then(|| locate(&frame).and_then(|found| fit(&found, self.width, self.height)))
+            .flatten();
+        if let Some(homography) = located.or(self.last.filter(|_| !rescaled)) {
+            self.last = Some(homography);
+            if !self.reported_markers {
+                self.reported_markers = true;
+                println!(
+                    "Frames are {}x{} instead of {}x{}, or skewed; resampling them using the corner markers",
+                    frame.width(), frame.height(), self.width, self.height
+                );
+            }
+            return Some(Ok(warp(&frame, &homography, self.width, self.height)));
+        }
+        
+        if !self.reported_scale && frame.dimensions() != (self.width, self.height) {
+            self.reported_scale = true;
+            println!(
+                "Frames are {}x{} instead of {}x{}; scaling them back to {}x{} before reading the blocks",
+                frame.width(), frame.height(), self.width, self.height, self.width, self.height
+            );
+        }
+        Some(Ok(scaled))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::imageops::{self, FilterType};
+    use crate::decoder::{FrameLayout, LayoutHint};
+    use crate::etcher;
+    use crate::settings::{Data, OutputMode, Settings};
+    
+    // A 720p frame served back at 480p
+    #[test]
+    fn downscaled_frame_decodes() {
+        let settings = Settings::new(4, 1, 10, 1280, 720);
+        let frame_bytes = (etcher::frame_bits(&settings, OutputMode::Binary) / 8) as usize;
+        let payload: Vec<u8> = (0..frame_bytes).map(|i| (i * 151 + 7) as u8).collect();
+        let data = Data::new(payload.clone(), OutputMode::Binary);
+        
+        let frame = etcher::render_frame(&data, &settings, 0);
+        let small = imageops::resize(&frame, 854, 480, FilterType::Triangle);
+        assert!(frame_header::read(&small).is_none());
+        
+        let header = find_scaled_header(&small, &[(854, 480)]).expect("header of the rescaled frame");
+        assert_eq!((header.width, header.height), (1280, 720));
+        let layout = FrameLayout::resolve(Some(&header), None, &LayoutHint::default(), 1280, 720).unwrap();
+        
+        let mut rectified = Rectified::new(std::iter::once(Ok(small)), 1280, 720, layout.markers);
+        let restored = rectified.next().unwrap().unwrap();
+        assert_eq!(restored.dimensions(), (1280, 720));
+        
+        let read = decoder::read_frame(&restored, &layout).unwrap();
+        assert_eq!(&read.bytes[..frame_bytes], &payload[..]);
+        assert!(read.intact());
+    }
+    
+    // A photo of a 720p frame: turned and in perspective, on a black
+    // background that shows as bars around it, and cropped from a larger shot
+    #[test]
+    fn warped_frame_decodes() {
+        let settings = Settings::new(4, 1, 10, 1280, 720);
+        let frame_bytes = (etcher::frame_bits(&settings, OutputMode::Binary) / 8) as usize;
+        let payload: Vec<u8> = (0..frame_bytes).map(|i| (i * 97 + i / 13) as u8).collect();
+        let data = Data::new(payload.clone(), OutputMode::Binary);
+        let frame = etcher::render_frame(&data, &settings, 0);
+        
+        // Where the corners of the frame land in the shot
+        let corners = [(0.0, 0.0), (1280.0, 0.0), (0.0, 720.0), (1280.0, 720.0)];
+        let placed = [(150.0, 120.0), (1430.0, 95.0), (135.0, 860.0), (1455.0, 880.0)];
+        let to_frame = Homography::from_points(&placed, &corners).unwrap();
+        let shot = RgbImage::from_fn(1600, 1000, |x, y| {
+            let (u, v) = to_frame.map(x as f64 + 0.5, y as f64 + 0.5);
+            if u < 0.0 || v < 0.0 || u >= 1280.0 || v >= 720.0 {
+                Rgb([0, 0, 0])
+            } else {
+                sample(&frame, u - 0.5, v - 0.5)
+            }
+        });
+        let photo = imageops::crop_imm(&shot, 40, 30, 1500, 900).to_image();
+        assert!(frame_header::read(&photo).is_none());
+        
+        let header = find_header(&photo, &[]).expect("header of the photo");
+        assert_eq!((header.width, header.height), (1280, 720));
+        let layout = FrameLayout::resolve(Some(&header), None, &LayoutHint::default(), 1280, 720).unwrap();
+        
+        let mut rectified = Rectified::new(std::iter::once(Ok(photo)), 1280, 720, layout.markers);
+        let restored = rectified.next().unwrap().unwrap();
+        let read = decoder::read_frame(&restored, &layout).unwrap();
+        assert_eq!(&read.bytes[..frame_bytes], &payload[..]);
+        assert!(read.intact());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,259 @@
+// Fountain-coded frames (RaptorQ, RFC 6330) for videos that may lose, repeat
+// or reorder frames when a platform re-times them.
+//
+// The packed container is cut into source symbols and topped up with repair
+// symbols, and every frame carries a self-contained packet:
+//
+//   frame id (u32 LE) | transmission info (12) | symbol count (u16 LE)
+//   | symbols (payload id (4) + data) | CRC32 of everything before it (u32 LE)
+//
+// Reed–Solomon codewords protect the packet inside its own frame. Frames that
+// still fail their CRC are dropped, and any big enough set of intact frames
+// rebuilds the container, whatever their order or duplication.
+
+use std::collections::HashSet;
+use anyhow::{Result, anyhow};
+use image::RgbImage;
+use raptorq::{Decoder, Encoder, EncodingPacket, ObjectTransmissionInformation};
+use crate::decoder::{self, FrameLayout};
+use crate::etcher;
+use crate::fec;
+use crate::settings::{OutputMode, Settings};
+
+// Repair symbols, in percent of the source symbols, when --fountain has no value
+pub const DEFAULT_OVERHEAD: u32 = 25;
+
+// Frame id, transmission info, symbol count and CRC
+const PACKET_OVERHEAD: usize = 4 + 12 + 2 + 4;
+// Payload id in front of every symbol
+const SYMBOL_ID_LEN: usize = 4;
+// Largest symbol RaptorQ allows, kept to its 8 byte alignment
+const MAX_SYMBOL: usize = 65528;
+
+// Bytes one frame of `bits` stream bits can carry. Each frame starts on a
+// block boundary; frames whose bit count is not a multiple of 8 leave enough
+// spare bits that the stream never spills into an extra frame.
+fn frame_capacity(bits: u64) -> usize {
+    let capacity = if bits.is_multiple_of(8) { bits / 8 } else { bits.saturating_sub(7) / 8 };
+    capacity as usize
+}
+
+// Packet bytes left in `capacity` after the Reed–Solomon check bytes
+fn packet_len(capacity: usize, parity: u8) -> usize {
+    let codewords = capacity / fec::CODEWORD_LEN;
+    let tail = capacity % fec::CODEWORD_LEN;
+    codewords * (fec::CODEWORD_LEN - parity as usize) + tail.saturating_sub(parity as usize)
+}
+
+// Symbols per frame and symbol size for a packet of `packet_len` bytes
+fn symbol_layout(packet_len: usize) -> Result<(usize, u16)> {
+    let area = packet_len.saturating_sub(PACKET_OVERHEAD);
+    let per_frame = area.div_ceil(SYMBOL_ID_LEN + MAX_SYMBOL).max(1);
+    let symbol_size = (area / per_frame).saturating_sub(SYMBOL_ID_LEN) & !7;
+    if symbol_size == 0 {
+        return Err(anyhow!("Frames are too small to carry fountain-coded symbols"));
+    }
+    Ok((per_frame, symbol_size as u16))
+}
+
+// Repair symbols for a source block of `symbols` symbols
+fn repair_symbols(symbols: u64, overhead: u32) -> u64 {
+    (symbols * overhead as u64).div_ceil(100).max(1)
+}
+
+// Number of frames `encode` produces for `len` bytes
+pub fn frame_count(len: u64, mode: OutputMode, settings: &Settings) -> Result<u64> {
+    let capacity = frame_capacity(etcher::frame_bits(settings, mode));
+    let (per_frame, symbol_size) = symbol_layout(packet_len(capacity, settings.fec_parity))?;
+    let overhead = settings.fountain.unwrap_or(DEFAULT_OVERHEAD);
+    
+    // Same split into source blocks as the encoder (RFC 6330, 4.4.1.2)
+    let config = ObjectTransmissionInformation::with_defaults(len, symbol_size);
+    let total = len.div_ceil(symbol_size as u64).max(1);
+    let blocks = config.source_blocks().max(1) as u64;
+    let (large, small) = (total.div_ceil(blocks), total / blocks);
+    let large_blocks = total - small * blocks;
+    
+    let symbols = large_blocks * (large + repair_symbols(large, overhead))
+        + (blocks - large_blocks) * (small + repair_symbols(small, overhead));
+    Ok(symbols.div_ceil(per_frame as u64).max(1))
+}
+
+// Fountain-code `data` into the bytes rendered by `etcher::frames`, one
+// packet per frame
+pub fn encode(data: &[u8], mode: OutputMode, settings: &Settings) -> Result<Vec<u8>> {
+    let stride = etcher::frame_bits(settings, mode);
+    let capacity = frame_capacity(stride);
+    let packet_len = packet_len(capacity, settings.fec_parity);
+    let (per_frame, symbol_size) = symbol_layout(packet_len)?;
+    let overhead = settings.fountain.unwrap_or(DEFAULT_OVERHEAD);
+    
+    let config = ObjectTransmissionInformation::with_defaults(data.len() as u64, symbol_size);
+    let encoder = Encoder::new(data, config);
+    
+    let mut symbols = Vec::new();
+    for block in encoder.get_block_encoders() {
+        let source = block.source_packets();
+        let repair = repair_symbols(source.len() as u64, overhead) as u32;
+        symbols.extend(source);
+        symbols.extend(block.repair_packets(0, repair));
+    }
+    
+    let frames = symbols.len().div_ceil(per_frame) as u64;
+    let total_bits = (frames - 1) * stride + capacity as u64 * 8;
+    let mut stream = vec![0u8; total_bits.div_ceil(8) as usize];
+    
+    for (id, chunk) in symbols.chunks(per_frame).enumerate() {
+        let mut packet = Vec::with_capacity(packet_len);
+        packet.extend_from_slice(&(id as u32).to_le_bytes());
+        packet.extend_from_slice(&config.serialize());
+        packet.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+        for symbol in chunk {
+            packet.extend_from_slice(&symbol.serialize());
+        }
+        packet.extend_from_slice(&crc32fast::hash(&packet).to_le_bytes());
+        packet.resize(packet_len, 0);
+        
+        let encoded = fec::encode(&packet, settings.fec_parity);
+        etcher::place_bits(&mut stream, id as u64 * stride, &encoded, encoded.len() as u64 * 8);
+    }
+    
+    Ok(stream)
+}
+
+// Check one frame's packet and split it into its id, transmission info and symbols
+fn read_packet(packet: &[u8]) -> Option<(u32, ObjectTransmissionInformation, Vec<EncodingPacket>)> {
+    if packet.len() < PACKET_OVERHEAD {
+        return None;
+    }
+    
+    let id = u32::from_le_bytes(packet[0..4].try_into().ok()?);
+    let config = ObjectTransmissionInformation::deserialize(packet[4..16].try_into().ok()?);
+    let count = u16::from_le_bytes([packet[16], packet[17]]) as usize;
+    let symbol_len = SYMBOL_ID_LEN + config.symbol_size() as usize;
+    
+    let end = 18 + count * symbol_len;
+    if config.symbol_size() == 0 || end + 4 > packet.len() {
+        return None;
+    }
+    if crc32fast::hash(&packet[..end]).to_le_bytes() != packet[end..end + 4] {
+        return None;
+    }
+    
+    let symbols = packet[18..end]
+        .chunks(symbol_len)
+        .map(EncodingPacket::deserialize)
+        .collect();
+    Some((id, config, symbols))
+}
+
+// Rebuild the container from whichever frames survived
+pub fn decode<I>(frames: I, layout: &FrameLayout) -> Result<Vec<u8>>
+where
+    I: Iterator<Item = Result<RgbImage>>,
+{
+    let capacity = frame_capacity(layout.frame_bits());
+    let packet_len = packet_len(capacity, layout.fec_parity);
+    let encoded_len = fec::encoded_len(packet_len as u64, layout.fec_parity) as usize;
+    
+    let mut decoder: Option<Decoder> = None;
+    let mut seen = HashSet::new();
+    let (mut read, mut duplicates, mut damaged) = (0, 0, 0);
+    let mut result = None;
+    
+    'frames: for frame in frames {
+        let data = decoder::read_frame(&frame?, layout)?;
+        read += 1;
+        
+        let len = encoded_len.min(data.bytes.len());
+        let (packet, report) = fec::decode(&data.bytes[..len], Some(&data.byte_reliability(0)[..len]), layout.fec_parity)?;
+        let Some((id, config, symbols)) = read_packet(&packet).filter(|_| report.failed_codewords == 0) else {
+            damaged += 1;
+            continue;
+        };
+        if !seen.insert(id) {
+            duplicates += 1;
+            continue;
+        }
+        
+        let decoder = decoder.get_or_insert_with(|| Decoder::new(config));
+        for symbol in symbols {
+            if let Some(data) = decoder.decode(symbol) {
+                result = Some(data);
+                break 'frames;
+            }
+        }
+    }
+    
+    println!(
+        "Fountain decoding: read {} frames, {} unique, {} duplicated, {} damaged",
+        read, seen.len(), duplicates, damaged
+    );
+    
+    result.ok_or_else(|| anyhow!(
+        "Not enough intact frames to rebuild the data ({} unique frames were readable)",
+        seen.len()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::LayoutHint;
+    use crate::frame_header;
+    use crate::settings::Data;
+    
+    // Render `data` as fountain-coded frames, along with the layout to read them
+    fn render(data: &[u8]) -> (Vec<RgbImage>, FrameLayout) {
+        let mut settings = Settings::new(2, 1, 10, 256, 144);
+        settings.fec_parity = 16;
+        settings.fountain = Some(50);
+        
+        let stream = encode(data, OutputMode::Binary, &settings).unwrap();
+        let frames: Vec<RgbImage> = etcher::frames(&Data::new(stream, OutputMode::Binary), &settings).collect();
+        assert_eq!(frames.len() as u64, frame_count(data.len() as u64, OutputMode::Binary, &settings).unwrap());
+        
+        let header = frame_header::read(&frames[0]).unwrap();
+        let layout = FrameLayout::resolve(Some(&header), None, &LayoutHint::default(), 256, 144).unwrap();
+        assert!(layout.fountain);
+        (frames, layout)
+    }
+    
+    fn payload() -> Vec<u8> {
+        (0..12000u32).map(|i| (i * 29 + i / 251) as u8).collect()
+    }
+    
+    #[test]
+    fn decodes_every_frame() {
+        let payload = payload();
+        let (frames, layout) = render(&payload);
+        let decoded = decode(frames.into_iter().map(Ok), &layout).unwrap();
+        assert_eq!(&decoded[..payload.len()], &payload[..]);
+    }
+    
+    #[test]
+    fn decodes_after_drops_and_reordering() {
+        let payload = payload();
+        let (frames, layout) = render(&payload);
+        assert!(frames.len() >= 8);
+        
+        // Lose every fourth frame, repeat one and play the rest backwards
+        let mut kept: Vec<RgbImage> = frames.into_iter()
+            .enumerate()
+            .filter(|(i, _)| i % 4 != 1)
+            .map(|(_, frame)| frame)
+            .collect();
+        kept.push(kept[0].clone());
+        kept.reverse();
+        
+        let decoded = decode(kept.into_iter().map(Ok), &layout).unwrap();
+        assert_eq!(&decoded[..payload.len()], &payload[..]);
+    }
+    
+    #[test]
+    fn fails_with_too_few_frames() {
+        let (frames, layout) = render(&payload());
+        let half = frames.len() / 2;
+        assert!(decode(frames.into_iter().take(half).map(Ok), &layout).is_err());
+    }
+}
\ No newline at end of file
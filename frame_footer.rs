@@ -0,0 +1,86 @@
+// Footer strip rendered across the bottom of every frame, in the same large
+// cells as the header strip (see frame_header.rs).
+//
+// Bytes: frame index (u32 LE) | total frames (u32 LE)
+//        | CRC32 of the frame's data blocks (u32 LE)
+//        | CRC32 of everything before it (u32 LE)
+//
+// The data CRC covers the blocks as the decoder reads them back: the symbol of
+// every block (or 2x2 group of blocks in the palette modes) in `BlockGrid`
+// order, each `OutputMode::bits_per_group` bits wide, packed least
+// significant bit first.
+//
+// Calibration frames (see calibration.rs) carry CALIBRATION_INDEX as their
+// index and no data CRC.
+
+use anyhow::{Result, anyhow};
+use image::RgbImage;
+use crate::frame_header;
+
+pub const ROWS: u32 = 2;
+
+const LEN: usize = 16;
+
+// Frame index that marks a calibration frame
+pub const CALIBRATION_INDEX: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameFooter {
+    pub index: u32,
+    pub total: u32,
+    pub data_crc: u32,
+}
+
+impl FrameFooter {
+    fn to_bytes(self) -> [u8; LEN] {
+        let mut bytes = [0u8; LEN];
+        bytes[0..4].copy_from_slice(&self.index.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.total.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.data_crc.to_le_bytes());
+        let crc = crc32fast::hash(&bytes[..12]);
+        bytes[12..16].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+    
+    fn from_bytes(bytes: &[u8]) -> Option<FrameFooter> {
+        if bytes.len() != LEN {
+            return None;
+        }
+        let word = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        if crc32fast::hash(&bytes[..12]) != word(12) {
+            return None;
+        }
+        
+        let footer = FrameFooter {
+            index: word(0),
+            total: word(4),
+            data_crc: word(8),
+        };
+        (footer.index < footer.total || footer.index == CALIBRATION_INDEX).then_some(footer)
+    }
+}
+
+// Height of the strip; data blocks end right above it
+pub fn height(width: u32) -> u32 {
+    frame_header::cell_size(width) * ROWS
+}
+
+// Make sure every frame index fits in the footer
+pub fn check_frames(frames: u64) -> Result<()> {
+    if frames > u32::MAX as u64 {
+        return Err(anyhow!("{} frames are more than the frame footer can number", frames));
+    }
+    Ok(())
+}
+
+// Draw the footer strip into the bottom of `img`
+pub fn render(img: &mut RgbImage, footer: FrameFooter) {
+    let top = img.height() - height(img.width());
+    frame_header::render_cells(img, top, ROWS, &footer.to_bytes());
+}
+
+// Read the footer strip of a frame, or None if it is missing or damaged
+pub fn read(img: &RgbImage) -> Option<FrameFooter> {
+    let top = img.height().checked_sub(height(img.width()))?;
+    FrameFooter::from_bytes(&frame_header::read_cells(img, top, LEN)?)
+}
\ No newline at end of file
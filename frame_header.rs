@@ -0,0 +1,297 @@
+// Self-describing header strip rendered across the top of every frame.
+//
+// The strip is a fixed grid of COLUMNS x ROWS black/white cells scaled to the
+// frame width, so its cells stay large whatever block size the data uses and
+// the decoder can read it without knowing anything about the layout. Every
+// frame repeats it, so losing the first frame does not lose the header.
+//
+// Bytes: MAGIC (2) | version (u8)
+//        | mode (u8: 0 binary, 1 color, 2/3/4 gray with 4/8/16 levels,
+//          5/6 palette with 8/16 colours) | block size (u16 LE)
+//        | width (u16 LE) | height (u16 LE) | frames (u32 LE)
+//        | payload length (u64 LE) | FEC parity (u8)
+//        | flags (u8: 1 interleaved, 2 fountain, 4 corner markers)
+//        | CRC32 of everything before it (u32 LE)
+//
+// Every frame also ends with the footer strip from frame_footer.rs. The
+// version covers the whole frame layout, strips, swatches and markers
+// included, and frames of any other version are not read.
+
+use std::ops::Range;
+use anyhow::{Result, anyhow};
+use image::{RgbImage, Rgb};
+use crate::etcher::VideoMetadata;
+use crate::settings::OutputMode;
+
+pub const COLUMNS: u32 = 64;
+pub const ROWS: u32 = 4;
+
+const MAGIC: &[u8; 2] = b"SH";
+const VERSION: u8 = 1;
+const LEN: usize = 28;
+
+// The header has to fit in its strip
+const _: () = assert!(LEN * 8 <= (COLUMNS * ROWS) as usize);
+
+// Cells of the strip after the header bytes, which hold the calibration
+// swatches from gray.rs
+pub const SPARE_CELLS: Range<u32> = LEN as u32 * 8..COLUMNS * ROWS;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub mode: OutputMode,
+    pub block_size: u32,
+    pub width: u32,
+    pub height: u32,
+    pub frames: u64,
+    // Length of the rendered bytes (the packed container plus FEC)
+    pub data_size: u64,
+    // Reed–Solomon check bytes per codeword
+    pub fec_parity: u8,
+    // Whether the codewords are interleaved across frames
+    pub interleaved: bool,
+    // Whether every frame carries its own fountain-coded packet
+    pub fountain: bool,
+    // Whether the data area carries the corner markers from fiducial.rs
+    pub markers: bool,
+}
+
+impl FrameHeader {
+    pub fn new(metadata: &VideoMetadata) -> FrameHeader {
+        FrameHeader {
+            mode: metadata.mode,
+            block_size: metadata.block_size,
+            width: metadata.width,
+            height: metadata.height,
+            frames: metadata.frames,
+            data_size: metadata.data_size,
+            fec_parity: metadata.fec_parity,
+            interleaved: metadata.interleaved,
+            fountain: metadata.fountain,
+            markers: metadata.markers,
+        }
+    }
+    
+    fn to_bytes(&self) -> [u8; LEN] {
+        let mut bytes = Vec::with_capacity(LEN);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.push(match self.mode {
+            OutputMode::Binary => 0,
+            OutputMode::Color => 1,
+            OutputMode::Gray4 => 2,
+            OutputMode::Gray8 => 3,
+            OutputMode::Gray16 => 4,
+            OutputMode::Palette8 => 5,
+            OutputMode::Palette16 => 6,
+        });
+        bytes.extend_from_slice(&(self.block_size as u16).to_le_bytes());
+        bytes.extend_from_slice(&(self.width as u16).to_le_bytes());
+        bytes.extend_from_slice(&(self.height as u16).to_le_bytes());
+        bytes.extend_from_slice(&(self.frames as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.data_size.to_le_bytes());
+        bytes.push(self.fec_parity);
+        bytes.push(self.interleaved as u8 | (self.fountain as u8) << 1 | (self.markers as u8) << 2);
+        bytes.extend_from_slice(&crc32fast::hash(&bytes).to_le_bytes());
+        
+        let mut out = [0u8; LEN];
+        out.copy_from_slice(&bytes);
+        out
+    }
+    
+    fn from_bytes(bytes: &[u8; LEN]) -> Option<FrameHeader> {
+        let (body, crc) = bytes.split_at(LEN - 4);
+        if &body[0..2] != MAGIC || body[2] != VERSION {
+            return None;
+        }
+        if crc32fast::hash(body).to_le_bytes() != crc {
+            return None;
+        }
+        
+        let u16_at = |i: usize| u16::from_le_bytes([body[i], body[i + 1]]) as u32;
+        let u32_at = |i: usize| u32::from_le_bytes(body[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(body[i..i + 8].try_into().unwrap());
+        
+        Some(FrameHeader {
+            mode: match body[3] {
+                0 => OutputMode::Binary,
+                1 => OutputMode::Color,
+                2 => OutputMode::Gray4,
+                3 => OutputMode::Gray8,
+                4 => OutputMode::Gray16,
+                5 => OutputMode::Palette8,
+                6 => OutputMode::Palette16,
+                _ => return None,
+            },
+            block_size: u16_at(4),
+            width: u16_at(6),
+            height: u16_at(8),
+            frames: u32_at(10) as u64,
+            data_size: u64_at(14),
+            fec_parity: body[22],
+            interleaved: body[23] & 1 != 0,
+            fountain: body[23] & 2 != 0,
+            markers: body[23] & 4 != 0,
+        })
+    }
+}
+
+// Side of one header cell in a frame `width` pixels wide
+pub fn cell_size(width: u32) -> u32 {
+    width / COLUMNS
+}
+
+// Height of the strip; data blocks start right below it
+pub fn height(width: u32) -> u32 {
+    cell_size(width) * ROWS
+}
+
+// Make sure the strip fits and every field can be stored
+pub fn check(width: u32, height: u32, block_size: u32) -> Result<()> {
+    // The footer strip uses the same cells, so this covers it as well
+    if cell_size(width) == 0 {
+        return Err(anyhow!("Frames must be at least {} pixels wide to hold the frame header", COLUMNS));
+    }
+    if width > u16::MAX as u32 || height > u16::MAX as u32 || block_size > u16::MAX as u32 {
+        return Err(anyhow!("{}x{} frames with block size {} do not fit in the frame header", width, height, block_size));
+    }
+    Ok(())
+}
+
+// Draw the header strip into the top of `img`
+pub fn render(img: &mut RgbImage, header: &FrameHeader) {
+    render_cells(img, 0, ROWS, &header.to_bytes());
+}
+
+// Read the header strip of a frame, or None if it is missing or damaged
+pub fn read(img: &RgbImage) -> Option<FrameHeader> {
+    if height(img.width()) > img.height() {
+        return None;
+    }
+    let bytes = read_cells(img, 0, LEN)?;
+    FrameHeader::from_bytes(bytes.as_slice().try_into().ok()?)
+}
+
+// Paint cell `index` (row-major) of the grid starting at pixel row `top`
+pub fn fill_cell(img: &mut RgbImage, top: u32, index: u32, color: Rgb<u8>) {
+    let cell = cell_size(img.width());
+    let (left, cell_top) = ((index % COLUMNS) * cell, top + (index / COLUMNS) * cell);
+    for y in cell_top..cell_top + cell {
+        for x in left..left + cell {
+            img.put_pixel(x, y, color);
+        }
+    }
+}
+
+// Mean colour of the inner half of cell `index`, away from the edges where
+// compression smears neighbouring cells together. None if it is off the frame.
+pub fn cell_color(img: &RgbImage, top: u32, index: u32) -> Option<[f64; 3]> {
+    let cell = cell_size(img.width());
+    let (column, row) = (index % COLUMNS, index / COLUMNS);
+    if cell == 0 || top + (row + 1) * cell > img.height() {
+        return None;
+    }
+    
+    let margin = cell / 4;
+    let inner = (cell - margin * 2).max(1);
+    let (left, cell_top) = (column * cell + margin, top + row * cell + margin);
+    
+    let mut total = [0u32; 3];
+    for y in cell_top..cell_top + inner {
+        for x in left..left + inner {
+            for (sum, channel) in total.iter_mut().zip(img.get_pixel(x, y).0) {
+                *sum += channel as u32;
+            }
+        }
+    }
+    Some(total.map(|sum| sum as f64 / (inner * inner) as f64))
+}
+
+// Mean luma of the inner half of cell `index`, None if it is off the frame
+pub fn cell_luma(img: &RgbImage, top: u32, index: u32) -> Option<f64> {
+    let [r, g, b] = cell_color(img, top, index)?;
+    Some((r * 299.0 + g * 587.0 + b * 114.0) / 1000.0)
+}
+
+// Draw `bytes` as `rows` rows of COLUMNS black/white cells starting at pixel
+// row `top`. Bits go row-major, least significant bit of each byte first;
+// cells past the end of `bytes` are black.
+pub fn render_cells(img: &mut RgbImage, top: u32, rows: u32, bytes: &[u8]) {
+    for index in 0..rows * COLUMNS {
+        let bit = index as usize;
+        let set = bytes.get(bit / 8).is_some_and(|byte| (byte >> (bit % 8)) & 1 == 1);
+        let color = if set { Rgb([255, 255, 255]) } else { Rgb([0, 0, 0]) };
+        fill_cell(img, top, index, color);
+    }
+}
+
+// Read `len` bytes drawn by `render_cells`, judging each cell on `cell_luma`
+pub fn read_cells(img: &RgbImage, top: u32, len: usize) -> Option<Vec<u8>> {
+    let mut bytes = vec![0u8; len];
+    for bit in 0..len * 8 {
+        if cell_luma(img, top, bit as u32)? >= 128.0 {
+            bytes[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn header(mode: OutputMode) -> FrameHeader {
+        FrameHeader {
+            mode,
+            block_size: 3,
+            width: 1280,
+            height: 720,
+            frames: 4321,
+            data_size: 0x1_2345_6789,
+            fec_parity: 32,
+            interleaved: true,
+            fountain: false,
+            markers: true,
+        }
+    }
+    
+    #[test]
+    fn round_trip() {
+        for mode in [
+            OutputMode::Binary, OutputMode::Color, OutputMode::Gray4, OutputMode::Gray8,
+            OutputMode::Gray16, OutputMode::Palette8, OutputMode::Palette16,
+        ] {
+            let header = header(mode);
+            assert_eq!(FrameHeader::from_bytes(&header.to_bytes()), Some(header.clone()));
+            
+            let mut img = RgbImage::new(1280, 720);
+            render(&mut img, &header);
+            assert_eq!(read(&img), Some(header));
+        }
+    }
+    
+    #[test]
+    fn rejects_other_versions() {
+        let mut bytes = header(OutputMode::Binary).to_bytes();
+        bytes[2] = VERSION + 1;
+        // A valid CRC, so only the version is wrong
+        let crc = crc32fast::hash(&bytes[..LEN - 4]);
+        bytes[LEN - 4..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(FrameHeader::from_bytes(&bytes), None);
+    }
+    
+    #[test]
+    fn rejects_a_corrupted_cell() {
+        let mut img = RgbImage::new(1280, 720);
+        render(&mut img, &header(OutputMode::Gray8));
+        
+        // Flip the cell holding the lowest bit of the frame count
+        let cell = 10 * 8;
+        let flipped = if cell_luma(&img, 0, cell).unwrap() >= 128.0 { Rgb([0, 0, 0]) } else { Rgb([255, 255, 255]) };
+        fill_cell(&mut img, 0, cell, flipped);
+        assert_eq!(read(&img), None);
+        
+        // Frames too short for the strip have no header
+        assert_eq!(read(&RgbImage::new(1280, 40)), None);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,175 @@
+// Multi-level grayscale modulation.
+//
+// Gray modes split the rendered stream into symbols of 2, 3 or 4 bits and draw
+// every block at one of 4, 8 or 16 evenly spaced luma levels. Symbols are
+// Gray-coded onto levels, so a block read one level too high or too low costs
+// a single bit that the Reed–Solomon layer can repair.
+//
+// Calibration: the header strip cells that the header bytes leave free hold
+// swatches of every level (spare cell i shows level i % levels). The decoder
+// measures them in each frame and places its thresholds halfway between
+// neighbouring levels as they actually came out, which absorbs gamma,
+// limited-range squashing and brightness drift from the codec.
+
+use image::{RgbImage, Rgb};
+use crate::frame_header;
+
+// Measured levels closer together than this are not trusted
+const MIN_LEVEL_GAP: f64 = 1.0;
+
+// Gray code of `value`: neighbouring values differ in exactly one bit
+pub fn to_gray(value: u32) -> u32 {
+    value ^ (value >> 1)
+}
+
+// Inverse of `to_gray`
+pub fn from_gray(mut gray: u32) -> u32 {
+    let mut value = gray;
+    while gray > 0 {
+        gray >>= 1;
+        value ^= gray;
+    }
+    value
+}
+
+// Luma of `level` out of `levels`, spread evenly from black to white
+pub fn level_luma(level: u32, levels: u32) -> u8 {
+    ((level * 255 + (levels - 1) / 2) / (levels - 1)) as u8
+}
+
+// Colour of a block carrying `symbol`
+pub fn symbol_color(symbol: u32, levels: u32) -> Rgb<u8> {
+    let luma = level_luma(from_gray(symbol), levels);
+    Rgb([luma, luma, luma])
+}
+
+// Draw the calibration swatches into the spare cells of the header strip
+pub fn render_swatches(img: &mut RgbImage, levels: u32) {
+    for (i, cell) in frame_header::SPARE_CELLS.enumerate() {
+        let luma = level_luma(i as u32 % levels, levels);
+        frame_header::fill_cell(img, 0, cell, Rgb([luma, luma, luma]));
+    }
+}
+
+// Thresholds for telling the levels of one frame apart
+#[derive(Debug, Clone)]
+pub struct Calibration {
+    // Luma of every level, as rendered or as measured
+    centres: Vec<f64>,
+    // Luma boundary between level i and level i + 1
+    thresholds: Vec<f64>,
+}
+
+impl Calibration {
+    // Thresholds halfway between the levels as rendered
+    pub fn nominal(levels: u32) -> Calibration {
+        let centres: Vec<f64> = (0..levels).map(|level| level_luma(level, levels) as f64).collect();
+        Calibration {
+            thresholds: midpoints(&centres),
+            centres,
+        }
+    }
+    
+    // Thresholds from the swatches of `img`, or the nominal ones if the
+    // swatches are missing or do not look like distinct, ordered levels
+    pub fn measure(img: &RgbImage, levels: u32) -> Calibration {
+        let mut sums = vec![(0.0, 0u32); levels as usize];
+        for (i, cell) in frame_header::SPARE_CELLS.enumerate() {
+            let Some(luma) = frame_header::cell_luma(img, 0, cell) else {
+                return Calibration::nominal(levels);
+            };
+            let level = &mut sums[i % levels as usize];
+            level.0 += luma;
+            level.1 += 1;
+        }
+        
+        if sums.iter().any(|&(_, count)| count == 0) {
+            return Calibration::nominal(levels);
+        }
+        let centres: Vec<f64> = sums.iter().map(|&(sum, count)| sum / count as f64).collect();
+        if centres.windows(2).any(|pair| pair[1] - pair[0] < MIN_LEVEL_GAP) {
+            return Calibration::nominal(levels);
+        }
+        
+        Calibration {
+            thresholds: midpoints(&centres),
+            centres,
+        }
+    }
+    
+    // Luma of every level, darkest first
+    pub fn centres(&self) -> &[f64] {
+        &self.centres
+    }
+    
+    // Level of a block with the given luma
+    pub fn level(&self, luma: f64) -> u32 {
+        self.thresholds.iter().take_while(|&&threshold| luma >= threshold).count() as u32
+    }
+    
+    // Symbol carried by a block with the given luma
+    pub fn symbol(&self, luma: f64) -> u32 {
+        to_gray(self.level(luma))
+    }
+}
+
+fn midpoints(centres: &[f64]) -> Vec<f64> {
+    centres.windows(2).map(|pair| (pair[0] + pair[1]) / 2.0).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn gray_code_round_trip() {
+        for value in 0..256 {
+            assert_eq!(from_gray(to_gray(value)), value);
+            assert_eq!((to_gray(value) ^ to_gray(value + 1)).count_ones(), 1);
+        }
+    }
+    
+    #[test]
+    fn nominal_levels_read_back() {
+        for levels in [4, 8, 16] {
+            let calibration = Calibration::nominal(levels);
+            assert_eq!(calibration.centres().first(), Some(&0.0));
+            assert_eq!(calibration.centres().last(), Some(&255.0));
+            for symbol in 0..levels {
+                let luma = symbol_color(symbol, levels).0[0] as f64;
+                assert_eq!(calibration.symbol(luma), symbol);
+                // One level off by less than half a step still reads right
+                let step = 255.0 / (levels - 1) as f64;
+                assert_eq!(calibration.symbol((luma + step * 0.4).min(255.0)), symbol);
+                assert_eq!(calibration.symbol((luma - step * 0.4).max(0.0)), symbol);
+            }
+        }
+    }
+    
+    #[test]
+    fn swatches_follow_the_codec() {
+        let levels = 8;
+        // Limited range with a gamma bend, as some encoders leave it
+        let squash = |luma: u8| (16.0 + 219.0 * (luma as f64 / 255.0).powf(1.4)).round() as u8;
+        
+        let mut img = RgbImage::new(640, 360);
+        render_swatches(&mut img, levels);
+        for pixel in img.pixels_mut() {
+            pixel.0 = pixel.0.map(squash);
+        }
+        
+        let measured = Calibration::measure(&img, levels);
+        let nominal = Calibration::nominal(levels);
+        let mut nominal_misses = 0;
+        for symbol in 0..levels {
+            let luma = squash(symbol_color(symbol, levels).0[0]) as f64;
+            assert_eq!(measured.symbol(luma), symbol);
+            nominal_misses += usize::from(nominal.symbol(luma) != symbol);
+        }
+        assert!(nominal_misses > 0);
+        
+        // A frame without swatches falls back to the nominal levels
+        let blank = Calibration::measure(&RgbImage::new(640, 360), levels);
+        assert_eq!(blank.centres(), nominal.centres());
+    }
+}
\ No newline at end of file
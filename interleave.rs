@@ -0,0 +1,63 @@
+// Block interleaver for error-corrected data.
+//
+// The input is read as rows of `row_len` bytes (one FEC codeword per row, the
+// last row possibly shorter) and written out column by column. Neighbouring
+// bytes in the rendered frames then belong to different codewords, and each
+// codeword is spread evenly over every frame, so a burst of damage (a smeared
+// macroblock, a lost keyframe) costs each codeword only a few bytes.
+
+// Reorder `bytes` column by column
+pub fn interleave(bytes: &[u8], row_len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len());
+    for index in order(bytes.len(), row_len) {
+        out.push(bytes[index]);
+    }
+    out
+}
+
+// Undo `interleave` for the same length and row size
+pub fn deinterleave(bytes: &[u8], row_len: usize) -> Vec<u8> {
+    let mut out = vec![0u8; bytes.len()];
+    for (&byte, index) in bytes.iter().zip(order(bytes.len(), row_len)) {
+        out[index] = byte;
+    }
+    out
+}
+
+// Positions in the row-major input, in the order they are written out
+fn order(len: usize, row_len: usize) -> impl Iterator<Item = usize> {
+    let row_len = row_len.max(1);
+    let rows = len.div_ceil(row_len);
+    (0..row_len)
+        .flat_map(move |column| (0..rows).map(move |row| row * row_len + column))
+        .filter(move |&index| index < len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn deinterleave_inverts_interleave() {
+        for len in [0, 1, 254, 255, 256, 1000, 2550] {
+            let bytes: Vec<u8> = (0..len).map(|i| (i * 13 + i / 256) as u8).collect();
+            let interleaved = interleave(&bytes, 255);
+            assert_eq!(interleaved.len(), len);
+            assert_eq!(deinterleave(&interleaved, 255), bytes, "{} bytes", len);
+        }
+    }
+    
+    #[test]
+    fn every_position_is_used_once() {
+        let mut positions: Vec<usize> = order(1000, 255).collect();
+        positions.sort_unstable();
+        assert_eq!(positions, (0..1000).collect::<Vec<_>>());
+    }
+    
+    // A burst of neighbouring bytes lands in different rows
+    #[test]
+    fn spreads_bursts_over_rows() {
+        let bytes: Vec<u8> = (0..4 * 255).map(|i| (i / 255) as u8).collect();
+        assert_eq!(&interleave(&bytes, 255)[..8], &[0, 1, 2, 3, 0, 1, 2, 3]);
+    }
+}
\ No newline at end of file
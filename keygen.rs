@@ -0,0 +1,49 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use anyhow::{Context, Result};
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::rand_core::RngCore;
+use ed25519_dalek::SigningKey;
+use x25519_dalek::{PublicKey, StaticSecret};
+use crate::args::KeygenParams;
+use crate::{crypto, signing};
+
+pub async fn run_keygen(args: KeygenParams) -> Result<()> {
+    let (public, secret) = if args.signing {
+        let mut seed = [0u8; 32];
+        OsRng.fill_bytes(&mut seed);
+        let key = SigningKey::from_bytes(&seed);
+        (signing::format_public_key(&key.verifying_key()), signing::format_secret_key(&key))
+    } else {
+        let key = StaticSecret::random_from_rng(OsRng);
+        (crypto::format_public_key(&PublicKey::from(&key)), crypto::format_secret_key(&key))
+    };
+    
+    let identity = format!(
+        "# created: {}\n# public key: {}\n{}\n",
+        chrono::Local::now().to_rfc3339(), public, secret
+    );
+    
+    match args.out_path {
+        Some(path) => {
+            // Never overwrite an existing key, and keep the new one private
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            
+            let mut file = options.open(&path)
+                .with_context(|| format!("Cannot create identity file {}", path))?;
+            file.write_all(identity.as_bytes())?;
+            
+            println!("Identity written to: {}", path);
+            println!("Public key: {}", public);
+        }
+        None => print!("{}", identity),
+    }
+    
+    Ok(())
+}
\ No newline at end of file
@@ -1,6 +1,26 @@
 mod args;
 mod settings;
 mod etcher;
+mod container;
+mod compress;
+mod crypto;
+mod signing;
+mod fec;
+mod fountain;
+mod interleave;
+mod frame_header;
+mod frame_footer;
+mod gray;
+mod palette;
+mod threshold;
+mod sampling;
+mod calibration;
+mod fiducial;
+mod png_sequence;
+mod y4m;
+mod avi;
+mod ffmpeg;
+mod decoder;
 mod run_tasks;
 mod youtube_dl;
 mod ui;
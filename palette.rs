@@ -0,0 +1,282 @@
+// Colour palette modulation that survives chroma subsampling.
+//
+// Codecs usually keep chroma at half resolution (4:2:0, one sample per 2x2
+// pixels), so a colour that changes from block to block bleeds into its
+// neighbours. Palette modes therefore work on groups of 2x2 blocks: the whole
+// group shares one of four chroma points, and each block picks its own luma
+// level, which the codec keeps at full resolution. With 2 or 4 luma levels
+// that makes a palette of 8 or 16 colours, spread out in YCbCr.
+//
+// A group carries 2 bits of chroma followed by the luma symbol of each block
+// (left to right, top to bottom), least significant bit first. Chroma points
+// and luma levels are both Gray-coded, so confusing neighbours costs one bit.
+//
+// The spare header cells hold swatches of every palette colour. The decoder
+// measures them in each frame and gives every group the nearest palette
+// colours as they actually came out, along with a confidence per block.
+
+use std::f64::consts::{FRAC_PI_2, FRAC_PI_4, SQRT_2};
+use image::{Rgb, RgbImage};
+use crate::frame_header;
+use crate::gray::{from_gray, to_gray};
+use crate::settings::OutputMode;
+
+// Chroma points a group can take, and the bits they carry
+const CHROMAS: u32 = 4;
+const CHROMA_BITS: u32 = 2;
+
+// Measured colours closer together than this are not trusted
+const MIN_COLOUR_GAP: f64 = 4.0;
+
+// Blocks read with less confidence than this count as doubtful
+pub const LOW_CONFIDENCE: f32 = 0.25;
+
+// Y, Cb, Cr (BT.601, full range, chroma centred on 0)
+type Ycc = [f64; 3];
+
+fn to_ycc(Rgb(rgb): Rgb<u8>) -> Ycc {
+    rgb_to_ycc(rgb.map(|channel| channel as f64))
+}
+
+fn rgb_to_ycc([r, g, b]: [f64; 3]) -> Ycc {
+    [
+        0.299 * r + 0.587 * g + 0.114 * b,
+        -0.168736 * r - 0.331264 * g + 0.5 * b,
+        0.5 * r - 0.418688 * g - 0.081312 * b,
+    ]
+}
+
+fn to_rgb([y, cb, cr]: Ycc) -> Rgb<u8> {
+    let channel = |value: f64| value.round().clamp(0.0, 255.0) as u8;
+    Rgb([
+        channel(y + 1.402 * cr),
+        channel(y - 0.344136 * cb - 0.714136 * cr),
+        channel(y + 1.772 * cb),
+    ])
+}
+
+fn distance(a: &Ycc, b: &Ycc) -> f64 {
+    a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum::<f64>().sqrt()
+}
+
+// 1 for a block right on its colour, falling to 0 halfway to the runner-up
+fn confidence(nearest: f64, runner_up: f64) -> f32 {
+    if runner_up <= 0.0 {
+        return 0.0;
+    }
+    ((runner_up - nearest) / (runner_up + nearest)) as f32
+}
+
+// The colours of a palette mode
+#[derive(Debug, Clone)]
+pub struct Palette {
+    luma_levels: u32,
+    // What the encoder draws, chroma point c at luma level l at c * luma_levels + l
+    rendered: Vec<Rgb<u8>>,
+    // The same colours in YCbCr, as rendered or as measured in a frame
+    colors: Vec<Ycc>,
+}
+
+impl Palette {
+    // The palette as rendered, None outside the palette modes
+    pub fn nominal(mode: OutputMode) -> Option<Palette> {
+        let (luma_levels, chroma): (u32, f64) = match mode {
+            OutputMode::Palette8 => (2, 40.0),
+            OutputMode::Palette16 => (4, 32.0),
+            _ => return None,
+        };
+        // Blue swings furthest with chroma (1.772 x Cb), so keep the luma
+        // levels far enough from black and white for it to stay in range
+        let margin = 1.772 * chroma;
+        
+        let mut rendered = Vec::new();
+        for point in 0..CHROMAS {
+            // Chroma points sit on the diagonals, in order round the circle
+            let angle = FRAC_PI_4 + FRAC_PI_2 * point as f64;
+            let (cb, cr) = (chroma * SQRT_2 * angle.cos(), chroma * SQRT_2 * angle.sin());
+            for level in 0..luma_levels {
+                let y = margin + (255.0 - 2.0 * margin) * level as f64 / (luma_levels - 1) as f64;
+                rendered.push(to_rgb([y, cb, cr]));
+            }
+        }
+        
+        Some(Palette {
+            luma_levels,
+            colors: rendered.iter().map(|&color| to_ycc(color)).collect(),
+            rendered,
+        })
+    }
+    
+    // The palette as it came out in the swatches of `img`. Falls back to the
+    // nominal colours if the swatches are missing or run into each other.
+    pub fn measure(img: &RgbImage, mode: OutputMode) -> Option<Palette> {
+        let nominal = Palette::nominal(mode)?;
+        let size = nominal.colors.len();
+        
+        let mut sums = vec![([0.0; 3], 0u32); size];
+        for (i, cell) in frame_header::SPARE_CELLS.enumerate() {
+            let Some(color) = frame_header::cell_color(img, 0, cell) else {
+                return Some(nominal);
+            };
+            let ycc = rgb_to_ycc(color);
+            let swatch = &mut sums[i % size];
+            for (sum, value) in swatch.0.iter_mut().zip(ycc) {
+                *sum += value;
+            }
+            swatch.1 += 1;
+        }
+        
+        if sums.iter().any(|&(_, count)| count == 0) {
+            return Some(nominal);
+        }
+        let colors: Vec<Ycc> = sums.iter().map(|&(sum, count)| sum.map(|value| value / count as f64)).collect();
+        for (i, a) in colors.iter().enumerate() {
+            if colors[i + 1..].iter().any(|b| distance(a, b) < MIN_COLOUR_GAP) {
+                return Some(nominal);
+            }
+        }
+        
+        Some(Palette { colors, ..nominal })
+    }
+    
+    // Every colour of the palette as drawn
+    pub fn colors(&self) -> &[Rgb<u8>] {
+        &self.rendered
+    }
+    
+    fn luma_bits(&self) -> u32 {
+        self.luma_levels.trailing_zeros()
+    }
+    
+    // Colours of the four blocks of a group carrying `symbol`, in reading order
+    pub fn group_colors(&self, symbol: u32) -> [Rgb<u8>; 4] {
+        let point = from_gray(symbol & (CHROMAS - 1));
+        let bits = self.luma_bits();
+        std::array::from_fn(|block| {
+            let level = from_gray((symbol >> (CHROMA_BITS + block as u32 * bits)) & (self.luma_levels - 1));
+            self.rendered[(point * self.luma_levels + level) as usize]
+        })
+    }
+    
+    // Draw the swatches into the spare cells of the header strip
+    pub fn render_swatches(&self, img: &mut RgbImage) {
+        for (i, cell) in frame_header::SPARE_CELLS.enumerate() {
+            frame_header::fill_cell(img, 0, cell, self.rendered[i % self.rendered.len()]);
+        }
+    }
+    
+    // Nearest and second nearest luma level among the colours of chroma point
+    // `point`, with their distances
+    fn nearest_level(&self, point: u32, ycc: &Ycc) -> (u32, f64, f64) {
+        let mut best = (0, f64::MAX, f64::MAX);
+        for level in 0..self.luma_levels {
+            let d = distance(ycc, &self.colors[(point * self.luma_levels + level) as usize]);
+            if d < best.1 {
+                best = (level, d, best.1);
+            } else if d < best.2 {
+                best.2 = d;
+            }
+        }
+        best
+    }
+    
+    // Symbol carried by a group whose four blocks show `pixels`, in reading
+    // order, and how confident the decoder is in each block.
+    //
+    // The blocks share their chroma, so it is averaged over the group, which is
+    // what a subsampled chroma plane holds anyway. The group then takes the
+    // chroma point whose colours lie nearest to all four blocks together.
+    pub fn read_group(&self, pixels: [Rgb<u8>; 4]) -> (u32, [f32; 4]) {
+        let mut blocks = pixels.map(to_ycc);
+        for channel in 1..3 {
+            let mean = blocks.iter().map(|ycc| ycc[channel]).sum::<f64>() / 4.0;
+            for ycc in blocks.iter_mut() {
+                ycc[channel] = mean;
+            }
+        }
+        
+        let mut costs: Vec<(u32, f64)> = (0..CHROMAS)
+            .map(|point| {
+                let cost = blocks.iter().map(|ycc| self.nearest_level(point, ycc).1.powi(2)).sum::<f64>();
+                (point, cost.sqrt())
+            })
+            .collect();
+        costs.sort_by(|a, b| a.1.total_cmp(&b.1));
+        let point = costs[0].0;
+        let chroma_confidence = confidence(costs[0].1, costs[1].1);
+        
+        let mut symbol = to_gray(point);
+        let mut confidences = [0.0; 4];
+        let bits = self.luma_bits();
+        for (block, ycc) in blocks.iter().enumerate() {
+            let (level, nearest, runner_up) = self.nearest_level(point, ycc);
+            symbol |= to_gray(level) << (CHROMA_BITS + block as u32 * bits);
+            confidences[block] = chroma_confidence.min(confidence(nearest, runner_up));
+        }
+        (symbol, confidences)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    const MODES: [OutputMode; 2] = [OutputMode::Palette8, OutputMode::Palette16];
+    
+    // What 4:2:0 does to a group: one chroma sample for the four blocks,
+    // here pulled a quarter of the way towards a neighbouring group's
+    fn subsample(pixels: [Rgb<u8>; 4], neighbour: Rgb<u8>) -> [Rgb<u8>; 4] {
+        let blocks = pixels.map(to_ycc);
+        let bleed = to_ycc(neighbour);
+        let mut chroma = [0.0; 2];
+        for (channel, value) in chroma.iter_mut().enumerate() {
+            let mean = blocks.iter().map(|ycc| ycc[channel + 1]).sum::<f64>() / 4.0;
+            *value = 0.75 * mean + 0.25 * bleed[channel + 1];
+        }
+        blocks.map(|[y, _, _]| to_rgb([y, chroma[0], chroma[1]]))
+    }
+    
+    #[test]
+    fn groups_round_trip() {
+        for mode in MODES {
+            let palette = Palette::nominal(mode).unwrap();
+            for symbol in 0..1 << mode.bits_per_group() {
+                let (read, confidences) = palette.read_group(palette.group_colors(symbol));
+                assert_eq!(read, symbol, "{:?}", mode);
+                assert!(confidences.iter().all(|&c| c > 0.9), "{:?} symbol {}: {:?}", mode, symbol, confidences);
+            }
+        }
+    }
+    
+    #[test]
+    fn nearest_colours_survive_chroma_subsampling() {
+        for mode in MODES {
+            let palette = Palette::nominal(mode).unwrap();
+            let symbols = 1u32 << mode.bits_per_group();
+            for symbol in 0..symbols {
+                // The neighbour sits on the opposite chroma point
+                let neighbour = palette.group_colors(symbol ^ 3)[0];
+                let pixels = subsample(palette.group_colors(symbol), neighbour);
+                let (read, confidences) = palette.read_group(pixels);
+                assert_eq!(read, symbol, "{:?}", mode);
+                assert!(confidences.iter().all(|&c| c > LOW_CONFIDENCE), "{:?} symbol {}: {:?}", mode, symbol, confidences);
+            }
+        }
+    }
+    
+    #[test]
+    fn measured_swatches_follow_a_shift() {
+        // Every colour came out 20 levels darker
+        let palette = Palette::nominal(OutputMode::Palette16).unwrap();
+        let mut img = RgbImage::new(1280, 720);
+        palette.render_swatches(&mut img);
+        let darken = |Rgb(rgb): Rgb<u8>| Rgb(rgb.map(|channel| channel.saturating_sub(20)));
+        img.pixels_mut().for_each(|pixel| *pixel = darken(*pixel));
+        
+        let measured = Palette::measure(&img, OutputMode::Palette16).unwrap();
+        for symbol in [0, 0x155, 0x2aa, 0x3ff] {
+            let (read, _) = measured.read_group(palette.group_colors(symbol).map(darken));
+            assert_eq!(read, symbol);
+        }
+    }
+}
\ No newline at end of file
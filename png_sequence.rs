@@ -0,0 +1,183 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Context, anyhow};
+use image::RgbImage;
+use crate::etcher::{self, VideoMetadata};
+use crate::settings::{Data, Settings};
+
+// Name of the manifest written next to the frames
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+// File name of frame `index` (zero based) inside the sequence directory
+pub fn frame_name(index: usize) -> String {
+    format!("frame_{:06}.png", index + 1)
+}
+
+// Whether `name` is a frame file written by `write_sequence`
+fn is_frame_name(name: &str) -> bool {
+    name.strip_prefix("frame_")
+        .and_then(|rest| rest.strip_suffix(".png"))
+        .is_some_and(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+}
+
+// Delete the frames of an earlier sequence in `dir`, so a longer earlier run
+// does not leave its last frames behind to be read as part of this one
+fn remove_old_frames(dir: &Path) -> Result<()> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read directory {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_file() && path.file_name().and_then(|name| name.to_str()).is_some_and(is_frame_name) {
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove old frame {}", path.display()))?;
+            removed += 1;
+        }
+    }
+    if removed > 0 {
+        println!("Removed {} frames of an earlier sequence from {}", removed, dir.display());
+    }
+    Ok(())
+}
+
+// Write every rendered frame as a numbered PNG into `dir`, plus a manifest
+// describing how the frames were laid out. Frames already in `dir` are
+// replaced.
+pub fn write_sequence<P: AsRef<Path>>(dir: P, data: &Data, settings: &Settings) -> Result<()> {
+    let dir = dir.as_ref();
+    etcher::check_layout(settings, data.out_mode)?;
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create output directory {}", dir.display()))?;
+    remove_old_frames(dir)?;
+    
+    let frames = etcher::frames(data, settings);
+    let pb = etcher::frame_progress(frames.len());
+    
+    for (index, frame) in frames.enumerate() {
+        let frame_path = dir.join(frame_name(index));
+        frame.save(&frame_path)
+            .with_context(|| format!("Failed to write frame {}", frame_path.display()))?;
+        pb.inc(1);
+    }
+    
+    pb.finish_and_clear();
+    
+    write_manifest(dir, &VideoMetadata::new(data, settings))?;
+    
+    println!("Frames written to: {}", dir.display());
+    Ok(())
+}
+
+// Write the manifest for a frame sequence
+pub fn write_manifest<P: AsRef<Path>>(dir: P, metadata: &VideoMetadata) -> Result<()> {
+    let manifest_path = dir.as_ref().join(MANIFEST_NAME);
+    let manifest = serde_json::to_string_pretty(metadata)?;
+    fs::write(&manifest_path, manifest)
+        .with_context(|| format!("Failed to write manifest {}", manifest_path.display()))?;
+    Ok(())
+}
+
+// Read the manifest of a frame sequence
+pub fn read_manifest<P: AsRef<Path>>(dir: P) -> Result<VideoMetadata> {
+    let manifest_path = dir.as_ref().join(MANIFEST_NAME);
+    let manifest = fs::read_to_string(&manifest_path)
+        .with_context(|| format!("Failed to read manifest {}", manifest_path.display()))?;
+    let metadata = serde_json::from_str(&manifest)
+        .with_context(|| format!("Invalid manifest {}", manifest_path.display()))?;
+    Ok(metadata)
+}
+
+// Reads the frames of a sequence directory in file name order
+pub struct PngSequenceReader {
+    pub width: u32,
+    pub height: u32,
+    manifest: Option<VideoMetadata>,
+    frames: std::vec::IntoIter<PathBuf>,
+}
+
+impl PngSequenceReader {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<PngSequenceReader> {
+        let dir = dir.as_ref();
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+            .with_context(|| format!("Failed to read directory {}", dir.display()))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            // Pictures of the frames (see fiducial.rs) are often JPEG
+            .filter(|path| path.extension().is_some_and(|ext| ["png", "jpg", "jpeg"].iter().any(|known| ext.eq_ignore_ascii_case(known))))
+            .collect();
+        paths.sort();
+        
+        let first = paths.first()
+            .ok_or_else(|| anyhow!("No PNG or JPEG frames found in {}", dir.display()))?;
+        let (width, height) = image::image_dimensions(first)
+            .with_context(|| format!("Failed to read frame {}", first.display()))?;
+        
+        // The manifest is optional; without it the layout has to be given by hand
+        let manifest = read_manifest(dir).ok();
+        
+        Ok(PngSequenceReader {
+            width,
+            height,
+            manifest,
+            frames: paths.into_iter(),
+        })
+    }
+    
+    // Layout recorded in the manifest, if there is one
+    pub fn metadata(&self) -> Option<VideoMetadata> {
+        self.manifest.clone()
+    }
+}
+
+impl Iterator for PngSequenceReader {
+    type Item = Result<RgbImage>;
+    
+    fn next(&mut self) -> Option<Result<RgbImage>> {
+        let path = self.frames.next()?;
+        Some(
+            image::open(&path)
+                .map(|img| img.to_rgb8())
+                .with_context(|| format!("Failed to read frame {}", path.display())),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::OutputMode;
+    
+    fn frame_files(dir: &Path) -> usize {
+        fs::read_dir(dir).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_str().is_some_and(is_frame_name))
+            .count()
+    }
+    
+    #[test]
+    fn shorter_run_replaces_every_frame() {
+        let dir = std::env::temp_dir().join(format!("sdh_png_{}", std::process::id()));
+        let settings = Settings::new(4, 1, 10, 256, 144);
+        let frame_bytes = (etcher::frame_bits(&settings, OutputMode::Binary) / 8) as usize;
+        
+        let long = Data::new(vec![0x5a; frame_bytes * 3], OutputMode::Binary);
+        write_sequence(&dir, &long, &settings).unwrap();
+        assert_eq!(frame_files(&dir), 3);
+        
+        // Other files in the directory are left alone
+        fs::write(dir.join("notes.txt"), "keep").unwrap();
+        
+        let short = Data::new(vec![0xa5; 10], OutputMode::Binary);
+        write_sequence(&dir, &short, &settings).unwrap();
+        assert_eq!(frame_files(&dir), 1);
+        assert!(dir.join("notes.txt").exists());
+        assert_eq!(PngSequenceReader::open(&dir).unwrap().count(), 1);
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn frame_names() {
+        assert!(is_frame_name(&frame_name(0)));
+        assert!(is_frame_name("frame_1234567.png"));
+        assert!(!is_frame_name("frame_.png"));
+        assert!(!is_frame_name("frame_000001.jpg"));
+        assert!(!is_frame_name("photo_000001.png"));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,176 @@
+// Reading the colour of a block from its pixels.
+//
+// A block of size 2 or more covers several pixels, and the ones on its border
+// are the least trustworthy: compression blurs neighbouring blocks into them,
+// and a frame that was rescaled or warped back into shape smears them
+// further. The strategies:
+//
+// - Centre: the pixel at the centre of the block, or the four around it when
+//   the block size is even
+// - Median: per channel, the median of the block's inner pixels
+// - TrimmedMean: per channel, the mean of the block's inner pixels once the
+//   darkest and brightest quarter are dropped
+//
+// The inner pixels leave out a border of a quarter of the block size: one
+// pixel on 4x4 to 7x7 blocks, two on 8x8 blocks. Blocks under 4x4 have no
+// pixels to spare, and spreading the read over their blurred border only adds
+// errors, so they are read from the centre whatever the strategy.
+//
+// TrimmedMean is the default: like the median it ignores a few smeared
+// pixels, and it still averages the noise over the rest (see the tests below).
+// `--compare-sampling` decodes a video with every strategy and reports how
+// they did on it.
+
+use image::{Rgb, RgbImage};
+
+// Blocks smaller than this are read from their centre whatever the strategy
+pub const MIN_SAMPLED_BLOCK_SIZE: u32 = 4;
+
+// How a block's pixels are turned into one colour
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Sampling {
+    Centre,
+    Median,
+    #[default]
+    TrimmedMean,
+}
+
+impl Sampling {
+    // The default first, so it wins a tie
+    pub const ALL: [Sampling; 3] = [Sampling::TrimmedMean, Sampling::Median, Sampling::Centre];
+}
+
+// Samples blocks of one size, reusing its buffers from block to block
+pub struct Sampler {
+    sampling: Sampling,
+    block_size: u32,
+    channels: [Vec<u8>; 3],
+}
+
+impl Sampler {
+    pub fn new(sampling: Sampling, block_size: u32) -> Sampler {
+        Sampler {
+            sampling,
+            block_size,
+            channels: Default::default(),
+        }
+    }
+    
+    // Colour of the block whose top left pixel is (`left`, `top`)
+    pub fn sample(&mut self, frame: &RgbImage, left: u32, top: u32) -> Rgb<u8> {
+        if self.sampling == Sampling::Centre || self.block_size < MIN_SAMPLED_BLOCK_SIZE {
+            return self.centre(frame, left, top);
+        }
+        
+        let margin = self.block_size / 4;
+        let inner = left + margin..left + self.block_size - margin;
+        for channel in &mut self.channels {
+            channel.clear();
+        }
+        for y in top + margin..top + self.block_size - margin {
+            for x in inner.clone() {
+                for (channel, &value) in self.channels.iter_mut().zip(&frame.get_pixel(x, y).0) {
+                    channel.push(value);
+                }
+            }
+        }
+        
+        let sampling = self.sampling;
+        Rgb(self.channels.each_mut().map(|channel| {
+            channel.sort_unstable();
+            let len = channel.len();
+            match sampling {
+                Sampling::Median if len % 2 == 0 => (channel[len / 2 - 1] as u32 + channel[len / 2] as u32).div_ceil(2) as u8,
+                Sampling::Median => channel[len / 2],
+                _ => {
+                    let kept = &channel[len / 4..len - len / 4];
+                    let sum: u32 = kept.iter().map(|&value| value as u32).sum();
+                    ((sum + kept.len() as u32 / 2) / kept.len() as u32) as u8
+                }
+            }
+        }))
+    }
+    
+    // With an even block size the centre falls between four pixels, which are
+    // averaged; one of them alone would sit half a pixel off, which matters
+    // once a rescaled frame has been scaled back
+    fn centre(&self, frame: &RgbImage, left: u32, top: u32) -> Rgb<u8> {
+        let x = left + (self.block_size - 1) / 2;
+        let y = top + (self.block_size - 1) / 2;
+        let far = 1 - self.block_size % 2;
+        let pixels = [(x, y), (x + far, y), (x, y + far), (x + far, y + far)].map(|(x, y)| frame.get_pixel(x, y).0);
+        Rgb(std::array::from_fn(|channel| {
+            let sum: u32 = pixels.iter().map(|pixel| pixel[channel] as u32).sum();
+            ((sum + 2) / 4) as u8
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    // A block of `size` at (`left`, `top`) in `color`, its border smeared to
+    // white by the blocks around it, with `outlier` at one of the centre pixels
+    fn block(size: u32, left: u32, top: u32, color: Rgb<u8>, outlier: Rgb<u8>) -> RgbImage {
+        let mut frame = RgbImage::from_pixel(left + size + 4, top + size + 4, Rgb([255, 255, 255]));
+        let margin = size / 4;
+        for y in top + margin..top + size - margin {
+            for x in left + margin..left + size - margin {
+                frame.put_pixel(x, y, color);
+            }
+        }
+        frame.put_pixel(left + (size - 1) / 2, top + (size - 1) / 2, outlier);
+        frame
+    }
+    
+    fn sample(sampling: Sampling, size: u32, frame: &RgbImage, left: u32, top: u32) -> Rgb<u8> {
+        Sampler::new(sampling, size).sample(frame, left, top)
+    }
+    
+    #[test]
+    fn inner_pixels_outvote_an_outlier() {
+        let color = Rgb([100, 150, 200]);
+        let outlier = Rgb([0, 150, 255]);
+        for (size, left, top) in [(8, 0, 0), (8, 16, 8), (4, 4, 12), (6, 6, 0)] {
+            let frame = block(size, left, top, color, outlier);
+            assert_eq!(sample(Sampling::Median, size, &frame, left, top), color, "{}x{}", size, size);
+            assert_eq!(sample(Sampling::TrimmedMean, size, &frame, left, top), color, "{}x{}", size, size);
+            assert_ne!(sample(Sampling::Centre, size, &frame, left, top), color, "{}x{}", size, size);
+        }
+    }
+    
+    #[test]
+    fn trimmed_mean_averages_the_middle() {
+        // Inner 4x4 of an 8x8 block: four dark, eight mid and four light pixels
+        let mut frame = RgbImage::from_pixel(8, 8, Rgb([255, 255, 255]));
+        for (i, (x, y)) in (2..6).flat_map(|y| (2..6).map(move |x| (x, y))).enumerate() {
+            let luma = match i {
+                0..4 => 10,
+                4..8 => 90,
+                8..12 => 110,
+                _ => 250,
+            };
+            frame.put_pixel(x, y, Rgb([luma; 3]));
+        }
+        assert_eq!(sample(Sampling::TrimmedMean, 8, &frame, 0, 0), Rgb([100; 3]));
+        assert_eq!(sample(Sampling::Median, 8, &frame, 0, 0), Rgb([100; 3]));
+    }
+    
+    #[test]
+    fn small_blocks_read_the_centre() {
+        let mut frame = RgbImage::from_pixel(3, 3, Rgb([255, 255, 255]));
+        frame.put_pixel(1, 1, Rgb([20, 40, 60]));
+        for sampling in Sampling::ALL {
+            assert_eq!(sample(sampling, 3, &frame, 0, 0), Rgb([20, 40, 60]));
+        }
+        
+        // The centre of an even block falls between four pixels
+        let mut frame = RgbImage::new(2, 2);
+        frame.put_pixel(1, 1, Rgb([200, 100, 0]));
+        for sampling in Sampling::ALL {
+            assert_eq!(sample(sampling, 2, &frame, 0, 0), Rgb([50, 25, 0]));
+        }
+        assert_eq!(sample(Sampling::Median, 1, &frame, 1, 1), Rgb([200, 100, 0]));
+    }
+}
\ No newline at end of file
@@ -1,29 +1,101 @@
-#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+use serde::{Serialize, Deserialize};
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Serialize, Deserialize)]
 pub enum OutputMode {
     Binary,
     Color,
+    // Gray-coded luma levels, see gray.rs
+    Gray4,
+    Gray8,
+    Gray16,
+    // Colours that share their chroma over 2x2 block groups, see palette.rs
+    Palette8,
+    Palette16,
+}
+
+impl OutputMode {
+    // Side, in blocks, of the square group that carries one symbol
+    pub fn group_side(self) -> u32 {
+        match self {
+            OutputMode::Palette8 | OutputMode::Palette16 => 2,
+            _ => 1,
+        }
+    }
+    
+    // Bits of the rendered stream carried by one group of blocks (one block
+    // outside the palette modes)
+    pub fn bits_per_group(self) -> u32 {
+        match self {
+            OutputMode::Binary => 1,
+            OutputMode::Gray4 => 2,
+            OutputMode::Gray8 => 3,
+            OutputMode::Gray16 => 4,
+            OutputMode::Color => 24,
+            // Two bits of chroma for the group, one or two bits of luma per block
+            OutputMode::Palette8 => 2 + 4,
+            OutputMode::Palette16 => 2 + 4 * 2,
+        }
+    }
+    
+    // Luma levels a block can take, None for raw RGB and palette blocks
+    pub fn levels(self) -> Option<u32> {
+        match self {
+            OutputMode::Color | OutputMode::Palette8 | OutputMode::Palette16 => None,
+            mode => Some(1 << mode.bits_per_group()),
+        }
+    }
 }
 
 pub struct Data {
     pub bytes: Vec<u8>,
-    pub binary: Vec<bool>,
     pub out_mode: OutputMode,
 }
 
 impl Data {
-    pub fn from_binary(binary: Vec<bool>) -> Data {
+    // Binary mode keeps the bytes packed and hands them out one bit per block,
+    // least significant bit first, so large payloads do not cost a bool per bit
+    pub fn new(bytes: Vec<u8>, out_mode: OutputMode) -> Data {
         Data {
-            bytes: Vec::new(),
-            binary,
-            out_mode: OutputMode::Binary,
+            bytes,
+            out_mode,
         }
     }
     
-    pub fn from_color(bytes: Vec<u8>) -> Data {
-        Data {
-            bytes,
-            binary: Vec::new(),
-            out_mode: OutputMode::Color,
+    // Bit `index` of the payload, or None past the end
+    pub fn bit(&self, index: u64) -> Option<bool> {
+        let byte = self.bytes.get(usize::try_from(index / 8).ok()?)?;
+        Some((byte >> (index % 8)) & 1 == 1)
+    }
+    
+    // `count` bits starting at bit `index`, least significant first, zero past the end
+    pub fn bits(&self, index: u64, count: u32) -> u32 {
+        (0..count).fold(0, |symbol, i| symbol | (self.bit(index + i as u64).unwrap_or(false) as u32) << i)
+    }
+    
+    // Size of the payload in bytes
+    pub fn len_bytes(&self) -> u64 {
+        self.bytes.len() as u64
+    }
+}
+
+// Packs symbols of a few bits each into bytes, least significant bit first,
+// the same way `Data::bits` reads them
+#[derive(Default)]
+pub struct BitWriter {
+    pub bytes: Vec<u8>,
+    bit_pos: u32,
+}
+
+impl BitWriter {
+    pub fn push(&mut self, symbol: u32, count: u32) {
+        for i in 0..count {
+            if self.bit_pos == 0 {
+                self.bytes.push(0);
+            }
+            if (symbol >> i) & 1 == 1 {
+                *self.bytes.last_mut().unwrap() |= 1 << self.bit_pos;
+            }
+            self.bit_pos = (self.bit_pos + 1) % 8;
         }
     }
 }
@@ -35,6 +107,14 @@ pub struct Settings {
     pub fps: f64, 
     pub width: i32,
     pub height: i32,
+    // Reed–Solomon check bytes per 255 byte codeword, 0 to turn FEC off
+    pub fec_parity: u8,
+    // Fountain-code the frames with this many repair symbols (percent), None for plain frames
+    pub fountain: Option<u32>,
+    // Calibration frames in front of the data frames (see calibration.rs)
+    pub calibration_frames: u32,
+    // Repeat a calibration frame every this many data frames, 0 for never
+    pub calibration_interval: u32,
 }
 
 impl Settings {
@@ -45,6 +125,10 @@ impl Settings {
             fps: fps as f64,
             height,
             width,
+            fec_parity: 0,
+            fountain: None,
+            calibration_frames: 0,
+            calibration_interval: 0,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,174 @@
+// Detached Ed25519 signatures over the container.
+//
+// The signed message is the embedded file's name and SHA-256 followed by the
+// container header as stored (without the signature itself), so it pins the
+// contents, the file name, the frame layout and any encryption parameters:
+//
+//   DOMAIN | file name and SHA-256 (JSON) | 0 | header (JSON, signature left out)
+//
+// For encrypted containers the name and SHA-256 are those of the decrypted
+// file, so the signature is checked after decryption.
+
+use std::path::Path;
+use anyhow::{Result, anyhow};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Serialize, Deserialize};
+use crate::container::ContainerHeader;
+use crate::crypto::{self, Sealed, from_hex, to_hex};
+
+pub const PUBLIC_KEY_PREFIX: &str = "sdh-sign-pub-";
+pub const SECRET_KEY_PREFIX: &str = "SDH-SIGN-SECRET-KEY-";
+
+const DOMAIN: &[u8] = b"steganographic_data_handling signature v1\0";
+
+// Signature stored in the container header
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContainerSignature {
+    pub algorithm: String,
+    // Hex encoded
+    pub public_key: String,
+    // Hex encoded
+    pub signature: String,
+}
+
+pub fn format_public_key(key: &VerifyingKey) -> String {
+    format!("{}{}", PUBLIC_KEY_PREFIX, to_hex(key.as_bytes()))
+}
+
+pub fn format_secret_key(key: &SigningKey) -> String {
+    format!("{}{}", SECRET_KEY_PREFIX, to_hex(key.as_bytes()).to_uppercase())
+}
+
+// Read the signing key from a file written by `keygen --signing`
+pub fn read_signing_key(path: &str) -> Result<SigningKey> {
+    let line = crypto::read_key_file(path, "signing key")?;
+    Ok(SigningKey::from_bytes(&crypto::key_bytes(&line, SECRET_KEY_PREFIX, "Signing key")?))
+}
+
+// Parse a --verify-key value: the public key itself, or a file holding it
+// (such as the signing key file, whose comment lists the public key)
+pub fn read_verifying_key(value: &str) -> Result<VerifyingKey> {
+    let text = if Path::new(value).is_file() {
+        let line = crypto::read_key_file(value, "verify key")?;
+        if line.starts_with(SECRET_KEY_PREFIX) {
+            return Ok(read_signing_key(value)?.verifying_key());
+        }
+        line
+    } else {
+        value.to_string()
+    };
+    
+    let bytes = crypto::key_bytes(&text, PUBLIC_KEY_PREFIX, "Verify key")?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| anyhow!("Verify key is not a valid Ed25519 public key"))
+}
+
+fn message(header: &ContainerHeader, file: &Sealed) -> Result<Vec<u8>> {
+    let mut unsigned = header.clone();
+    unsigned.signature = None;
+    
+    let mut message = DOMAIN.to_vec();
+    message.extend_from_slice(&serde_json::to_vec(file)?);
+    message.push(0);
+    message.extend_from_slice(&serde_json::to_vec(&unsigned)?);
+    Ok(message)
+}
+
+// Sign `header` for `file`, replacing any earlier signature
+pub fn sign(header: &mut ContainerHeader, file: &Sealed, key: &SigningKey) -> Result<()> {
+    let signature = key.sign(&message(header, file)?);
+    header.signature = Some(ContainerSignature {
+        algorithm: "ed25519".to_string(),
+        public_key: to_hex(key.verifying_key().as_bytes()),
+        signature: to_hex(&signature.to_bytes()),
+    });
+    Ok(())
+}
+
+// Check the signature on `header` (as stored, before decryption) for `file`
+// (as decoded) against `key`
+pub fn verify(header: &ContainerHeader, file: &Sealed, key: &VerifyingKey) -> Result<()> {
+    let Some(stored) = &header.signature else {
+        return Err(anyhow!("The data is not signed, but --verify-key was given"));
+    };
+    if stored.algorithm != "ed25519" {
+        return Err(anyhow!("Unsupported signature algorithm {}", stored.algorithm));
+    }
+    if from_hex(&stored.public_key)? != key.as_bytes() {
+        return Err(anyhow!(
+            "The data was signed by a different key: expected {}, signed by {}{}",
+            format_public_key(key), PUBLIC_KEY_PREFIX, stored.public_key
+        ));
+    }
+    
+    let signature: [u8; 64] = from_hex(&stored.signature)?
+        .try_into()
+        .map_err(|_| anyhow!("Invalid signature length"))?;
+    key.verify(&message(header, file)?, &Signature::from_bytes(&signature))
+        .map_err(|_| anyhow!("Signature check FAILED: the data or its header was changed after signing"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container;
+    use crate::crypto::Protection;
+    use crate::settings::{OutputMode, Settings};
+    
+    fn signed(payload: &[u8], key: &SigningKey) -> (ContainerHeader, Sealed) {
+        let settings = Settings::new(2, 1, 10, 256, 144);
+        let protection = Protection {
+            signing_key: Some(key.clone()),
+            ..Default::default()
+        };
+        let bytes = container::pack(payload, "notes.txt", OutputMode::Binary, &settings, &Default::default(), &protection).unwrap();
+        let header = container::unpack(bytes).unwrap().header.unwrap();
+        let file = Sealed {
+            filename: Some("notes.txt".to_string()),
+            sha256: container::sha256_hex(payload),
+        };
+        (header, file)
+    }
+    
+    #[test]
+    fn verifies_the_signer() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let (header, file) = signed(b"signed, sealed, delivered", &key);
+        verify(&header, &file, &key.verifying_key()).unwrap();
+        
+        let other = SigningKey::from_bytes(&[8; 32]);
+        assert!(verify(&header, &file, &other.verifying_key()).is_err());
+        
+        let mut unsigned = header;
+        unsigned.signature = None;
+        assert!(verify(&unsigned, &file, &key.verifying_key()).is_err());
+    }
+    
+    #[test]
+    fn detects_tampering() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let (header, file) = signed(b"signed, sealed, delivered", &key);
+        
+        let changed_file = Sealed { sha256: container::sha256_hex(b"something else"), ..file.clone() };
+        assert!(verify(&header, &changed_file, &key.verifying_key()).is_err());
+        
+        let renamed = Sealed { filename: Some("other.txt".to_string()), ..file.clone() };
+        assert!(verify(&header, &renamed, &key.verifying_key()).is_err());
+        
+        let mut relaid = header.clone();
+        relaid.metadata.block_size = 4;
+        assert!(verify(&relaid, &file, &key.verifying_key()).is_err());
+        
+        let mut forged = header;
+        let signature = forged.signature.as_mut().unwrap();
+        signature.signature = signature.signature.replacen('0', "1", 1);
+        assert!(verify(&forged, &file, &key.verifying_key()).is_err());
+    }
+    
+    #[test]
+    fn key_text_round_trip() {
+        let key = SigningKey::from_bytes(&[9; 32]);
+        let public = format_public_key(&key.verifying_key());
+        assert_eq!(read_verifying_key(&public).unwrap(), key.verifying_key());
+        assert!(read_verifying_key("sdh-sign-pub-00").is_err());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,258 @@
+// Telling the black and white blocks of binary mode apart.
+//
+// The encoder draws a 0 as black and a 1 as white, but by the time a frame
+// comes back from a codec neither is where it started. A fixed cut-off at 128
+// fails once the codec shifts the brightness, and ringing next to sharp edges
+// pulls blocks in busy parts of the frame towards the other colour. The
+// strategies:
+//
+// - Fixed: 128, as rendered
+// - Otsu: per frame, the split of the frame's block lumas that best separates
+//   a dark and a light class, cutting halfway between the two
+// - Adaptive: the same per tile of TILE x TILE blocks, over the tile and its
+//   neighbours, so the cut-off follows brightness that varies across the frame
+// - Calibrated: halfway between black and white as measured from the swatches
+//   in the frame's header strip (after any calibration frame has corrected the
+//   levels)
+//
+// Whenever a frame or tile does not look like two colours (a stretch of
+// padding, a flat patch) the cut-off falls back to the swatches, then to 128.
+//
+// Every bit also gets a confidence, 0 right on the cut-off and 1 at or past
+// the typical level of its colour, which the Reed–Solomon layer uses to pick
+// erasures.
+
+use image::RgbImage;
+use crate::decoder::FrameLayout;
+use crate::gray::Calibration;
+
+// Blocks per side of the tiles the adaptive strategy works on
+const TILE: u32 = 32;
+
+// Dark and light classes closer together than this are not two colours
+const MIN_SEPARATION: f64 = 48.0;
+
+// How binary blocks are told apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strategy {
+    Fixed,
+    Otsu,
+    Adaptive,
+    #[default]
+    Calibrated,
+}
+
+// A cut-off and the typical luma on either side of it
+#[derive(Debug, Clone, Copy)]
+struct Cut {
+    threshold: f64,
+    dark: f64,
+    light: f64,
+}
+
+impl Cut {
+    const FIXED: Cut = Cut {
+        threshold: 128.0,
+        dark: 0.0,
+        light: 255.0,
+    };
+    
+    fn between(dark: f64, light: f64) -> Cut {
+        Cut {
+            threshold: (dark + light) / 2.0,
+            dark,
+            light,
+        }
+    }
+    
+    // Bit of a block with the given luma, and how sure that is
+    fn bit(&self, luma: f64) -> (bool, f32) {
+        let light = luma >= self.threshold;
+        let reach = if light { self.light - self.threshold } else { self.threshold - self.dark };
+        let confidence = ((luma - self.threshold).abs() / reach.max(1.0)).min(1.0);
+        (light, confidence as f32)
+    }
+}
+
+type Histogram = [u32; 256];
+
+// Otsu's split of a luma histogram: the one that maximises the variance
+// between the dark and light classes. None unless both classes are there and
+// far enough apart.
+fn otsu(histogram: &Histogram) -> Option<Cut> {
+    let total: f64 = histogram.iter().map(|&count| count as f64).sum();
+    let weighted: f64 = histogram.iter().enumerate().map(|(luma, &count)| luma as f64 * count as f64).sum();
+    
+    let (mut dark_count, mut dark_weighted) = (0.0, 0.0);
+    let mut best: Option<(f64, f64, f64)> = None; // variance, dark mean, light mean
+    for (luma, &count) in histogram.iter().enumerate().take(255) {
+        dark_count += count as f64;
+        dark_weighted += luma as f64 * count as f64;
+        let light_count = total - dark_count;
+        if dark_count == 0.0 || light_count == 0.0 {
+            continue;
+        }
+        
+        let dark = dark_weighted / dark_count;
+        let light = (weighted - dark_weighted) / light_count;
+        let variance = dark_count * light_count * (light - dark) * (light - dark);
+        if best.is_none_or(|(best_variance, _, _)| variance > best_variance) {
+            best = Some((variance, dark, light));
+        }
+    }
+    
+    let (_, dark, light) = best?;
+    (light - dark >= MIN_SEPARATION).then(|| Cut::between(dark, light))
+}
+
+// The cut-off from the swatches in the header strip, or 128 if they cannot be told apart
+fn calibrated(frame: &RgbImage) -> Cut {
+    match Calibration::measure(frame, 2).centres() {
+        &[dark, light] if light - dark >= MIN_SEPARATION => Cut::between(dark, light),
+        _ => Cut::FIXED,
+    }
+}
+
+// Bits carried by binary blocks and how sure the decoder is of each. `blocks`
+// holds the top left pixel and the luma of every block, in stream order.
+pub fn read_bits(strategy: Strategy, frame: &RgbImage, layout: &FrameLayout, blocks: &[(u32, u32, f64)]) -> Vec<(bool, f32)> {
+    let fallback = || calibrated(frame);
+    let histogram = |blocks: &mut dyn Iterator<Item = &(u32, u32, f64)>| {
+        let mut histogram = [0u32; 256];
+        for &(_, _, luma) in blocks {
+            histogram[luma.round().clamp(0.0, 255.0) as usize] += 1;
+        }
+        histogram
+    };
+    
+    match strategy {
+        Strategy::Fixed => blocks.iter().map(|&(_, _, luma)| Cut::FIXED.bit(luma)).collect(),
+        Strategy::Calibrated => {
+            let cut = fallback();
+            blocks.iter().map(|&(_, _, luma)| cut.bit(luma)).collect()
+        }
+        Strategy::Otsu => {
+            let cut = otsu(&histogram(&mut blocks.iter())).unwrap_or_else(fallback);
+            blocks.iter().map(|&(_, _, luma)| cut.bit(luma)).collect()
+        }
+        Strategy::Adaptive => {
+            let frame_cut = otsu(&histogram(&mut blocks.iter())).unwrap_or_else(fallback);
+            
+            // One histogram per tile, then each tile's cut-off from the
+            // histograms of the tiles around it
+            let span = TILE * layout.block_size;
+            let tile_of = |&(left, top, _): &(u32, u32, f64)| ((left / span) as usize, ((top - layout.top) / span) as usize);
+            let columns = (layout.width / span + 1) as usize;
+            let rows = ((layout.bottom - layout.top) / span + 1) as usize;
+            let mut tiles = vec![[0u32; 256]; columns * rows];
+            for block in blocks {
+                let (column, row) = tile_of(block);
+                tiles[row * columns + column][block.2.round().clamp(0.0, 255.0) as usize] += 1;
+            }
+            
+            let cuts: Vec<Cut> = (0..rows * columns)
+                .map(|tile| {
+                    let (column, row) = (tile % columns, tile / columns);
+                    let mut around = [0u32; 256];
+                    for r in row.saturating_sub(1)..(row + 2).min(rows) {
+                        for c in column.saturating_sub(1)..(column + 2).min(columns) {
+                            for (sum, count) in around.iter_mut().zip(&tiles[r * columns + c]) {
+                                *sum += count;
+                            }
+                        }
+                    }
+                    otsu(&around).unwrap_or(frame_cut)
+                })
+                .collect();
+            
+            blocks.iter()
+                .map(|block| {
+                    let (column, row) = tile_of(block);
+                    cuts[row * columns + column].bit(block.2)
+                })
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::LayoutHint;
+    use crate::settings::OutputMode;
+    
+    fn histogram(peaks: &[(u8, u32)]) -> Histogram {
+        let mut histogram = [0; 256];
+        for &(luma, count) in peaks {
+            // A little spread around every peak
+            for offset in 0..5 {
+                histogram[(luma as usize + offset).min(255)] += count;
+            }
+        }
+        histogram
+    }
+    
+    #[test]
+    fn otsu_splits_two_classes() {
+        let cut = otsu(&histogram(&[(20, 100), (200, 60)])).unwrap();
+        assert!((cut.dark - 22.0).abs() < 1.0);
+        assert!((cut.light - 202.0).abs() < 1.0);
+        assert!((cut.threshold - 112.0).abs() < 1.0);
+        
+        assert!(otsu(&histogram(&[(120, 100)])).is_none());
+        assert!(otsu(&histogram(&[(100, 50), (130, 50)])).is_none());
+        assert!(otsu(&[0; 256]).is_none());
+    }
+    
+    #[test]
+    fn confidence_grows_away_from_the_cut() {
+        let cut = Cut::between(40.0, 200.0);
+        assert_eq!(cut.bit(120.0), (true, 0.0));
+        assert_eq!(cut.bit(40.0), (false, 1.0));
+        assert_eq!(cut.bit(255.0), (true, 1.0));
+        let (bit, confidence) = cut.bit(80.0);
+        assert!(!bit && (confidence - 0.5).abs() < 1e-6);
+    }
+    
+    #[test]
+    fn adaptive_follows_uneven_brightness() {
+        // Left half dim (0 and 100), right half washed out (150 and 255)
+        let (width, height) = (1024, 160);
+        let hint = LayoutHint {
+            mode: Some(OutputMode::Binary),
+            block_size: Some(1),
+            ..Default::default()
+        };
+        let layout = FrameLayout::resolve(None, None, &hint, width, height).unwrap();
+        let frame = RgbImage::new(width, height);
+        
+        let mut bits = Vec::new();
+        let mut blocks = Vec::new();
+        for y in layout.top..layout.bottom {
+            for x in 0..width {
+                let bit = (x * 7 + y * 3) % 5 < 2;
+                let luma = match (x < width / 2, bit) {
+                    (true, false) => 0.0,
+                    (true, true) => 100.0,
+                    (false, false) => 150.0,
+                    (false, true) => 255.0,
+                };
+                bits.push(bit);
+                blocks.push((x, y, luma));
+            }
+        }
+        
+        let errors = |strategy| {
+            read_bits(strategy, &frame, &layout, &blocks)
+                .iter()
+                .zip(&bits)
+                .filter(|((read, _), bit)| read != *bit)
+                .count()
+        };
+        let quarter = blocks.len() / 4;
+        assert!(errors(Strategy::Fixed) > quarter);
+        assert!(errors(Strategy::Otsu) > quarter);
+        // Only tiles next to the seam see both halves
+        assert!(errors(Strategy::Adaptive) <= (2 * TILE * (layout.bottom - layout.top)) as usize);
+    }
+}
\ No newline at end of file
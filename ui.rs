@@ -1,6 +1,6 @@
 use std::io::{self, Write};
 use anyhow::Result;
-use crate::args::{Commands, EmbedParams, DislodgeParams, DownloadParams, EmbedPreset, EmbedOutputMode};
+use crate::args::{Commands, EmbedParams, DislodgeParams, DownloadParams, EmbedPreset, EmbedOutputMode, EmbedOutputFormat};
 
 // Simple interactive UI for command-line interface
 pub async fn enrich_arguments(command: Option<Commands>) -> Result<Commands> {
@@ -86,7 +86,12 @@ async fn configure_embed() -> Result<EmbedParams> {
             println!("\nSelect output mode:");
             println!("1. Binary (black and white, 1 bit per pixel)");
             println!("2. Color (RGB values, 24 bits per pixel)");
-            print!("Enter your choice (1-2): ");
+            println!("3. Grayscale, 4 levels (2 bits per pixel)");
+            println!("4. Grayscale, 8 levels (3 bits per pixel)");
+            println!("5. Grayscale, 16 levels (4 bits per pixel)");
+            println!("6. Palette, 8 colours (1.5 bits per pixel, survives chroma subsampling)");
+            println!("7. Palette, 16 colours (2.5 bits per pixel, survives chroma subsampling)");
+            print!("Enter your choice (1-7): ");
             io::stdout().flush()?;
             
             let mut mode_choice = String::new();
@@ -96,6 +101,11 @@ async fn configure_embed() -> Result<EmbedParams> {
             params.mode = match mode_choice {
                 "1" => Some(EmbedOutputMode::Binary),
                 "2" => Some(EmbedOutputMode::Colored),
+                "3" => Some(EmbedOutputMode::Gray4),
+                "4" => Some(EmbedOutputMode::Gray8),
+                "5" => Some(EmbedOutputMode::Gray16),
+                "6" => Some(EmbedOutputMode::Palette8),
+                "7" => Some(EmbedOutputMode::Palette16),
                 _ => Some(EmbedOutputMode::Binary), // Default to binary
             };
             
@@ -170,6 +180,29 @@ async fn configure_embed() -> Result<EmbedParams> {
         }
     }
     
+    // Output format
+    println!("\nSelect output format:");
+    println!("1. Binvid (single .binvid file)");
+    println!("2. PNG frames (numbered images plus manifest)");
+    println!("3. Y4M (uncompressed video)");
+    println!("4. AVI (MJPEG video)");
+    println!("5. MP4 (H.264 video, needs ffmpeg)");
+    println!("6. MKV (lossless FFV1 video, needs ffmpeg)");
+    print!("Enter your choice (1-6): ");
+    io::stdout().flush()?;
+    
+    let mut format_choice = String::new();
+    io::stdin().read_line(&mut format_choice)?;
+    
+    params.format = match format_choice.trim() {
+        "2" => Some(EmbedOutputFormat::Png),
+        "3" => Some(EmbedOutputFormat::Y4m),
+        "4" => Some(EmbedOutputFormat::Avi),
+        "5" => Some(EmbedOutputFormat::Mp4),
+        "6" => Some(EmbedOutputFormat::Mkv),
+        _ => Some(EmbedOutputFormat::Binvid), // Default to binvid
+    };
+    
     Ok(params)
 }
 
@@ -185,7 +218,7 @@ async fn configure_dislodge() -> Result<DislodgeParams> {
     let in_path = if in_path.is_empty() { None } else { Some(in_path.to_string()) };
     
     // Get output file path
-    print!("Enter output file path (or press Enter for default 'extracted_file.bin'): ");
+    print!("Enter output file path (or press Enter to use the original file name): ");
     io::stdout().flush()?;
     let mut out_path = String::new();
     io::stdin().read_line(&mut out_path)?;
@@ -195,6 +228,7 @@ async fn configure_dislodge() -> Result<DislodgeParams> {
     let params = DislodgeParams {
         in_path,
         out_path,
+        ..Default::default()
     };
     
     Ok(params)
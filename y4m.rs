@@ -0,0 +1,375 @@
+// YUV4MPEG2 (.y4m) reading and writing.
+//
+// Frames we write are stored 4:4:4 as full-range BT.601 YCbCr, which is what
+// the C444 and XCOLORRANGE=FULL tags promise, so they look right in any
+// player. Greys come back exactly, other colours within a step or two, which
+// every mode but Color tolerates. Color mode needs `Y4mColor::Rct`, a
+// reversible colour transform (Y = G, Cb = B - G + 128, Cr = R - G + 128, all
+// mod 256) that survives the round trip bit for bit but is marked with
+// XSDH_COLOR=RCT, which only this tool reads: anywhere else those frames
+// show the wrong colours. The layout is recorded in X* header parameters,
+// which other tools ignore.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use anyhow::{Result, Context, anyhow};
+use image::{RgbImage, Rgb};
+use crate::etcher::{self, VideoMetadata};
+use crate::settings::{Data, OutputMode, Settings};
+
+pub const MAGIC: &[u8] = b"YUV4MPEG2";
+
+// Chroma layouts we can read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Chroma {
+    Mono,
+    C420,
+    C422,
+    C444,
+}
+
+impl Chroma {
+    fn parse(value: &str) -> Result<Chroma> {
+        match value {
+            "mono" => Ok(Chroma::Mono),
+            "420" | "420jpeg" | "420mpeg2" | "420paldv" => Ok(Chroma::C420),
+            "422" => Ok(Chroma::C422),
+            "444" => Ok(Chroma::C444),
+            _ => Err(anyhow!("Unsupported Y4M colour space: C{}", value)),
+        }
+    }
+    
+    // Size of one chroma plane for a frame of the given size
+    fn plane_size(&self, width: usize, height: usize) -> (usize, usize) {
+        match self {
+            Chroma::Mono => (0, 0),
+            Chroma::C420 => (width.div_ceil(2), height.div_ceil(2)),
+            Chroma::C422 => (width.div_ceil(2), height),
+            Chroma::C444 => (width, height),
+        }
+    }
+}
+
+// How the RGB frames are stored in the Y, Cb and Cr planes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Y4mColor {
+    // Full-range BT.601 YCbCr
+    Bt601,
+    // The reversible colour transform, exact but only read correctly by us
+    Rct,
+}
+
+// Frame rate as the numerator/denominator pair used by the header
+fn frame_rate(fps: f64) -> (u32, u32) {
+    if fps.fract() == 0.0 {
+        (fps as u32, 1)
+    } else {
+        ((fps * 1000.0).round() as u32, 1000)
+    }
+}
+
+// Stream every rendered frame into a Y4M file at `settings.fps`
+pub fn write_y4m<P: AsRef<Path>>(path: P, data: &Data, settings: &Settings, color: Y4mColor) -> Result<()> {
+    etcher::check_layout(settings, data.out_mode)?;
+    let metadata = VideoMetadata::new(data, settings);
+    
+    let file = File::create(&path)
+        .with_context(|| format!("Failed to create {}", path.as_ref().display()))?;
+    let mut out = BufWriter::new(file);
+    
+    let (rate_num, rate_den) = frame_rate(settings.fps);
+    writeln!(
+        out,
+        "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444 XCOLORRANGE=FULL{} XSDH_MODE={:?} XSDH_BLOCK={} XSDH_FRAMES={} XSDH_SIZE={} XSDH_FEC={} XSDH_INTERLEAVE={} XSDH_FOUNTAIN={} XSDH_CALIBRATION={} XSDH_MARKERS={}",
+        metadata.width, metadata.height, rate_num, rate_den,
+        if color == Y4mColor::Rct { " XSDH_COLOR=RCT" } else { "" },
+        metadata.mode, metadata.block_size, metadata.frames, metadata.data_size, metadata.fec_parity, metadata.interleaved as u8, metadata.fountain as u8,
+        metadata.calibration_frames, metadata.markers as u8,
+    )?;
+    
+    let plane = (metadata.width * metadata.height) as usize;
+    let mut planes = vec![0u8; plane * 3];
+    
+    let frames = etcher::frames(data, settings);
+    let pb = etcher::frame_progress(frames.len());
+    
+    for frame in frames {
+        for (i, pixel) in frame.pixels().enumerate() {
+            let [r, g, b] = pixel.0;
+            let [y, cb, cr] = match color {
+                Y4mColor::Bt601 => rgb_to_ycbcr(pixel),
+                Y4mColor::Rct => [g, b.wrapping_sub(g).wrapping_add(128), r.wrapping_sub(g).wrapping_add(128)],
+            };
+            planes[i] = y;
+            planes[plane + i] = cb;
+            planes[plane * 2 + i] = cr;
+        }
+        
+        out.write_all(b"FRAME\n")?;
+        out.write_all(&planes)?;
+        pb.inc(1);
+    }
+    
+    pb.finish_and_clear();
+    out.flush()?;
+    
+    println!("Video written to: {}", path.as_ref().display());
+    Ok(())
+}
+
+// Frame-by-frame reader for Y4M files
+pub struct Y4mReader {
+    input: BufReader<File>,
+    pub width: u32,
+    pub height: u32,
+    pub fps: f64,
+    chroma: Chroma,
+    full_range: bool,
+    reversible: bool,
+    params: HashMap<String, String>,
+}
+
+impl Y4mReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Y4mReader> {
+        let file = File::open(&path)
+            .with_context(|| format!("Failed to open {}", path.as_ref().display()))?;
+        let mut input = BufReader::new(file);
+        
+        let mut header = String::new();
+        input.read_line(&mut header)?;
+        let mut tokens = header.trim_end().split(' ');
+        
+        if tokens.next().map(str::as_bytes) != Some(MAGIC) {
+            return Err(anyhow!("Not a YUV4MPEG2 file"));
+        }
+        
+        let mut width = 0;
+        let mut height = 0;
+        let mut fps = 0.0;
+        let mut chroma = Chroma::C420;
+        let mut params = HashMap::new();
+        
+        for token in tokens.filter(|t| !t.is_empty()) {
+            let (tag, value) = token.split_at(1);
+            match tag {
+                "W" => width = value.parse().context("Invalid Y4M width")?,
+                "H" => height = value.parse().context("Invalid Y4M height")?,
+                "F" => {
+                    if let Some((num, den)) = value.split_once(':') {
+                        let num: f64 = num.parse().context("Invalid Y4M frame rate")?;
+                        let den: f64 = den.parse().context("Invalid Y4M frame rate")?;
+                        if den != 0.0 {
+                            fps = num / den;
+                        }
+                    }
+                }
+                "C" => chroma = Chroma::parse(value)?,
+                "X" => {
+                    if let Some((key, value)) = value.split_once('=') {
+                        params.insert(key.to_string(), value.to_string());
+                    }
+                }
+                _ => (),
+            }
+        }
+        
+        if width == 0 || height == 0 {
+            return Err(anyhow!("Y4M header is missing the frame size"));
+        }
+        
+        let full_range = params.get("COLORRANGE").map(String::as_str) == Some("FULL");
+        let reversible = params.get("SDH_COLOR").map(String::as_str) == Some("RCT");
+        
+        Ok(Y4mReader {
+            input,
+            width,
+            height,
+            fps,
+            chroma,
+            full_range,
+            reversible,
+            params,
+        })
+    }
+    
+    // Layout recorded by `write_y4m`, if this file was written by us
+    pub fn metadata(&self) -> Option<VideoMetadata> {
+        let param = |key: &str| self.params.get(key);
+        
+        let mode = match param("SDH_MODE")?.as_str() {
+            "Binary" => OutputMode::Binary,
+            "Color" => OutputMode::Color,
+            "Gray4" => OutputMode::Gray4,
+            "Gray8" => OutputMode::Gray8,
+            "Gray16" => OutputMode::Gray16,
+            "Palette8" => OutputMode::Palette8,
+            "Palette16" => OutputMode::Palette16,
+            _ => return None,
+        };
+        
+        Some(VideoMetadata {
+            mode,
+            frames: param("SDH_FRAMES")?.parse().ok()?,
+            width: self.width,
+            height: self.height,
+            block_size: param("SDH_BLOCK")?.parse().ok()?,
+            fps: self.fps,
+            data_size: param("SDH_SIZE")?.parse().ok()?,
+            fec_parity: param("SDH_FEC").and_then(|value| value.parse().ok()).unwrap_or(0),
+            interleaved: param("SDH_INTERLEAVE").is_some_and(|value| value == "1"),
+            fountain: param("SDH_FOUNTAIN").is_some_and(|value| value == "1"),
+            calibration_frames: param("SDH_CALIBRATION").and_then(|value| value.parse().ok()).unwrap_or(0),
+            markers: param("SDH_MARKERS").is_some_and(|value| value == "1"),
+        })
+    }
+    
+    // Read the next frame, or None at the end of the file
+    pub fn next_frame(&mut self) -> Result<Option<RgbImage>> {
+        let mut frame_header = String::new();
+        if self.input.read_line(&mut frame_header)? == 0 {
+            return Ok(None);
+        }
+        if !frame_header.starts_with("FRAME") {
+            return Err(anyhow!("Corrupt Y4M frame header"));
+        }
+        
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let (chroma_width, chroma_height) = self.chroma.plane_size(width, height);
+        
+        let mut luma = vec![0u8; width * height];
+        let mut cb = vec![128u8; chroma_width * chroma_height];
+        let mut cr = vec![128u8; chroma_width * chroma_height];
+        self.input.read_exact(&mut luma).context("Y4M file ends mid-frame")?;
+        self.input.read_exact(&mut cb).context("Y4M file ends mid-frame")?;
+        self.input.read_exact(&mut cr).context("Y4M file ends mid-frame")?;
+        
+        let mut img = RgbImage::new(self.width, self.height);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            let (x, y) = (x as usize, y as usize);
+            let luma = luma[y * width + x];
+            
+            let chroma_index = match self.chroma {
+                Chroma::Mono => None,
+                Chroma::C420 => Some((y / 2) * chroma_width + x / 2),
+                Chroma::C422 => Some(y * chroma_width + x / 2),
+                Chroma::C444 => Some(y * width + x),
+            };
+            let (cb, cr) = chroma_index.map_or((128, 128), |i| (cb[i], cr[i]));
+            
+            *pixel = if self.reversible {
+                let g = luma;
+                Rgb([cr.wrapping_add(g).wrapping_sub(128), g, cb.wrapping_add(g).wrapping_sub(128)])
+            } else {
+                ycbcr_to_rgb(luma, cb, cr, self.full_range)
+            };
+        }
+        
+        Ok(Some(img))
+    }
+}
+
+impl Iterator for Y4mReader {
+    type Item = Result<RgbImage>;
+    
+    fn next(&mut self) -> Option<Result<RgbImage>> {
+        self.next_frame().transpose()
+    }
+}
+
+// RGB to full-range BT.601 YCbCr
+fn rgb_to_ycbcr(pixel: &Rgb<u8>) -> [u8; 3] {
+    let [r, g, b] = pixel.0.map(|channel| channel as f32);
+    let y = 0.299 * r + 0.587 * g + 0.114 * b;
+    let cb = 128.0 + (b - y) / 1.772;
+    let cr = 128.0 + (r - y) / 1.402;
+    [y, cb, cr].map(|value| value.round().clamp(0.0, 255.0) as u8)
+}
+
+// BT.601 YCbCr to RGB
+fn ycbcr_to_rgb(y: u8, cb: u8, cr: u8, full_range: bool) -> Rgb<u8> {
+    let (y, cb, cr) = if full_range {
+        (y as f32, cb as f32 - 128.0, cr as f32 - 128.0)
+    } else {
+        (
+            (y as f32 - 16.0) * 255.0 / 219.0,
+            (cb as f32 - 128.0) * 255.0 / 224.0,
+            (cr as f32 - 128.0) * 255.0 / 224.0,
+        )
+    };
+    
+    let r = y + 1.402 * cr;
+    let g = y - 0.344_136 * cb - 0.714_136 * cr;
+    let b = y + 1.772 * cb;
+    Rgb([r.round().clamp(0.0, 255.0) as u8, g.round().clamp(0.0, 255.0) as u8, b.round().clamp(0.0, 255.0) as u8])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    // Write the frames `write_y4m` renders and read them back, along with the layout
+    fn round_trip(mode: OutputMode, color: Y4mColor) -> (Vec<RgbImage>, Vec<RgbImage>) {
+        let settings = Settings::new(2, 1, 10, 256, 144);
+        let payload: Vec<u8> = (0..3000u32).map(|i| (i * 31 + i / 7) as u8).collect();
+        let data = Data::new(payload, mode);
+        let path = std::env::temp_dir().join(format!("sdh_y4m_{}_{:?}_{:?}.y4m", std::process::id(), mode, color));
+        
+        write_y4m(&path, &data, &settings, color).unwrap();
+        let reader = Y4mReader::open(&path).unwrap();
+        let metadata = reader.metadata().unwrap();
+        let frames: Vec<RgbImage> = reader.collect::<Result<_>>().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        
+        assert_eq!(metadata.mode, mode);
+        assert_eq!((metadata.width, metadata.height, metadata.block_size), (256, 144, 2));
+        assert_eq!(metadata.data_size, 3000);
+        assert_eq!(metadata.frames, frames.len() as u64);
+        (frames, etcher::frames(&data, &settings).collect())
+    }
+    
+    #[test]
+    fn binary_round_trip() {
+        let (frames, expected) = round_trip(OutputMode::Binary, Y4mColor::Bt601);
+        assert_eq!(frames, expected);
+    }
+    
+    #[test]
+    fn palette_round_trip() {
+        let (frames, expected) = round_trip(OutputMode::Palette16, Y4mColor::Bt601);
+        assert_eq!(frames.len(), expected.len());
+        for (frame, expected) in frames.iter().zip(&expected) {
+            for (pixel, expected) in frame.pixels().zip(expected.pixels()) {
+                assert!(pixel.0.iter().zip(&expected.0).all(|(a, b)| a.abs_diff(*b) <= 2), "{:?} read as {:?}", expected, pixel);
+            }
+        }
+    }
+    
+    #[test]
+    fn color_round_trip() {
+        let (frames, expected) = round_trip(OutputMode::Color, Y4mColor::Rct);
+        assert_eq!(frames, expected);
+    }
+    
+    #[test]
+    fn writes_standard_ycbcr() {
+        assert_eq!(rgb_to_ycbcr(&Rgb([0, 0, 0])), [0, 128, 128]);
+        assert_eq!(rgb_to_ycbcr(&Rgb([255, 255, 255])), [255, 128, 128]);
+        assert_eq!(rgb_to_ycbcr(&Rgb([255, 0, 0])), [76, 85, 255]);
+        assert_eq!(rgb_to_ycbcr(&Rgb([0, 0, 255])), [29, 255, 107]);
+        for luma in 0..=255 {
+            let [y, cb, cr] = rgb_to_ycbcr(&Rgb([luma; 3]));
+            assert_eq!(ycbcr_to_rgb(y, cb, cr, true), Rgb([luma; 3]));
+        }
+    }
+    
+    #[test]
+    fn reads_limited_range_420() {
+        assert_eq!(Chroma::parse("420jpeg").unwrap().plane_size(5, 3), (3, 2));
+        assert_eq!(ycbcr_to_rgb(16, 128, 128, false), Rgb([0, 0, 0]));
+        assert_eq!(ycbcr_to_rgb(235, 128, 128, false), Rgb([255, 255, 255]));
+        assert_eq!(ycbcr_to_rgb(200, 128, 128, true), Rgb([200, 200, 200]));
+    }
+}
\ No newline at end of file